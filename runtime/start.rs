@@ -23,8 +23,19 @@ extern "C" {
     // it does not add an underscore in front of the name, which happens on OSX
     // Courtesy of Max New
     // (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
-    #[link_name = "\x01our_code_starts_here"]
-    fn our_code_starts_here(input: i64) -> i64;
+    // ELF symbols have no prefix, so Linux uses the plain names.
+    #[cfg_attr(target_os = "macos", link_name = "\x01our_code_starts_here")]
+    fn our_code_starts_here(input: i64, heap: *mut i64) -> i64;
+
+    // NUL-terminated "(+ x y) at fac.snek:4:12" strings, one per error site id
+    #[cfg_attr(target_os = "macos", link_name = "\x01snek_error_sites")]
+    #[cfg_attr(not(target_os = "macos"), link_name = "snek_error_sites")]
+    static SNEK_ERROR_SITES: u8;
+
+    // Words of heap the compiled code expects in R15
+    #[cfg_attr(target_os = "macos", link_name = "\x01snek_heap_words")]
+    #[cfg_attr(not(target_os = "macos"), link_name = "snek_heap_words")]
+    static SNEK_HEAP_WORDS: u64;
 }
 
 // #[export_name = "\x01snek_error"]
@@ -41,13 +52,40 @@ extern "C" {
 
 pub static REPL: AtomicBool = AtomicBool::new(false);
 
-#[export_name = "\x01snek_error"]
-pub extern "C" fn snek_error(errcode: i64) {
-    match errcode {
-        1 => eprintln!("overflow"),
-        2 => eprintln!("invalid argument"),
-        3 => eprintln!("bad cast"),
-        _ => eprintln!("unknown error code: {}", errcode),
+fn error_site(site: i64) -> Option<String> {
+    if site < 0 {
+        return None;
+    }
+    unsafe {
+        let mut ptr = std::ptr::addr_of!(SNEK_ERROR_SITES);
+        for _ in 0..site {
+            if *ptr == 0 {
+                return None;
+            }
+            ptr = ptr.add(std::ffi::CStr::from_ptr(ptr as *const _).to_bytes().len() + 1);
+        }
+        if *ptr == 0 {
+            return None;
+        }
+        Some(std::ffi::CStr::from_ptr(ptr as *const _).to_string_lossy().into_owned())
+    }
+}
+
+#[cfg_attr(target_os = "macos", export_name = "\x01snek_error")]
+#[cfg_attr(not(target_os = "macos"), no_mangle)]
+pub extern "C" fn snek_error(errcode: i64, site: i64) {
+    let msg = match errcode {
+        1 => "overflow".to_string(),
+        2 => "invalid argument".to_string(),
+        3 => "bad cast".to_string(),
+        4 => "division by zero".to_string(),
+        5 => "index out of bounds".to_string(),
+        6 => "out of memory".to_string(),
+        _ => format!("unknown error code: {}", errcode),
+    };
+    match error_site(site) {
+        Some(description) => eprintln!("{} in {}", msg, description),
+        None => eprintln!("{}", msg),
     }
     if REPL.load(Ordering::SeqCst) {
         panic!("Runtime error");
@@ -57,23 +95,52 @@ pub extern "C" fn snek_error(errcode: i64) {
     }
 }
 
-#[export_name = "\x01snek_print"]
+#[cfg_attr(target_os = "macos", export_name = "\x01snek_print")]
+#[cfg_attr(not(target_os = "macos"), no_mangle)]
 pub extern "C" fn snek_print(val: i64) -> i64 {
     // Print the value and return it
-    println!("{}", if val & 1 == 0 {
-        format!("{}", val >> 1)
-    } else if val == 1 {
-        "true".to_string()
-    } else if val == 3 {
-        "false".to_string()
-    } else {
-        format!("Unknown value: {}", val)
-    });
+    println!("{}", format_value(val));
     val
 }
 
 const TRUE_VAL: i64 = 1;   // 0b01
 const FALSE_VAL: i64 = 3;  // 0b11
+const TUPLE_TAG: i64 = 5;  // 0b101, in the low bits of a tuple pointer
+
+// Tuples print as `(tuple 1 (tuple true 2))`; one inside itself as `...`
+fn format_value(val: i64) -> String {
+    let mut text = String::new();
+    write_value(&mut text, val, &mut Vec::new());
+    text
+}
+
+fn write_value(text: &mut String, val: i64, enclosing: &mut Vec<i64>) {
+    if val & 1 == 0 {
+        text.push_str(&(val >> 1).to_string());
+    } else if val == TRUE_VAL {
+        text.push_str("true");
+    } else if val == FALSE_VAL {
+        text.push_str("false");
+    } else if val & 7 == TUPLE_TAG {
+        if enclosing.contains(&val) {
+            text.push_str("...");
+            return;
+        }
+        enclosing.push(val);
+        // A length word, tagged like a number, then the elements
+        let words = (val - TUPLE_TAG) as *const i64;
+        let len = unsafe { *words } >> 1;
+        text.push_str("(tuple");
+        for k in 1..=len {
+            text.push(' ');
+            write_value(text, unsafe { *words.offset(k as isize) }, enclosing);
+        }
+        text.push(')');
+        enclosing.pop();
+    } else {
+        text.push_str(&format!("Unknown value: {}", val));
+    }
+}
 
 fn parse_input(input: &str) -> i64 {
     match input {
@@ -97,20 +164,11 @@ fn parse_input(input: &str) -> i64 {
 }
 
 fn print_result(val: i64) {
-    if val & 1 == 1 {
-        // It's a boolean
-        if val == TRUE_VAL {
-            println!("true");
-        } else if val == FALSE_VAL {
-            println!("false");
-        } else {
-            eprintln!("Invalid boolean value: {}", val);
-            std::process::exit(1);
-        }
-    } else {
-        // It's a number - shift right to get actual value
-        println!("{}", val >> 1);
+    if val & 1 == 1 && val != TRUE_VAL && val != FALSE_VAL && val & 7 != TUPLE_TAG {
+        eprintln!("Invalid boolean value: {}", val);
+        std::process::exit(1);
     }
+    println!("{}", format_value(val));
 }
 
 fn main() {
@@ -123,6 +181,9 @@ fn main() {
         FALSE_VAL 
     };
     
-    let result: i64 = unsafe { our_code_starts_here(input) };
+    // Heap for the input slot, other global state and tuples, addressed
+    // through R15
+    let mut heap: Vec<i64> = vec![0; unsafe { SNEK_HEAP_WORDS } as usize];
+    let result: i64 = unsafe { our_code_starts_here(input, heap.as_mut_ptr()) };
     print_result(result);
 }
\ No newline at end of file
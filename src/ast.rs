@@ -1,10 +1,17 @@
 // src/ast.rs
+use crate::span::Span;
+
+/// The largest and smallest numbers a tagged 63-bit snek value holds
+pub const MAX_NUM: i64 = (1 << 62) - 1;
+pub const MIN_NUM: i64 = -(1 << 62);
+
 #[derive(Debug, Clone)]
 pub enum Op1 {
     Add1,
     Sub1,
     IsNum,
     IsBool,
+    Not,
     Print
 }
 
@@ -13,29 +20,69 @@ pub enum Op2 {
     Plus,
     Minus,
     Times,
+    Divide,
+    Remainder,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
     Equal, 
     Greater, 
     GreaterEqual, 
     Less,
     LessEqual,
+    // Element of a tuple, counting from 0
+    Index,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub name: String,
+    pub expr: Expr,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
-pub enum Expr {
-    Number(i32),
+pub enum ExprKind {
+    Number(i64),
     Id(String),
     Input,
-    Let(Vec<(String, Expr)>, Box<Expr>),
+    Let(Vec<Binding>, Box<Expr>),
     UnOp(Op1, Box<Expr>),
     BinOp(Op2, Box<Expr>, Box<Expr>),
     Boolean(bool),
     If(Box<Expr>, Box<Expr>, Box<Expr>),  
     Block(Vec<Expr>),  
     Set(String, Box<Expr>),  
-    Loop(Box<Expr>),    
-    Break(Box<Expr>),  
+    // Labeled `(loop :name body)` can be left from nested loops with `(break :name value)`
+    Loop(Option<String>, Box<Expr>),
+    Break(Option<String>, Box<Expr>),
+    // Loops that run out give false; a `break` still gives its value
+    While(Box<Expr>, Box<Expr>),
+    // `(for (i lo hi) body)` counts i from lo up to hi - 1
+    For(String, Box<Expr>, Box<Expr>, Box<Expr>),
+    Continue,
     Call(String, Vec<Expr>),  
     Cast(Box<Expr>, Type),
+    // Short-circuiting, over any number of booleans
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Tuple(Vec<Expr>),
+    // `(tuple-set! t i v)` gives v, like `set!`
+    TupleSet(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
 #[derive(Debug)]
@@ -61,6 +108,7 @@ pub struct FunDefn {
     pub body: Box<Expr>,
     pub param_types: Option<Vec<Type>>, 
     pub return_type: Option<Type>, 
+    pub span: Span,
 }
 
 //eastern
@@ -70,4 +118,5 @@ pub enum Type {
     Bool,
     Any,
     Nothing,
+    Tuple,
 }
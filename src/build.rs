@@ -0,0 +1,120 @@
+// src/build.rs
+// `cobra build`: assemble compiled code and link it against the runtime in
+// one step, working in a private temp directory.
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// runtime/start.rs, compiled into the binary so builds work from anywhere
+const RUNTIME_SOURCE: &str = include_str!("../runtime/start.rs");
+
+// Rust target and nasm object format for the host
+#[cfg(target_os = "macos")]
+const NATIVE: (&str, &str) = ("x86_64-apple-darwin", "macho64");
+#[cfg(not(target_os = "macos"))]
+const NATIVE: (&str, &str) = ("x86_64-unknown-linux-gnu", "elf64");
+
+/// What gets linked: assembly text for nasm, or an object file from the
+/// built-in ELF writer
+pub enum Code {
+    Asm(String),
+    Object(Vec<u8>),
+}
+
+/// A build step that failed, with the tool's own output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildError {
+    pub step: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "build failed while {}: {}", self.step, self.message)
+    }
+}
+
+/// Scratch directory removed when dropped, whether or not the build worked
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<Self, BuildError> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let path = std::env::temp_dir().join(format!("cobra-build-{}-{}", std::process::id(), nanos));
+        fs::create_dir_all(&path).map_err(|e| BuildError {
+            step: "creating a temp directory",
+            message: format!("{}: {}", path.display(), e),
+        })?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn write_file(step: &'static str, path: &Path, contents: &[u8]) -> Result<(), BuildError> {
+    fs::write(path, contents).map_err(|e| BuildError { step, message: format!("{}: {}", path.display(), e) })
+}
+
+/// Runs one tool, turning a missing program or a non-zero exit into a
+/// BuildError carrying its stderr
+fn run_step(step: &'static str, cmd: &mut Command) -> Result<(), BuildError> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd.output().map_err(|e| BuildError {
+        step,
+        message: format!("could not run `{}` ({}); is it installed and on PATH?", program, e),
+    })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(BuildError {
+            step,
+            message: format!("`{}` exited with {}\n{}", program, output.status, stderr.trim_end()),
+        });
+    }
+    Ok(())
+}
+
+/// Turn compiled code into an executable at `output`
+pub fn build_executable(code: &Code, output: &Path) -> Result<(), BuildError> {
+    let (target, format) = NATIVE;
+    let dir = TempDir::new()?;
+    let obj_path = dir.0.join("our_code.o");
+    let runtime_path = dir.0.join("start.rs");
+
+    write_file("writing the runtime", &runtime_path, RUNTIME_SOURCE.as_bytes())?;
+
+    match code {
+        Code::Asm(asm) => {
+            let asm_path = dir.0.join("our_code.s");
+            write_file("writing assembly", &asm_path, asm.as_bytes())?;
+            run_step(
+                "assembling",
+                Command::new("nasm").arg("-f").arg(format).arg(&asm_path).arg("-o").arg(&obj_path),
+            )?;
+        }
+        Code::Object(object) => write_file("writing the object file", &obj_path, object)?,
+    }
+
+    run_step(
+        "archiving",
+        Command::new("ar").arg("rcs").arg(dir.0.join("libour_code.a")).arg(&obj_path),
+    )?;
+    run_step(
+        "linking",
+        Command::new("rustc")
+            .arg("--target")
+            .arg(target)
+            .arg("-A")
+            .arg("warnings")
+            .arg("-L")
+            .arg(&dir.0)
+            .arg(&runtime_path)
+            .arg("-o")
+            .arg(output),
+    )
+}
@@ -1,38 +1,156 @@
 // src/compiler.rs
 use im::HashMap;
 use crate::ast::*;
+use crate::helpers::reserve_error_site;
 use crate::instr::*;
+use crate::ir::{self, lower_expr, lower_function, lower_program, visit_vars, AExpr, CExpr, Failure, Imm, Var};
+use crate::optimize::{optimize, optimize_program, OptLevel};
+use crate::peephole::peephole;
+use crate::regalloc::allocate_registers;
+use crate::span::{Span, describe_span};
+
+/// A runtime check in the generated code. Each site gets its own stub that
+/// loads the site id into RSI before jumping to the shared error handler, so
+/// snek_error can report which operation failed.
+#[derive(Debug, Clone)]
+pub struct ErrorSite {
+    pub id: usize,
+    pub handler: &'static str,
+    pub span: Span,
+}
 
-static mut LABEL_COUNTER: i32 = 0;
-static mut HEAP_OFFSET: i32 = 0;
-static mut INPUT_HEAP_OFFSET: Option<i32> = None;
+/// Words in the heap each entry point is handed in R15. AOT objects export
+/// it as `snek_heap_words` for the runtime to allocate.
+pub const HEAP_WORDS: usize = 128 * 1024;
+
+/// The heap starts with this many words of fixed slots: the input, REPL
+/// defines and the allocation count. Tuples are bump-allocated after them.
+const GLOBAL_WORDS: usize = 1024;
+
+/// Mutable state shared by everything compiled into one code buffer: label
+/// numbering, heap slot layout and pending error sites. AOT compilation uses
+/// a fresh context per program; the JIT and REPL keep one alive for as long
+/// as they keep emitting into the same buffer.
+#[derive(Debug, Default)]
+pub struct CompilerContext {
+    pub opt_level: OptLevel,
+    label_counter: i32,
+    heap_offset: i32,
+    input_heap_offset: Option<i32>,
+    alloc_heap_offset: Option<i32>,
+    next_error_site: usize,
+    // Take site ids from helpers::reserve_error_site instead of numbering
+    // them from 0
+    registered_sites: bool,
+    error_sites: Vec<ErrorSite>,
+    peephole_saved: usize,
+}
 
-fn new_label(prefix: &str) -> String {
-    unsafe {
-        LABEL_COUNTER += 1;
-        format!("{}_{}", prefix, LABEL_COUNTER)
+impl CompilerContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A context for code that reports its error sites through
+    /// helpers::register_error_site, whose ids must not clash with any
+    /// other context's in the process
+    pub fn with_registered_sites() -> Self {
+        Self { registered_sites: true, ..Self::default() }
+    }
+
+    fn new_label(&mut self, prefix: &str) -> String {
+        self.label_counter += 1;
+        format!("{}_{}", prefix, self.label_counter)
     }
-}
 
-fn alloc_heap_slot() -> i32 {
-    unsafe {
-        let offset = HEAP_OFFSET;
-        HEAP_OFFSET += 8;
+    pub fn alloc_heap_slot(&mut self) -> i32 {
+        if self.heap_offset as usize >= 8 * GLOBAL_WORDS {
+            panic!("Too many globals: at most {} fit on the heap", GLOBAL_WORDS);
+        }
+        let offset = self.heap_offset;
+        self.heap_offset += 8;
         offset
     }
+
+    /// Registers a check site and returns the label its check should jump to
+    fn error_site(&mut self, handler: &'static str, span: Span) -> String {
+        let id = if self.registered_sites {
+            reserve_error_site()
+        } else {
+            let id = self.next_error_site;
+            self.next_error_site += 1;
+            id
+        };
+        self.error_sites.push(ErrorSite { id, handler, span });
+        format!("error_site_{}", id)
+    }
+
+    /// Instructions the peephole optimizer has removed so far
+    pub fn peephole_saved(&self) -> usize {
+        self.peephole_saved
+    }
+
+    /// Removes and returns every site registered since the last call
+    pub fn take_error_sites(&mut self) -> Vec<ErrorSite> {
+        std::mem::take(&mut self.error_sites)
+    }
 }
 
-pub fn get_input_heap_offset() -> i32 {
-    unsafe {
-        if INPUT_HEAP_OFFSET.is_none() {
-            INPUT_HEAP_OFFSET = Some(alloc_heap_slot());
-        }
-        INPUT_HEAP_OFFSET.unwrap()
+pub fn get_input_heap_offset(ctx: &mut CompilerContext) -> i32 {
+    if ctx.input_heap_offset.is_none() {
+        ctx.input_heap_offset = Some(ctx.alloc_heap_slot());
+    }
+    ctx.input_heap_offset.unwrap()
+}
+
+/// The slot counting the bytes tuples have taken so far. The heap starts
+/// zeroed, so it needs no setup.
+fn get_alloc_heap_offset(ctx: &mut CompilerContext) -> i32 {
+    if ctx.alloc_heap_offset.is_none() {
+        ctx.alloc_heap_offset = Some(ctx.alloc_heap_slot());
+    }
+    ctx.alloc_heap_offset.unwrap()
+}
+
+/// `error_site_N: mov rsi, N; jmp <handler>` for each site
+pub fn error_site_stubs(sites: &[ErrorSite]) -> Vec<Instr> {
+    let mut code = Vec::new();
+    for site in sites {
+        code.push(Instr::ILabel(format!("error_site_{}", site.id)));
+        code.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Imm(site.id as i32)));
+        code.push(Instr::IJmp(site.handler.to_string()));
+    }
+    code
+}
+
+/// Descriptions for the AOT runtime's side table, in id order. Each becomes
+/// a NUL-terminated string, so quotes are swapped out for nasm's sake.
+pub fn error_site_descriptions(sites: &[ErrorSite], file: &str, source: &str) -> Vec<String> {
+    sites
+        .iter()
+        .map(|site| describe_span(file, source, site.span).replace('"', "'"))
+        .collect()
+}
+
+/// Side table for the AOT runtime: one NUL-terminated description per site,
+/// in id order, under the `snek_error_sites` symbol.
+fn error_site_table(descriptions: &[String]) -> String {
+    let mut table = String::from("\nsnek_error_sites:\n");
+    for desc in descriptions {
+        table.push_str(&format!("  db \"{}\", 0\n", desc));
     }
+    table.push_str("  db 0\n");
+    table
 }
 
+/// Registers carrying a snek function's first six arguments, in order. Any
+/// further arguments are pushed on the stack, last argument first.
+pub const ARG_REGS: [Reg; 6] = [Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX, Reg::R8, Reg::R9];
+
 pub struct FunContext {
     pub functions: HashMap<String, FunDefn>,
+    /// Calling convention shared by every function in the program
+    pub arg_regs: &'static [Reg],
 }
 
 impl FunContext {
@@ -48,7 +166,7 @@ impl FunContext {
             functions = functions.update(defn.name.clone(), defn.clone());
         }
         
-        FunContext { functions }
+        FunContext { functions, arg_regs: &ARG_REGS }
     }
     
     pub fn check_function_exists(&self, name: &str) -> bool {
@@ -58,61 +176,352 @@ impl FunContext {
     pub fn get_param_count(&self, name: &str) -> usize {
         self.functions.get(name).map(|f| f.params.len()).unwrap_or(0)
     }
+
+    /// Arguments of an `n`-argument call that don't fit in registers
+    pub fn stack_args(&self, n: usize) -> usize {
+        n.saturating_sub(self.arg_regs.len())
+    }
+
+    /// Words a caller reserves above the return address for an `n`-argument
+    /// call: the stack arguments plus the pad that keeps RSP aligned
+    pub fn stack_arg_slots(&self, n: usize) -> usize {
+        self.stack_args(n).next_multiple_of(2)
+    }
+
+    /// Where each parameter lives once the function is entered. Register
+    /// arguments are spilled to the top of the frame, from [rbp-8] down, so
+    /// the body is free to clobber the argument registers. Returns the
+    /// environment, the spill code and the first free stack slot.
+    pub fn param_env(&self, params: &[Var]) -> (HashMap<Var, i32>, Vec<Instr>, i32) {
+        let mut env = HashMap::new();
+        let mut spills = Vec::new();
+        let mut si = -8;
+        for (i, param) in params.iter().enumerate() {
+            match self.arg_regs.get(i) {
+                Some(reg) => {
+                    spills.push(Instr::IMov(Val::RegOffset(Reg::RBP, si), Val::Reg(*reg)));
+                    env.insert(param.clone(), si);
+                    si -= 8;
+                }
+                None => {
+                    let offset = 16 + 8 * (i - self.arg_regs.len()) as i32;
+                    env.insert(param.clone(), offset);
+                }
+            }
+        }
+        (env, spills, si)
+    }
 }
 
 const TRUE_VAL: i32 = 1;
 const FALSE_VAL: i32 = 3;
+// Tuples are 8-byte aligned pointers with 0b101 in the low bits. Booleans
+// have bit 2 clear, numbers bit 0.
+const TUPLE_TAG: i32 = 5;
+
+/// Where an immediate lives: constants are encoded in place, variables are
+/// read from their stack slot or, for REPL defines, from the heap
+fn imm_to_val(ctx: &mut CompilerContext, imm: &Imm, env: &HashMap<Var, i32>, defines: &HashMap<String, i32>) -> Val {
+    match imm {
+        // Parsing kept numbers in the 63-bit range, so shifting can't overflow
+        Imm::Num(n) => match i32::try_from(*n << 1) {
+            Ok(n) => Val::Imm(n),
+            Err(_) => Val::Imm64(*n << 1),
+        },
+        Imm::Bool(true) => Val::Imm(TRUE_VAL),
+        Imm::Bool(false) => Val::Imm(FALSE_VAL),
+        Imm::Input => Val::RegOffset(Reg::R15, get_input_heap_offset(ctx)),
+        Imm::Var(var) => var_to_val(var, env, defines),
+    }
+}
 
-pub fn compile_to_instrs(
-    e: &Expr,
+fn var_to_val(var: &Var, env: &HashMap<Var, i32>, defines: &HashMap<String, i32>) -> Val {
+    if let Some(&offset) = env.get(var) {
+        return Val::RegOffset(Reg::RBP, offset);
+    }
+    match var {
+        Var::Global(name) => match defines.get(name) {
+            Some(&heap_offset) => Val::RegOffset(Reg::R15, heap_offset),
+            None => panic!("Unbound variable identifier {}", name),
+        },
+        _ => panic!("Unbound variable identifier {}", var),
+    }
+}
+
+/// `test reg, 1; jne` over whichever of `regs` might not hold a number.
+/// Numeric constants need no check.
+fn check_nums(ctx: &mut CompilerContext, code: &mut Vec<Instr>, checks: &[(&Imm, Reg)], span: Span) {
+    let regs: Vec<Reg> = checks
+        .iter()
+        .filter(|(imm, _)| !matches!(imm, Imm::Num(_)))
+        .map(|(_, reg)| *reg)
+        .collect();
+    let tested = match regs.as_slice() {
+        [] => return,
+        [reg] => *reg,
+        [first, rest @ ..] => {
+            code.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(*first)));
+            for reg in rest {
+                code.push(Instr::IOr(Val::Reg(Reg::RDX), Val::Reg(*reg)));
+            }
+            Reg::RDX
+        }
+    };
+    code.push(Instr::ITest(Val::Reg(tested), Val::Imm(1)));
+    code.push(Instr::IJne(ctx.error_site("error_invalid_argument", span)));
+}
+
+/// Sets the flags so that `je` jumps when `reg` holds a boolean, using RDX
+/// as scratch
+fn test_bool(code: &mut Vec<Instr>, reg: Reg) {
+    code.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(reg)));
+    code.push(Instr::IAnd(Val::Reg(Reg::RDX), Val::Imm(TUPLE_TAG)));
+    code.push(Instr::ICmp(Val::Reg(Reg::RDX), Val::Imm(TRUE_VAL)));
+}
+
+/// Sets the flags so that `je` jumps when `reg` holds a tuple
+fn test_tuple(code: &mut Vec<Instr>, reg: Reg) {
+    code.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(reg)));
+    code.push(Instr::IAnd(Val::Reg(Reg::RDX), Val::Imm(7)));
+    code.push(Instr::ICmp(Val::Reg(Reg::RDX), Val::Imm(TUPLE_TAG)));
+}
+
+/// Fails with invalid argument unless `reg` holds a boolean, or `imm` is a
+/// boolean constant
+fn check_bool(ctx: &mut CompilerContext, code: &mut Vec<Instr>, imm: &Imm, reg: Reg, span: Span) {
+    if !matches!(imm, Imm::Bool(_)) {
+        test_bool(code, reg);
+        code.push(Instr::IJne(ctx.error_site("error_invalid_argument", span)));
+    }
+}
+
+/// With the tuple in RAX and the index in RCX, checks both and leaves RAX
+/// pointing 8 bytes before the element, just past the length word
+fn compile_element_address(ctx: &mut CompilerContext, code: &mut Vec<Instr>, index: &Imm, span: Span) {
+    check_nums(ctx, code, &[(index, Reg::RCX)], span);
+    test_tuple(code, Reg::RAX);
+    code.push(Instr::IJne(ctx.error_site("error_invalid_argument", span)));
+    code.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(TUPLE_TAG)));
+
+    // The length is tagged like the index, so they compare as they are
+    code.push(Instr::ICmp(Val::Reg(Reg::RCX), Val::Imm(0)));
+    code.push(Instr::IJl(ctx.error_site("error_index_out_of_bounds", span)));
+    code.push(Instr::IMov(Val::Reg(Reg::RDX), Val::RegOffset(Reg::RAX, 0)));
+    code.push(Instr::ICmp(Val::Reg(Reg::RCX), Val::Reg(Reg::RDX)));
+    code.push(Instr::IJge(ctx.error_site("error_index_out_of_bounds", span)));
+    code.push(Instr::IShl(Val::Reg(Reg::RCX), Val::Imm(2)));
+    code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+}
+
+/// `tail` is `Some(slots)` when `a` is in tail position of a function whose
+/// caller reserved `slots` argument words above the return address. Calls
+/// there reuse the current frame instead of growing the stack.
+#[allow(clippy::too_many_arguments)]
+fn compile_aexpr(
+    ctx: &mut CompilerContext,
+    a: &AExpr,
     si: i32,
-    env: &HashMap<String, i32>,
+    env: &HashMap<Var, i32>,
     defines: &HashMap<String, i32>,
     fun_ctx: &FunContext,
-    input: bool,
-    loop_end: &Option<String>,
+    loop_labels: &Option<LoopLabels>,
+    tail: Option<usize>,
 ) -> (Vec<Instr>, i32) {
-    let mut code: Vec<Instr> = Vec::new();
-    let mut current_min = si;
+    match a {
+        AExpr::Let(var, c, body) => {
+            if let Some(fused) = compile_fused_if(ctx, var, c, body, si, env, defines, fun_ctx, loop_labels, tail) {
+                return fused;
+            }
+            let (mut code, c_min) = compile_cexpr(ctx, c, si, env, defines, fun_ctx, loop_labels, None);
+            code.push(Instr::IMov(Val::RegOffset(Reg::RBP, si), Val::Reg(Reg::RAX)));
+            let env = env.update(var.clone(), si);
+            let (body_code, body_min) = compile_aexpr(ctx, body, si - 8, &env, defines, fun_ctx, loop_labels, tail);
+            code.extend(body_code);
+            (code, c_min.min(si).min(body_min))
+        }
+        AExpr::Seq(c, body) => {
+            let (mut code, c_min) = compile_cexpr(ctx, c, si, env, defines, fun_ctx, loop_labels, None);
+            let (body_code, body_min) = compile_aexpr(ctx, body, si, env, defines, fun_ctx, loop_labels, tail);
+            code.extend(body_code);
+            (code, c_min.min(body_min))
+        }
+        AExpr::Value(c) => compile_cexpr(ctx, c, si, env, defines, fun_ctx, loop_labels, tail),
+    }
+}
+
+/// A conditional jump, such as `Instr::IJne`, waiting for its label
+type Jump = fn(String) -> Instr;
+
+/// Where `break` and `continue` jump to in the innermost enclosing loop,
+/// and where a labeled `break` leaves each named loop around it
+#[derive(Debug, Clone)]
+struct LoopLabels {
+    start: String,
+    end: String,
+    named: HashMap<String, String>,
+}
 
-    match e {
-        Expr::Number(n) => {
-            code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(*n << 1)));
+/// For a comparison or type test, code that sets the flags, and the jump
+/// to take on them when it is false. No boolean is made along the way.
+fn compile_test(
+    ctx: &mut CompilerContext,
+    c: &CExpr,
+    env: &HashMap<Var, i32>,
+    defines: &HashMap<String, i32>,
+) -> Option<(Vec<Instr>, Jump)> {
+    let mut code = Vec::new();
+    let jump_if_false: Jump = match c {
+        CExpr::Prim1(Op1::IsNum, arg, _) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, arg, env, defines)));
+            code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
+            Instr::IJne
         }
-        Expr::Boolean(b) => {
-            let val = if *b { TRUE_VAL } else { FALSE_VAL };
-            code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(val)));
+        CExpr::Prim1(Op1::IsBool, arg, _) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, arg, env, defines)));
+            test_bool(&mut code, Reg::RAX);
+            Instr::IJne
         }
-        Expr::Input => {
-            let input_heap_offset = get_input_heap_offset();
-            code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::R15, input_heap_offset)));
+        CExpr::Prim1(Op1::Not, arg, span) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, arg, env, defines)));
+            check_bool(ctx, &mut code, arg, Reg::RAX, *span);
+            code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Imm(TRUE_VAL)));
+            Instr::IJe
         }
-        Expr::Id(name) => {
-            if let Some(&offset) = env.get(name) {
-                code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, offset)));
-            } else if let Some(&heap_offset) = defines.get(name) {
-                code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::R15, heap_offset)));
-            } else {
-                panic!("Unbound variable identifier {}", name);
+        CExpr::Prim2(op @ (Op2::Less | Op2::Greater | Op2::LessEqual | Op2::GreaterEqual | Op2::Equal), left, right, span) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, left, env, defines)));
+            code.push(Instr::IMov(Val::Reg(Reg::RCX), imm_to_val(ctx, right, env, defines)));
+            if !matches!(op, Op2::Equal) {
+                check_nums(ctx, &mut code, &[(left, Reg::RAX), (right, Reg::RCX)], *span);
+            }
+            code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+            match op {
+                Op2::Less => Instr::IJge,
+                Op2::Greater => Instr::IJle,
+                Op2::LessEqual => Instr::IJg,
+                Op2::GreaterEqual => Instr::IJl,
+                Op2::Equal => Instr::IJne,
+                _ => unreachable!(),
             }
         }
-        Expr::UnOp(op, expr) => {
-            let (mut expr_code, expr_min) = compile_to_instrs(expr, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(expr_min);
-            code.append(&mut expr_code);
+        _ => return None,
+    };
+    Some((code, jump_if_false))
+}
+
+/// `test` jumps to `else_label` when the condition is false
+#[allow(clippy::too_many_arguments)]
+fn compile_if(
+    ctx: &mut CompilerContext,
+    test: Vec<Instr>,
+    else_label: String,
+    then_branch: &AExpr,
+    else_branch: &AExpr,
+    si: i32,
+    env: &HashMap<Var, i32>,
+    defines: &HashMap<String, i32>,
+    fun_ctx: &FunContext,
+    loop_labels: &Option<LoopLabels>,
+    tail: Option<usize>,
+) -> (Vec<Instr>, i32) {
+    let end_label = ctx.new_label("endif");
+    let mut code = test;
+
+    let (then_code, then_min) = compile_aexpr(ctx, then_branch, si, env, defines, fun_ctx, loop_labels, tail);
+    code.extend(then_code);
+    code.push(Instr::IJmp(end_label.clone()));
+
+    code.push(Instr::ILabel(else_label));
+    let (else_code, else_min) = compile_aexpr(ctx, else_branch, si, env, defines, fun_ctx, loop_labels, tail);
+    code.extend(else_code);
+
+    code.push(Instr::ILabel(end_label));
+    (code, si.min(then_min).min(else_min))
+}
+
+/// `let var = c` followed by an `if` on `var` that is its only use: branch
+/// on `c` with a single compare and jump
+#[allow(clippy::too_many_arguments)]
+fn compile_fused_if(
+    ctx: &mut CompilerContext,
+    var: &Var,
+    c: &CExpr,
+    body: &AExpr,
+    si: i32,
+    env: &HashMap<Var, i32>,
+    defines: &HashMap<String, i32>,
+    fun_ctx: &FunContext,
+    loop_labels: &Option<LoopLabels>,
+    tail: Option<usize>,
+) -> Option<(Vec<Instr>, i32)> {
+    let (head, rest) = match body {
+        AExpr::Let(_, head, rest) | AExpr::Seq(head, rest) => (head, Some(rest)),
+        AExpr::Value(head) => (head, None),
+    };
+    let CExpr::If(Imm::Var(cond), then_branch, else_branch) = head else {
+        return None;
+    };
+    let mut uses = 0;
+    visit_vars(body, &mut |v| {
+        if v == var {
+            uses += 1;
+        }
+    });
+    if cond != var || uses != 1 {
+        return None;
+    }
+    let (mut test, jump_if_false) = compile_test(ctx, c, env, defines)?;
+    let else_label = ctx.new_label("else");
+    test.push(jump_if_false(else_label.clone()));
+
+    let if_tail = if rest.is_some() { None } else { tail };
+    let (mut code, if_min) = compile_if(ctx, test, else_label, then_branch, else_branch, si, env, defines, fun_ctx, loop_labels, if_tail);
+    let (rest_code, rest_min) = match body {
+        AExpr::Let(result, _, rest) => {
+            code.push(Instr::IMov(Val::RegOffset(Reg::RBP, si), Val::Reg(Reg::RAX)));
+            let env = env.update(result.clone(), si);
+            let (rest_code, rest_min) = compile_aexpr(ctx, rest, si - 8, &env, defines, fun_ctx, loop_labels, tail);
+            (rest_code, rest_min.min(si))
+        }
+        AExpr::Seq(_, rest) => compile_aexpr(ctx, rest, si, env, defines, fun_ctx, loop_labels, tail),
+        AExpr::Value(_) => (vec![], si),
+    };
+    code.extend(rest_code);
+    Some((code, if_min.min(rest_min)))
+}
+
+/// Leaves the value of `c` in RAX. Slots from `si` down are free for
+/// temporaries.
+#[allow(clippy::too_many_arguments)]
+fn compile_cexpr(
+    ctx: &mut CompilerContext,
+    c: &CExpr,
+    si: i32,
+    env: &HashMap<Var, i32>,
+    defines: &HashMap<String, i32>,
+    fun_ctx: &FunContext,
+    loop_labels: &Option<LoopLabels>,
+    tail: Option<usize>,
+) -> (Vec<Instr>, i32) {
+    let mut code: Vec<Instr> = Vec::new();
+    let mut current_min = si;
+
+    match c {
+        CExpr::Imm(imm) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, imm, env, defines)));
+        }
+        CExpr::Prim1(op, arg, span) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, arg, env, defines)));
 
             match op {
                 Op1::Add1 => {
-                    code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
-                    code.push(Instr::IJne("error_invalid_argument".to_string()));
+                    check_nums(ctx, &mut code, &[(arg, Reg::RAX)], *span);
                     code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1 << 1)));
-                    code.push(Instr::IJo("error_overflow".to_string()));
+                    code.push(Instr::IJo(ctx.error_site("error_overflow", *span)));
                 }
                 Op1::Sub1 => {
-                    code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
-                    code.push(Instr::IJne("error_invalid_argument".to_string()));
+                    check_nums(ctx, &mut code, &[(arg, Reg::RAX)], *span);
                     code.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(1 << 1)));
-                    code.push(Instr::IJo("error_overflow".to_string()));
+                    code.push(Instr::IJo(ctx.error_site("error_overflow", *span)));
                 }
                 Op1::IsNum => {
                     code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
@@ -121,73 +530,104 @@ pub fn compile_to_instrs(
                     code.push(Instr::ICMovE(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
                 }
                 Op1::IsBool => {
-                    code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
-                    code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(TRUE_VAL)));
-                    code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(FALSE_VAL)));
+                    test_bool(&mut code, Reg::RAX);
+                    code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(FALSE_VAL)));
+                    code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(TRUE_VAL)));
                     code.push(Instr::ICMovE(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
                 }
+                Op1::Not => {
+                    check_bool(ctx, &mut code, arg, Reg::RAX, *span);
+                    // Flips between true (1) and false (3)
+                    code.push(Instr::IXor(Val::Reg(Reg::RAX), Val::Imm(TRUE_VAL ^ FALSE_VAL)));
+                }
                 Op1::Print => {
-                    code.push(Instr::IPush(Val::Reg(Reg::RAX)));
+                    // snek_print returns its argument, so RAX survives the call
+                    // without a push that would misalign the stack
                     code.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Reg(Reg::RAX)));
                     code.push(Instr::ICall("snek_print".to_string()));
-                    code.push(Instr::IPop(Val::Reg(Reg::RAX)));
                 }
             }
         }
-        Expr::BinOp(op, left, right) => {
-            let (mut left_code, left_min) = compile_to_instrs(left, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(left_min);
-            code.append(&mut left_code);
-
-            code.push(Instr::IMov(Val::RegOffset(Reg::RBP, si), Val::Reg(Reg::RAX)));
-            current_min = current_min.min(si);
-
-            let (mut right_code, right_min) = compile_to_instrs(right, si - 8, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(right_min);
-            code.append(&mut right_code);
+        CExpr::Prim2(op, left, right, span) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, left, env, defines)));
+            code.push(Instr::IMov(Val::Reg(Reg::RCX), imm_to_val(ctx, right, env, defines)));
 
             match op {
                 Op2::Plus | Op2::Minus | Op2::Times => {
-                    code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
-                    code.push(Instr::IOr(Val::Reg(Reg::RCX), Val::RegOffset(Reg::RBP, si)));
-                    code.push(Instr::ITest(Val::Reg(Reg::RCX), Val::Imm(1)));
-                    code.push(Instr::IJne("error_invalid_argument".to_string()));
-
+                    check_nums(ctx, &mut code, &[(left, Reg::RAX), (right, Reg::RCX)], *span);
                     match op {
-                        Op2::Plus => {
-                            code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, si)));
-                            code.push(Instr::IJo("error_overflow".to_string()));
-                        }
-                        Op2::Minus => {
-                            code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
-                            code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, si)));
-                            code.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
-                            code.push(Instr::IJo("error_overflow".to_string()));
-                        }
+                        Op2::Plus => code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        Op2::Minus => code.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
                         Op2::Times => {
                             code.push(Instr::ISar(Val::Reg(Reg::RAX), Val::Imm(1)));
-                            code.push(Instr::IMul(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, si)));
-                            code.push(Instr::IJo("error_overflow".to_string()));
+                            code.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                        }
+                        _ => unreachable!(),
+                    }
+                    code.push(Instr::IJo(ctx.error_site("error_overflow", *span)));
+                }
+                Op2::Divide | Op2::Remainder => {
+                    check_nums(ctx, &mut code, &[(left, Reg::RAX), (right, Reg::RCX)], *span);
+                    code.push(Instr::ITest(Val::Reg(Reg::RCX), Val::Reg(Reg::RCX)));
+                    code.push(Instr::IJz(ctx.error_site("error_divide_by_zero", *span)));
+                    // Dividing 2a by 2b gives the untagged a / b, and a
+                    // remainder of 2(a % b) that is already tagged
+                    code.push(Instr::ICqo);
+                    code.push(Instr::IIdiv(Val::Reg(Reg::RCX)));
+                    match op {
+                        Op2::Divide => {
+                            // Only MIN_NUM / -1 leaves the 63-bit range
+                            code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RAX)));
+                            code.push(Instr::IJo(ctx.error_site("error_overflow", *span)));
                         }
+                        _ => code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX))),
+                    }
+                }
+                Op2::BitAnd | Op2::BitOr | Op2::BitXor => {
+                    check_nums(ctx, &mut code, &[(left, Reg::RAX), (right, Reg::RCX)], *span);
+                    // Both tag bits are zero, and so is the result's
+                    match op {
+                        Op2::BitAnd => code.push(Instr::IAnd(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        Op2::BitOr => code.push(Instr::IOr(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        Op2::BitXor => code.push(Instr::IXor(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
                         _ => unreachable!(),
                     }
                 }
+                Op2::Shl | Op2::Shr => {
+                    check_nums(ctx, &mut code, &[(left, Reg::RAX), (right, Reg::RCX)], *span);
+                    // Untag the count. Negative counts are invalid, and ones
+                    // past 63 shift out as much as 63 does.
+                    code.push(Instr::ISar(Val::Reg(Reg::RCX), Val::Imm(1)));
+                    code.push(Instr::ICmp(Val::Reg(Reg::RCX), Val::Imm(0)));
+                    code.push(Instr::IJl(ctx.error_site("error_invalid_argument", *span)));
+                    let counted = ctx.new_label("shift");
+                    code.push(Instr::ICmp(Val::Reg(Reg::RCX), Val::Imm(63)));
+                    code.push(Instr::IJle(counted.clone()));
+                    code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(63)));
+                    code.push(Instr::ILabel(counted));
+                    match op {
+                        Op2::Shl => {
+                            // Overflowed if shifting back doesn't restore it
+                            code.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(Reg::RAX)));
+                            code.push(Instr::IShl(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                            code.push(Instr::ISar(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                            code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+                            code.push(Instr::IJne(ctx.error_site("error_overflow", *span)));
+                            code.push(Instr::IShl(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                        }
+                        _ => {
+                            // Clear whatever was shifted into the tag bit
+                            code.push(Instr::ISar(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                            code.push(Instr::IAnd(Val::Reg(Reg::RAX), Val::Imm(-2)));
+                        }
+                    }
+                }
                 Op2::Less | Op2::Greater | Op2::LessEqual | Op2::GreaterEqual => {
-                    code.push(Instr::IMov(Val::RegOffset(Reg::RBP, si - 8), Val::Reg(Reg::RAX)));
-                    current_min = current_min.min(si - 8);
-                    
-                    code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
-                    code.push(Instr::IOr(Val::Reg(Reg::RCX), Val::RegOffset(Reg::RBP, si)));
-                    code.push(Instr::ITest(Val::Reg(Reg::RCX), Val::Imm(1)));
-                    code.push(Instr::IJne("error_invalid_argument".to_string()));
-                    
-                    code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::RegOffset(Reg::RBP, si)));
-                    code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, si - 8)));
-                    code.push(Instr::ICmp(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
-                    
+                    check_nums(ctx, &mut code, &[(left, Reg::RAX), (right, Reg::RCX)], *span);
+                    code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
                     code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(FALSE_VAL)));
                     code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(TRUE_VAL)));
-                    
+
                     match op {
                         Op2::Less => code.push(Instr::ICMovL(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
                         Op2::Greater => code.push(Instr::ICMovG(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
@@ -196,160 +636,179 @@ pub fn compile_to_instrs(
                         _ => unreachable!(),
                     }
                 }
+                Op2::Index => {
+                    compile_element_address(ctx, &mut code, right, *span);
+                    code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 8)));
+                }
                 Op2::Equal => {
-                    code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, si)));
+                    code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
                     code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(TRUE_VAL)));
                     code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(FALSE_VAL)));
                     code.push(Instr::ICMovNE(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
                 }
             }
         }
-        Expr::Set(name, expr) => {
-            let (mut expr_code, expr_min) = compile_to_instrs(expr, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(expr_min);
-            code.append(&mut expr_code);
-
-            if let Some(offset) = env.get(name) {
-                code.push(Instr::IMov(Val::RegOffset(Reg::RBP, *offset), Val::Reg(Reg::RAX)));
-                current_min = current_min.min(*offset);
-            } else if let Some(&heap_offset) = defines.get(name) {
-                code.push(Instr::IMov(Val::RegOffset(Reg::R15, heap_offset), Val::Reg(Reg::RAX)));
-            } else {
-                panic!("Unbound variable identifier {}", name);
+        CExpr::Cast(arg, target_type, span) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, arg, env, defines)));
+
+            // Runtime type check for cast
+            match target_type {
+                Type::Num => {
+                    code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
+                    code.push(Instr::IJne(ctx.error_site("error_bad_cast", *span)));
+                }
+                Type::Bool => {
+                    test_bool(&mut code, Reg::RAX);
+                    code.push(Instr::IJne(ctx.error_site("error_bad_cast", *span)));
+                }
+                Type::Tuple => {
+                    test_tuple(&mut code, Reg::RAX);
+                    code.push(Instr::IJne(ctx.error_site("error_bad_cast", *span)));
+                }
+                Type::Nothing => {
+                    code.push(Instr::IJmp(ctx.error_site("error_bad_cast", *span)));
+                }
+                Type::Any => {
+                    // No check needed
+                }
             }
         }
-        Expr::If(cond, then_expr, else_expr) => {
-            let else_label = new_label("else");
-            let end_label = new_label("endif");
-
-            let (mut cond_code, cond_min) = compile_to_instrs(cond, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(cond_min);
-            code.append(&mut cond_code);
-
-            code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Imm(FALSE_VAL)));
-            code.push(Instr::IJe(else_label.clone()));
-
-            let (mut then_code, then_min) = compile_to_instrs(then_expr, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(then_min);
-            code.append(&mut then_code);
-            code.push(Instr::IJmp(end_label.clone()));
-
-            code.push(Instr::ILabel(else_label));
-            let (mut else_code, else_min) = compile_to_instrs(else_expr, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(else_min);
-            code.append(&mut else_code);
-
-            code.push(Instr::ILabel(end_label));
+        CExpr::Set(var, value) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, value, env, defines)));
+            code.push(Instr::IMov(var_to_val(var, env, defines), Val::Reg(Reg::RAX)));
         }
-        Expr::Block(exprs) => {
-            for expr in exprs {
-                let (mut expr_code, expr_min) = compile_to_instrs(expr, si, env, defines, fun_ctx, input, loop_end);
-                current_min = current_min.min(expr_min);
-                code.append(&mut expr_code);
+        CExpr::Tuple(elements, span) => {
+            // A length word, tagged like a number, then the elements
+            let size = 8 * (elements.len() as i32 + 1);
+            let alloc = Val::RegOffset(Reg::R15, get_alloc_heap_offset(ctx));
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), alloc.clone()));
+            code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(size)));
+            code.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Imm(8 * (HEAP_WORDS - GLOBAL_WORDS) as i32)));
+            code.push(Instr::IJg(ctx.error_site("error_out_of_memory", *span)));
+            code.push(Instr::IMov(alloc, Val::Reg(Reg::RAX)));
+            code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::R15)));
+            code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(8 * GLOBAL_WORDS as i32 - size)));
+
+            code.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm((elements.len() as i32) << 1)));
+            code.push(Instr::IMov(Val::RegOffset(Reg::RAX, 0), Val::Reg(Reg::RCX)));
+            for (k, element) in elements.iter().enumerate() {
+                code.push(Instr::IMov(Val::Reg(Reg::RCX), imm_to_val(ctx, element, env, defines)));
+                code.push(Instr::IMov(Val::RegOffset(Reg::RAX, 8 * (k as i32 + 1)), Val::Reg(Reg::RCX)));
             }
+            code.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(TUPLE_TAG)));
         }
-        Expr::Let(bindings, body) => {
-            let mut new_env = env.clone();
-            let mut current_si = si;
-            let mut local_min = current_min;
-
-            let mut seen_names = HashMap::new();
-            for (name, _) in bindings {
-                if seen_names.contains_key(name) {
-                    panic!("Duplicate binding");
-                }
-                seen_names = seen_names.update(name.clone(), ());
-            }
-
-            for (name, expr) in bindings {
-                let (mut expr_code, expr_min) = compile_to_instrs(expr, current_si - 8, &new_env, defines, fun_ctx, input, loop_end);
-                local_min = local_min.min(expr_min);
-                code.append(&mut expr_code);
-
-                code.push(Instr::IMov(Val::RegOffset(Reg::RBP, current_si), Val::Reg(Reg::RAX)));
-                new_env = new_env.update(name.clone(), current_si);
-                local_min = local_min.min(current_si);
-
-                current_si -= 8;
-            }
-
-            let (mut body_code, body_min) = compile_to_instrs(body, current_si, &new_env, defines, fun_ctx, input, loop_end);
-            local_min = local_min.min(body_min);
-            code.append(&mut body_code);
-
-            current_min = current_min.min(local_min);
+        CExpr::TupleSet(tuple, index, value, span) => {
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, tuple, env, defines)));
+            code.push(Instr::IMov(Val::Reg(Reg::RCX), imm_to_val(ctx, index, env, defines)));
+            compile_element_address(ctx, &mut code, index, *span);
+            code.push(Instr::IMov(Val::Reg(Reg::RCX), imm_to_val(ctx, value, env, defines)));
+            code.push(Instr::IMov(Val::RegOffset(Reg::RAX, 8), Val::Reg(Reg::RCX)));
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
         }
-        Expr::Loop(body) => {
-            let loop_start = new_label("loop_start");
-            let loop_end_label = new_label("loop_end");
+        CExpr::If(cond, then_branch, else_branch) => {
+            let else_label = ctx.new_label("else");
+            let test = vec![
+                Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, cond, env, defines)),
+                Instr::ICmp(Val::Reg(Reg::RAX), Val::Imm(FALSE_VAL)),
+                Instr::IJe(else_label.clone()),
+            ];
+            return compile_if(ctx, test, else_label, then_branch, else_branch, si, env, defines, fun_ctx, loop_labels, tail);
+        }
+        CExpr::Loop(label, body) => {
+            let (start, end) = (ctx.new_label("loop_start"), ctx.new_label("loop_end"));
+            let mut named = loop_labels.as_ref().map(|outer| outer.named.clone()).unwrap_or_default();
+            if let Some(label) = label {
+                named.insert(label.clone(), end.clone());
+            }
+            let labels = LoopLabels { start, end, named };
 
-            code.push(Instr::ILabel(loop_start.clone()));
-            let (mut body_code, body_min) = compile_to_instrs(body, si, env, defines, fun_ctx, input, &Some(loop_end_label.clone()));
+            code.push(Instr::ILabel(labels.start.clone()));
+            let (body_code, body_min) = compile_aexpr(ctx, body, si, env, defines, fun_ctx, &Some(labels.clone()), None);
             current_min = current_min.min(body_min);
-            code.append(&mut body_code);
-            code.push(Instr::IJmp(loop_start));
-            code.push(Instr::ILabel(loop_end_label));
+            code.extend(body_code);
+            code.push(Instr::IJmp(labels.start));
+            code.push(Instr::ILabel(labels.end));
         }
-        Expr::Break(expr) => {
-            let loop_end_label = loop_end.as_ref().expect("break outside of loop").clone();
-            let (mut expr_code, expr_min) = compile_to_instrs(expr, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(expr_min);
-            code.append(&mut expr_code);
-            code.push(Instr::IJmp(loop_end_label));
+        CExpr::Break(label, value) => {
+            // Loops and labels were checked before lowering
+            let labels = loop_labels.as_ref().expect("break outside of loop");
+            let target = match label {
+                Some(label) => &labels.named[label],
+                None => &labels.end,
+            };
+            code.push(Instr::IMov(Val::Reg(Reg::RAX), imm_to_val(ctx, value, env, defines)));
+            code.push(Instr::IJmp(target.clone()));
         }
-        Expr::Call(name, args) => {
-            if !fun_ctx.check_function_exists(name) {
-                panic!("Undefined function: {}", name);
-            }
-            let expected = fun_ctx.get_param_count(name);
-            if args.len() != expected {
-                panic!("Wrong number of arguments for {}: expected {}, got {}", name, expected, args.len());
+        CExpr::Continue => {
+            let labels = loop_labels.as_ref().expect("continue outside of loop");
+            code.push(Instr::IJmp(labels.start.clone()));
+        }
+        CExpr::Fail(failure, span) => {
+            let handler = match failure {
+                Failure::Overflow => "error_overflow",
+                Failure::InvalidArgument => "error_invalid_argument",
+                Failure::BadCast => "error_bad_cast",
+                Failure::DivideByZero => "error_divide_by_zero",
+            };
+            code.push(Instr::IJmp(ctx.error_site(handler, *span)));
+        }
+        CExpr::Call(name, args) => {
+            let args: Vec<Val> = args.iter().map(|arg| imm_to_val(ctx, arg, env, defines)).collect();
+            let stack_args = fun_ctx.stack_args(args.len());
+            let reg_args = args.len() - stack_args;
+
+            // Tail call: overwrite our own stack arguments, tear down the
+            // frame and jump. The callee returns straight to our caller, who
+            // pops the argument words it pushed for us.
+            if tail.is_some_and(|slots| stack_args <= slots) {
+                // Any argument may be read from our old stack arguments, so
+                // the new stack arguments and every register argument read
+                // from one go through temporaries before the first is
+                // overwritten
+                let temp = |k: usize| si - 8 * k as i32;
+                let mut args = args;
+                let mut temps = 0;
+                for (i, arg) in args.iter_mut().enumerate() {
+                    let reads_stack_arg = matches!(arg, Val::RegOffset(Reg::RBP, offset) if *offset > 0);
+                    if i >= reg_args || (stack_args > 0 && reads_stack_arg) {
+                        code.push(Instr::IMov(Val::Reg(Reg::RAX), arg.clone()));
+                        code.push(Instr::IMov(Val::RegOffset(Reg::RBP, temp(temps)), Val::Reg(Reg::RAX)));
+                        current_min = current_min.min(temp(temps));
+                        *arg = Val::RegOffset(Reg::RBP, temp(temps));
+                        temps += 1;
+                    }
+                }
+                for (k, arg) in args[reg_args..].iter().enumerate() {
+                    code.push(Instr::IMov(Val::Reg(Reg::RAX), arg.clone()));
+                    code.push(Instr::IMov(Val::RegOffset(Reg::RBP, 16 + 8 * k as i32), Val::Reg(Reg::RAX)));
+                }
+                for (reg, arg) in fun_ctx.arg_regs.iter().zip(&args[..reg_args]) {
+                    code.push(Instr::IMov(Val::Reg(*reg), arg.clone()));
+                }
+                code.push(Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)));
+                code.push(Instr::IPop(Val::Reg(Reg::RBP)));
+                code.push(Instr::IJmp(format!("fun_{}", name)));
+                return (code, current_min);
             }
-            let arg_bytes = (args.len() * 8) as i32;
 
-            let needs_pad = (arg_bytes + 8) % 16 != 0;
+            // Keep rsp 16-byte aligned at the call instruction
+            let needs_pad = !stack_args.is_multiple_of(2);
             if needs_pad {
                 code.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(8)));
             }
-            
-            for arg in args.iter().rev() {
-                let (mut arg_code, arg_min) = compile_to_instrs(arg, si, env, defines, fun_ctx, input, loop_end);
-                current_min = current_min.min(arg_min);
-                code.append(&mut arg_code);
+            for arg in args[reg_args..].iter().rev() {
+                code.push(Instr::IMov(Val::Reg(Reg::RAX), arg.clone()));
                 code.push(Instr::IPush(Val::Reg(Reg::RAX)));
             }
-            
-            code.push(Instr::ICall(format!("fun_{}", name)));
-            
-            if arg_bytes > 0 {
-                code.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(arg_bytes)));
+            for (reg, arg) in fun_ctx.arg_regs.iter().zip(&args[..reg_args]) {
+                code.push(Instr::IMov(Val::Reg(*reg), arg.clone()));
             }
-            
-            if needs_pad {
-                code.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(8)));
-            }
-        }
-        Expr::Cast(expr, target_type) => {
-            let (mut expr_code, expr_min) = compile_to_instrs(expr, si, env, defines, fun_ctx, input, loop_end);
-            current_min = current_min.min(expr_min);
-            code.append(&mut expr_code);
-            
-            // Runtime type check for cast
-            match target_type {
-                Type::Num => {
-                    code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
-                    code.push(Instr::IJne("error_bad_cast".to_string()));
-                }
-                Type::Bool => {
-                    code.push(Instr::ITest(Val::Reg(Reg::RAX), Val::Imm(1)));
-                    code.push(Instr::IJe("error_bad_cast".to_string()));
-                }
-                Type::Nothing => {
-                    code.push(Instr::IJmp("error_bad_cast".to_string()));
-                }
-                Type::Any => {
-                    // No check needed
-                }
+
+            code.push(Instr::ICall(format!("fun_{}", name)));
+
+            let stack_bytes = (fun_ctx.stack_arg_slots(args.len()) * 8) as i32;
+            if stack_bytes > 0 {
+                code.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(stack_bytes)));
             }
         }
     }
@@ -357,127 +816,176 @@ pub fn compile_to_instrs(
     (code, current_min)
 }
 
-pub fn compile(program: &Program) -> String {
-    unsafe {
-        LABEL_COUNTER = 0;
-        HEAP_OFFSET = 0;
-        INPUT_HEAP_OFFSET = None;
-    }
-    
+/// Symbols shared with runtime/start.rs. The names are plain on every
+/// target; the runtime takes care of Mach-O's leading underscore. ELF objects
+/// also get an empty .note.GNU-stack so the linker keeps the stack
+/// non-executable.
+const ASM_HEADER: &str = "\
+%ifidn __OUTPUT_FORMAT__, elf64
+section .note.GNU-stack noalloc noexec nowrite progbits
+%endif
+section .text
+global our_code_starts_here
+global snek_error_sites
+global snek_heap_words
+extern snek_error
+extern snek_print
+
+";
+
+/// The whole AOT program as instructions: functions, the
+/// `our_code_starts_here` entry, error site stubs and error handlers. Calls
+/// to `snek_error` and `snek_print` are left for the runtime to provide.
+pub fn compile_program(ctx: &mut CompilerContext, program: &Program) -> Vec<Instr> {
     let fun_ctx = FunContext::new(&program.defns);
-    let mut asm_code = String::new();
+    let mut code = Vec::new();
     
     for defn in &program.defns {
         check_no_input(&defn.body);
     }
     
-    for defn in &program.defns {
-        asm_code.push_str(&compile_function(defn, &fun_ctx));
+    let lowered = optimize_program(lower_program(program, &fun_ctx), &fun_ctx, ctx.opt_level);
+    for function in &lowered.functions {
+        code.extend(compile_ir_function(ctx, function, &HashMap::new(), &fun_ctx));
     }
     
-    asm_code.push_str("our_code_starts_here:\n");
-    asm_code.push_str("  push rbp\n");
-    asm_code.push_str("  mov rbp, rsp\n");
-    
-    let input_heap_offset = get_input_heap_offset();
-    asm_code.push_str(&format!("  mov [r15 + {}], rdi\n", input_heap_offset));
-    
-    let (instrs, min_offset) = compile_to_instrs(
-        &program.main, 
-        -8, 
-        &HashMap::new(), 
-        &HashMap::new(),
-        &fun_ctx,
-        true, 
-        &None
-    );
-
-    // Allocate stack space for local variables if needed
-    // Only allocate if we've actually used stack slots (min_offset < -8 means we used [rbp-16] or lower)
-    if min_offset <= -16 {
-        let needed = -min_offset - 8;  // Subtract 8 because -8 is just the starting point
-        let stack_space = ((needed + 15) / 16) * 16;
-        asm_code.push_str(&format!("  sub rsp, {}\n", stack_space));
+    // our_code_starts_here(input, heap): R15 is callee-saved for the runtime,
+    // so keep its value while main uses it as the heap base
+    let input_heap_offset = get_input_heap_offset(ctx);
+    code.extend([
+        Instr::ILabel("our_code_starts_here".to_string()),
+        Instr::IPush(Val::Reg(Reg::R15)),
+        Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RSI)),
+        Instr::IMov(Val::RegOffset(Reg::R15, input_heap_offset), Val::Reg(Reg::RDI)),
+        Instr::ICall("snek_main".to_string()),
+        Instr::IPop(Val::Reg(Reg::R15)),
+        Instr::IRet,
+        Instr::ILabel("snek_main".to_string()),
+    ]);
+    code.extend(compile_ir_main(ctx, &lowered.main, &HashMap::new(), &fun_ctx));
+
+    code.extend(error_site_stubs(&ctx.error_sites));
+
+    for (handler, errcode) in [
+        ("error_overflow", 1),
+        ("error_invalid_argument", 2),
+        ("error_bad_cast", 3),
+        ("error_divide_by_zero", 4),
+        ("error_index_out_of_bounds", 5),
+        ("error_out_of_memory", 6),
+    ] {
+        code.extend([
+            Instr::ILabel(handler.to_string()),
+            Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(errcode)),
+            Instr::IAnd(Val::Reg(Reg::RSP), Val::Imm(-16)),
+            Instr::ICall("snek_error".to_string()),
+            Instr::IRet,
+        ]);
     }
+    
+    code
+}
 
-    for instr in instrs {
+pub fn compile(program: &Program, file: &str, source: &str, opt_level: OptLevel) -> String {
+    let ctx = &mut CompilerContext::new();
+    ctx.opt_level = opt_level;
+    let mut asm_code = String::from(ASM_HEADER);
+
+    for instr in compile_program(ctx, program) {
+        if let Instr::ILabel(_) = instr {
+            asm_code.push('\n');
+        }
         asm_code.push_str(&instr_to_str(&instr));
         asm_code.push('\n');
     }
-    
-    asm_code.push_str("  mov rsp, rbp\n");
-    asm_code.push_str("  pop rbp\n");
-    asm_code.push_str("  ret\n");
-    
-    asm_code.push_str("\nerror_overflow:\n");
-    asm_code.push_str("  mov rdi, 1\n");
-    asm_code.push_str("  call snek_error\n");
-    asm_code.push_str("  ret\n");
-    
-    asm_code.push_str("\nerror_invalid_argument:\n");
-    asm_code.push_str("  mov rdi, 2\n");
-    asm_code.push_str("  call snek_error\n");
-    asm_code.push_str("  ret\n");
-
-    asm_code.push_str("\nerror_bad_cast:\n");
-    asm_code.push_str("  mov rdi, 3\n");
-    asm_code.push_str("  call snek_error\n");
-    asm_code.push_str("  ret\n");
+
+    asm_code.push_str(&format!("\nalign 8\nsnek_heap_words:\n  dq {}\n", HEAP_WORDS));
+    let sites = ctx.take_error_sites();
+    asm_code.push_str(&error_site_table(&error_site_descriptions(&sites, file, source)));
+    asm_code.push_str(&format!("\n; peephole optimizer saved {} instructions\n", ctx.peephole_saved()));
     
     asm_code
 }
 
-fn compile_function(defn: &FunDefn, fun_ctx: &FunContext) -> String {
-    let mut code = String::new();
-    code.push_str(&format!("fun_{}:\n", defn.name));
-
-    code.push_str("  push rbp\n");
-    code.push_str("  mov rbp, rsp\n");
-
-    let mut env = HashMap::new();
-    for (i, param) in defn.params.iter().enumerate() {
-        let offset = 16 + (i as i32 * 8);
-        env.insert(param.clone(), offset);
-    }
+/// `mov rsp, rbp; pop rbp; ret`
+fn epilogue() -> [Instr; 3] {
+    [
+        Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)),
+        Instr::IPop(Val::Reg(Reg::RBP)),
+        Instr::IRet,
+    ]
+}
 
-    let (instrs, min_offset) = compile_to_instrs(
-        &defn.body,
-        -8,
-        &env,
-        &HashMap::new(),
-        fun_ctx,
-        false,
-        &None,
-    );
-
-    // Allocate stack space for local variables if needed
-    if min_offset < 0 {
-        let needed = -min_offset;
-        let stack_space = ((needed + 15) / 16) * 16;
-        code.push_str(&format!("  sub rsp, {}\n", stack_space));
+/// Wrap compiled code in a frame with room for every slot it uses down to
+/// `min_offset`, let the register allocator take what slots it can, then
+/// clean up with the peephole optimizer
+fn compile_frame(ctx: &mut CompilerContext, body: Vec<Instr>, min_offset: i32) -> Vec<Instr> {
+    let stack_space = ((-min_offset + 15) / 16) * 16;
+    let mut code = vec![
+        Instr::IPush(Val::Reg(Reg::RBP)),
+        Instr::IMov(Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)),
+        Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(stack_space)),
+    ];
+    code.extend(body);
+    code.extend(epilogue());
+    let code = allocate_registers(code);
+    if ctx.opt_level == OptLevel::O0 {
+        return code;
     }
+    let (code, saved) = peephole(code);
+    ctx.peephole_saved += saved;
+    code
+}
 
-    for instr in instrs {
-        code.push_str(&instr_to_str(&instr));
-        code.push('\n');
-    }
+/// A function from its `fun_<name>` label to its final `ret`
+pub fn compile_function(
+    ctx: &mut CompilerContext,
+    defn: &FunDefn,
+    defines: &HashMap<String, i32>,
+    fun_ctx: &FunContext,
+) -> Vec<Instr> {
+    let mut function = lower_function(defn, fun_ctx);
+    function.body = optimize(function.body, &function.params, fun_ctx, ctx.opt_level);
+    compile_ir_function(ctx, &function, defines, fun_ctx)
+}
 
-    code.push_str("  mov rsp, rbp\n");
-    code.push_str("  pop rbp\n");
-    code.push_str("  ret\n");
+pub fn compile_ir_function(
+    ctx: &mut CompilerContext,
+    function: &ir::Function,
+    defines: &HashMap<String, i32>,
+    fun_ctx: &FunContext,
+) -> Vec<Instr> {
+    let (env, spills, si) = fun_ctx.param_env(&function.params);
+    let tail = Some(fun_ctx.stack_arg_slots(function.params.len()));
+    let (instrs, min_offset) = compile_aexpr(ctx, &function.body, si, &env, defines, fun_ctx, &None, tail);
 
+    let mut code = vec![Instr::ILabel(format!("fun_{}", function.name))];
+    code.extend(compile_frame(ctx, spills.into_iter().chain(instrs).collect(), min_offset));
     code
 }
 
-fn check_no_input(expr: &Expr) {
-    if let Expr::Input = expr {
+/// The main expression as a routine returning its value. R15 must already
+/// hold the heap base.
+pub fn compile_main(ctx: &mut CompilerContext, main: &Expr, defines: &HashMap<String, i32>, fun_ctx: &FunContext) -> Vec<Instr> {
+    let main = optimize(lower_expr(main, fun_ctx), &[], fun_ctx, ctx.opt_level);
+    compile_ir_main(ctx, &main, defines, fun_ctx)
+}
+
+pub fn compile_ir_main(ctx: &mut CompilerContext, main: &AExpr, defines: &HashMap<String, i32>, fun_ctx: &FunContext) -> Vec<Instr> {
+    let (instrs, min_offset) = compile_aexpr(ctx, main, -8, &HashMap::new(), defines, fun_ctx, &None, None);
+    compile_frame(ctx, instrs, min_offset)
+}
+
+pub fn check_no_input(expr: &Expr) {
+    if let ExprKind::Input = expr.kind {
         panic!("input not allowed in function definitions");
     }
 }
 
-pub fn compile_define(name: &str, expr: &Expr, defines: &HashMap<String, i32>, fun_ctx: &FunContext) -> (i32, Vec<Instr>) {
-    let heap_offset = alloc_heap_slot();
-    let (mut code, _) = compile_to_instrs(expr, -8, &HashMap::new(), defines, fun_ctx, false, &None);
+pub fn compile_define(ctx: &mut CompilerContext, expr: &Expr, defines: &HashMap<String, i32>, fun_ctx: &FunContext) -> (i32, Vec<Instr>) {
+    let heap_offset = ctx.alloc_heap_slot();
+    let expr = optimize(lower_expr(expr, fun_ctx), &[], fun_ctx, ctx.opt_level);
+    let (mut code, min_offset) = compile_aexpr(ctx, &expr, -8, &HashMap::new(), defines, fun_ctx, &None, None);
     code.push(Instr::IMov(Val::RegOffset(Reg::R15, heap_offset), Val::Reg(Reg::RAX)));
-    (heap_offset, code)
+    (heap_offset, compile_frame(ctx, code, min_offset))
 }
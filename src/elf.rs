@@ -0,0 +1,301 @@
+// src/elf.rs
+// Writes AOT code straight to a relocatable x86-64 ELF object, encoding the
+// same Vec<Instr> as the assembly text with the JIT's instr_to_dynasm.
+use std::collections::HashMap as StdHashMap;
+
+use dynasmrt::x64::X64Relocation;
+use dynasmrt::{DynasmApi, VecAssembler};
+
+use crate::ast::Program;
+use crate::compiler::{compile_program, error_site_descriptions, CompilerContext, HEAP_WORDS};
+use crate::instr::Instr;
+use crate::jit::emit_instrs;
+use crate::optimize::OptLevel;
+
+/// Functions the runtime provides; calls to them become relocations
+const EXTERNS: [&str; 2] = ["snek_error", "snek_print"];
+
+const R_X86_64_PLT32: u64 = 4;
+
+/// A call to an external symbol: patch the rel32 at `offset`
+struct Reloc {
+    offset: usize,
+    symbol: &'static str,
+}
+
+/// Compile a program to an ELF object exporting `our_code_starts_here`,
+/// `snek_heap_words` and `snek_error_sites`, for linking with runtime/start.rs
+pub fn compile_to_object(program: &Program, file: &str, source: &str, opt_level: OptLevel) -> Vec<u8> {
+    let ctx = &mut CompilerContext::new();
+    ctx.opt_level = opt_level;
+    let instrs = compile_program(ctx, program);
+    let descriptions = error_site_descriptions(&ctx.take_error_sites(), file, source);
+
+    let mut ops: VecAssembler<X64Relocation> = VecAssembler::new(0);
+    let mut label_map = StdHashMap::new();
+    let mut relocs = Vec::new();
+
+    // Extern calls are encoded by hand as `call rel32` with a zero
+    // displacement; everything in between goes through instr_to_dynasm
+    let mut pending = Vec::new();
+    for instr in instrs {
+        match instr {
+            Instr::ICall(ref name) if EXTERNS.contains(&name.as_str()) => {
+                emit_instrs(&pending, &mut ops, &mut label_map);
+                pending.clear();
+                ops.push(0xE8);
+                relocs.push(Reloc {
+                    offset: ops.offset().0,
+                    symbol: EXTERNS.iter().find(|e| **e == name).unwrap(),
+                });
+                ops.push_i32(0);
+            }
+            _ => pending.push(instr),
+        }
+    }
+    emit_instrs(&pending, &mut ops, &mut label_map);
+
+    let entry = ops.labels().resolve_dynamic(label_map["our_code_starts_here"]).unwrap().0;
+    let mut text = ops.finalize().expect("could not resolve labels");
+
+    // The heap size and site table live at the end of .text, as in the
+    // assembly output
+    while !text.len().is_multiple_of(8) {
+        text.push(0);
+    }
+    let heap_words_offset = text.len();
+    text.extend_from_slice(&(HEAP_WORDS as u64).to_le_bytes());
+    let sites_offset = text.len();
+    for desc in &descriptions {
+        text.extend_from_slice(desc.as_bytes());
+        text.push(0);
+    }
+    text.push(0);
+
+    write_object(&text, entry, heap_words_offset, sites_offset, &relocs)
+}
+
+// Section header indices: null, .text, .rela.text, .symtab, .strtab,
+// .shstrtab, .note.GNU-stack
+const TEXT: u32 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u32 = 5;
+const SECTION_COUNT: u16 = 7;
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// Appends little-endian fields to the object file
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn align(&mut self, align: usize) {
+        while !self.0.len().is_multiple_of(align) {
+            self.0.push(0);
+        }
+    }
+}
+
+/// NUL-separated string table; returns each name's offset
+fn string_table(names: &[&str]) -> (Vec<u8>, Vec<u32>) {
+    let mut table = vec![0];
+    let mut offsets = Vec::new();
+    for name in names {
+        offsets.push(table.len() as u32);
+        table.extend_from_slice(name.as_bytes());
+        table.push(0);
+    }
+    (table, offsets)
+}
+
+fn write_object(text: &[u8], entry: usize, heap_words_offset: usize, sites_offset: usize, relocs: &[Reloc]) -> Vec<u8> {
+    const STB_LOCAL: u8 = 0;
+    const STB_GLOBAL: u8 = 1;
+    const STT_NOTYPE: u8 = 0;
+    const STT_OBJECT: u8 = 1;
+    const STT_FUNC: u8 = 2;
+    const STT_SECTION: u8 = 3;
+
+    // Symbols: null, the .text section, then the globals. Undefined externs
+    // come last, so extern i is symbol FIRST_EXTERN + i.
+    let (strtab, names) =
+        string_table(&["our_code_starts_here", "snek_error_sites", "snek_heap_words", EXTERNS[0], EXTERNS[1]]);
+    const FIRST_GLOBAL: u32 = 2;
+    const FIRST_EXTERN: u32 = 5;
+    let symbols: [(u32, u8, u16, usize); 7] = [
+        (0, 0, 0, 0),
+        (0, STB_LOCAL << 4 | STT_SECTION, TEXT as u16, 0),
+        (names[0], STB_GLOBAL << 4 | STT_FUNC, TEXT as u16, entry),
+        (names[1], STB_GLOBAL << 4 | STT_OBJECT, TEXT as u16, sites_offset),
+        (names[2], STB_GLOBAL << 4 | STT_OBJECT, TEXT as u16, heap_words_offset),
+        (names[3], STB_GLOBAL << 4 | STT_NOTYPE, 0, 0),
+        (names[4], STB_GLOBAL << 4 | STT_NOTYPE, 0, 0),
+    ];
+
+    let (shstrtab, section_names) = string_table(&[
+        ".text",
+        ".rela.text",
+        ".symtab",
+        ".strtab",
+        ".shstrtab",
+        ".note.GNU-stack",
+    ]);
+
+    let mut out = Writer(Vec::new());
+
+    // ELF header, section header offset patched in at the end
+    out.0.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.0.extend_from_slice(&[0; 8]);
+    out.u16(1); // ET_REL
+    out.u16(62); // EM_X86_64
+    out.u32(1);
+    out.u64(0); // entry
+    out.u64(0); // program headers
+    let shoff_at = out.0.len();
+    out.u64(0);
+    out.u32(0); // flags
+    out.u16(64); // header size
+    out.u16(0);
+    out.u16(0);
+    out.u16(64); // section header size
+    out.u16(SECTION_COUNT);
+    out.u16(SHSTRTAB as u16);
+
+    out.align(16);
+    let text_offset = out.0.len();
+    out.0.extend_from_slice(text);
+
+    out.align(8);
+    let rela_offset = out.0.len();
+    for reloc in relocs {
+        let extern_index = EXTERNS.iter().position(|e| *e == reloc.symbol).unwrap() as u64;
+        out.u64(reloc.offset as u64);
+        out.u64((FIRST_EXTERN as u64 + extern_index) << 32 | R_X86_64_PLT32);
+        out.u64((-4i64) as u64);
+    }
+
+    let symtab_offset = out.0.len();
+    for (name, info, shndx, value) in symbols {
+        out.u32(name);
+        out.u8(info);
+        out.u8(0);
+        out.u16(shndx);
+        out.u64(value as u64);
+        out.u64(0);
+    }
+
+    let strtab_offset = out.0.len();
+    out.0.extend_from_slice(&strtab);
+    let shstrtab_offset = out.0.len();
+    out.0.extend_from_slice(&shstrtab);
+
+    let sections = [
+        SectionHeader { name: 0, kind: 0, flags: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entsize: 0 },
+        SectionHeader {
+            name: section_names[0],
+            kind: 1, // PROGBITS
+            flags: 0x2 | 0x4, // ALLOC | EXECINSTR
+            offset: text_offset,
+            size: text.len(),
+            link: 0,
+            info: 0,
+            align: 16,
+            entsize: 0,
+        },
+        SectionHeader {
+            name: section_names[1],
+            kind: 4, // RELA
+            flags: 0x40, // INFO_LINK
+            offset: rela_offset,
+            size: relocs.len() * 24,
+            link: SYMTAB,
+            info: TEXT,
+            align: 8,
+            entsize: 24,
+        },
+        SectionHeader {
+            name: section_names[2],
+            kind: 2, // SYMTAB
+            flags: 0,
+            offset: symtab_offset,
+            size: symbols.len() * 24,
+            link: STRTAB,
+            info: FIRST_GLOBAL,
+            align: 8,
+            entsize: 24,
+        },
+        SectionHeader {
+            name: section_names[3],
+            kind: 3, // STRTAB
+            flags: 0,
+            offset: strtab_offset,
+            size: strtab.len(),
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        },
+        SectionHeader {
+            name: section_names[4],
+            kind: 3, // STRTAB
+            flags: 0,
+            offset: shstrtab_offset,
+            size: shstrtab.len(),
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        },
+        // Empty marker so the linker keeps the stack non-executable
+        SectionHeader {
+            name: section_names[5],
+            kind: 1, // PROGBITS
+            flags: 0,
+            offset: shstrtab_offset + shstrtab.len(),
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        },
+    ];
+    out.align(8);
+    let shoff = out.0.len() as u64;
+    out.0[shoff_at..shoff_at + 8].copy_from_slice(&shoff.to_le_bytes());
+    for section in &sections {
+        out.u32(section.name);
+        out.u32(section.kind);
+        out.u64(section.flags);
+        out.u64(0); // address
+        out.u64(section.offset as u64);
+        out.u64(section.size as u64);
+        out.u32(section.link);
+        out.u32(section.info);
+        out.u64(section.align);
+        out.u64(section.entsize);
+    }
+
+    out.0
+}
@@ -0,0 +1,388 @@
+// src/engine.rs
+// Embedding API: compile a snek program once, then run it in-process as
+// many times as needed.
+//
+//     let out = Engine::new().compile("(+ input 1)")?.run(41)?;
+//     assert_eq!(out.value, Value::Num(42));
+use std::collections::HashMap as StdHashMap;
+use std::fmt;
+use std::path::Path;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+
+use dynasmrt::{AssemblyOffset, ExecutableBuffer};
+
+use crate::ast::*;
+use crate::build::{build_executable, BuildError, Code};
+use crate::compiler::{compile, CompilerContext, FunContext, HEAP_WORDS};
+use crate::elf::compile_to_object;
+use crate::ir::{self, lower_program};
+use crate::optimize::{optimize_program, OptLevel};
+use crate::helpers::{begin_capture, end_capture, error_name, FALSE_VAL, TRUE_VAL, TUPLE_TAG};
+use crate::jit::{compile_to_jit, emit_error_site_stubs};
+use crate::parser::parse_program;
+use crate::reader::read_program;
+use crate::typechecker::{check_program_loops, check_program_names, typecheck_program};
+
+/// A snek value as seen from Rust
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Num(i64),
+    Bool(bool),
+    /// Copied out of the heap when a program returns one. Programs cannot
+    /// take one as input.
+    Tuple(Vec<Value>),
+    /// A tuple inside itself: the one `n` tuples out from the tuple holding
+    /// this value, which is 0. Prints as `...`, like the runtime does.
+    Cycle(usize),
+}
+
+impl Value {
+    fn encode(&self) -> Result<i64, Error> {
+        match self {
+            Value::Num(n) if !(MIN_NUM..=MAX_NUM).contains(n) => {
+                Err(Error::Input("Input number out of range".to_string()))
+            }
+            Value::Num(n) => Ok(n << 1),
+            Value::Bool(true) => Ok(TRUE_VAL),
+            Value::Bool(false) => Ok(FALSE_VAL),
+            Value::Tuple(_) | Value::Cycle(_) => Err(Error::Input("Input cannot be a tuple".to_string())),
+        }
+    }
+
+    /// `val` as returned by a run on `heap`. `enclosing` holds the tuples
+    /// being decoded around this one, innermost last, so a tuple inside
+    /// itself becomes a Cycle.
+    fn decode(val: i64, heap: &[i64], enclosing: &mut Vec<i64>) -> Result<Value, String> {
+        match val {
+            _ if val & 1 == 0 => Ok(Value::Num(val >> 1)),
+            TRUE_VAL => Ok(Value::Bool(true)),
+            FALSE_VAL => Ok(Value::Bool(false)),
+            _ if val & 7 == TUPLE_TAG => {
+                if let Some(k) = enclosing.iter().position(|&outer| outer == val) {
+                    return Ok(Value::Cycle(enclosing.len() - 1 - k));
+                }
+                // A length word, tagged like a number, then the elements
+                let start = (val - TUPLE_TAG - heap.as_ptr() as i64) / 8;
+                let words = usize::try_from(start)
+                    .ok()
+                    .and_then(|start| {
+                        let len = usize::try_from(*heap.get(start)? >> 1).ok()?;
+                        heap.get(start + 1..start + 1 + len)
+                    })
+                    .ok_or_else(|| format!("Invalid tuple pointer: {}", val))?;
+                enclosing.push(val);
+                let elements: Result<Vec<Value>, String> =
+                    words.iter().map(|&word| Value::decode(word, heap, enclosing)).collect();
+                enclosing.pop();
+                Ok(Value::Tuple(elements?))
+            }
+            _ => Err(format!("Invalid boolean value: {}", val)),
+        }
+    }
+
+    pub fn type_of(&self) -> Type {
+        match self {
+            Value::Num(_) => Type::Num,
+            Value::Bool(_) => Type::Bool,
+            Value::Tuple(_) | Value::Cycle(_) => Type::Tuple,
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Num(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+/// Parses an input the way the command line takes it: `true`, `false` or a
+/// number
+impl FromStr for Value {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => {
+                let n = s
+                    .parse::<i64>()
+                    .map_err(|_| Error::Input(format!("Invalid input: {}", s)))?;
+                let value = Value::Num(n);
+                value.encode()?;
+                Ok(value)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Tuple(elements) => {
+                write!(f, "(tuple")?;
+                for element in elements {
+                    write!(f, " {}", element)?;
+                }
+                write!(f, ")")
+            }
+            Value::Cycle(_) => write!(f, "..."),
+        }
+    }
+}
+
+/// A runtime error raised by a check in the compiled code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    /// e.g. `overflow in (add1 x) at prog.snek:3:5`
+    pub message: String,
+    /// Everything the program printed before failing
+    pub printed: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Rendered parse diagnostic, with file, line and column
+    Parse(String),
+    /// Rendered type error, pointing at the offending expression
+    Type(String),
+    /// Rendered static error the parser and typechecker don't catch, e.g.
+    /// an unbound identifier, a call to an undefined function or a `break`
+    /// outside of a loop
+    Compile(String),
+    Input(String),
+    Runtime(RuntimeError),
+    Build(BuildError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(msg) | Error::Type(msg) | Error::Compile(msg) | Error::Input(msg) => {
+                write!(f, "{}", msg)
+            }
+            Error::Runtime(e) => write!(f, "{}", e.message),
+            Error::Build(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Result of a successful run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub value: Value,
+    /// Everything the program printed, one value per line
+    pub printed: String,
+}
+
+pub struct Engine {
+    file_name: String,
+    input_type: Option<Type>,
+    opt_level: OptLevel,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine { file_name: "<input>".to_string(), input_type: None, opt_level: OptLevel::default() }
+    }
+
+    /// Name used for the source in error messages
+    pub fn file_name(mut self, name: &str) -> Self {
+        self.file_name = name.to_string();
+        self
+    }
+
+    /// Typecheck programs before compiling them, with `input` of the given
+    /// type. Runs then reject inputs that are not of that type.
+    pub fn typecheck(mut self, input_type: Type) -> Self {
+        self.input_type = Some(input_type);
+        self
+    }
+
+    /// How much to optimize; the default is `OptLevel::O2`, everything
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.opt_level = level;
+        self
+    }
+
+    pub fn parse(&self, source: &str) -> Result<Program, Error> {
+        read_program(source)
+            .and_then(|sexp| parse_program(&sexp))
+            .map_err(|e| Error::Parse(e.render(&self.file_name, source)))
+    }
+
+    /// Type of the program's main expression. `input` is `Any` unless
+    /// typechecking was enabled with a more specific type.
+    pub fn type_of(&self, source: &str) -> Result<Type, Error> {
+        let program = self.parse(source)?;
+        self.typecheck_parsed(&program, source)
+    }
+
+    fn typecheck_parsed(&self, program: &Program, source: &str) -> Result<Type, Error> {
+        typecheck_program(program, self.input_type.clone())
+            .map_err(|e| Error::Type(e.render(&self.file_name, source)))
+    }
+
+    /// Parse, optionally typecheck, and JIT-compile a program
+    pub fn compile(&self, source: &str) -> Result<CompiledProgram, Error> {
+        let program = self.checked(source)?;
+        catch_compile_errors(|| {
+            let mut ctx = CompilerContext::new();
+            ctx.opt_level = self.opt_level;
+            let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+            let mut label_map = StdHashMap::new();
+            let fun_ctx = FunContext::new(&program.defns);
+
+            let entry = compile_to_jit(&mut ctx, &program, &mut ops, &fun_ctx, &mut label_map);
+            let error_sites = emit_error_site_stubs(&mut ctx, &mut ops, &mut label_map, &self.file_name, source)
+                .into_iter()
+                .map(|(_, description)| description)
+                .collect();
+
+            CompiledProgram {
+                code: ops.finalize().unwrap(),
+                entry,
+                error_sites,
+                input_type: self.input_type.clone(),
+                peephole_saved: ctx.peephole_saved(),
+            }
+        })
+    }
+
+    /// Parse, optionally typecheck, lower and optimize a program to the
+    /// A-normal form IR that code generation works from. Displaying the result gives the
+    /// `--emit ir` listing.
+    pub fn compile_to_ir(&self, source: &str) -> Result<ir::Program, Error> {
+        let program = self.checked(source)?;
+        catch_compile_errors(|| {
+            let fun_ctx = FunContext::new(&program.defns);
+            optimize_program(lower_program(&program, &fun_ctx), &fun_ctx, self.opt_level)
+        })
+    }
+
+    /// Parse, optionally typecheck, and compile a program to assembly text
+    /// for nasm and the runtime in runtime/start.rs
+    pub fn compile_to_asm(&self, source: &str) -> Result<String, Error> {
+        let program = self.checked(source)?;
+        catch_compile_errors(|| compile(&program, &self.file_name, source, self.opt_level))
+    }
+
+    /// Parse, optionally typecheck, and compile a program to a relocatable
+    /// x86-64 ELF object for linking with runtime/start.rs
+    pub fn compile_to_object(&self, source: &str) -> Result<Vec<u8>, Error> {
+        let program = self.checked(source)?;
+        catch_compile_errors(|| compile_to_object(&program, &self.file_name, source, self.opt_level))
+    }
+
+    /// Compile a program ahead of time and link it with the runtime into an
+    /// executable at `output`. Needs ar and rustc on the PATH, and nasm on
+    /// macOS, where objects are Mach-O rather than ELF.
+    pub fn build(&self, source: &str, output: &Path) -> Result<(), Error> {
+        let code = if cfg!(target_os = "macos") {
+            Code::Asm(self.compile_to_asm(source)?)
+        } else {
+            Code::Object(self.compile_to_object(source)?)
+        };
+        build_executable(&code, output).map_err(Error::Build)
+    }
+
+    fn checked(&self, source: &str) -> Result<Program, Error> {
+        let program = self.parse(source)?;
+        check_program_loops(&program)
+            .and_then(|()| check_program_names(&program))
+            .map_err(|e| Error::Compile(e.render(&self.file_name, source)))?;
+        if self.input_type.is_some() {
+            self.typecheck_parsed(&program, source)?;
+        }
+        Ok(program)
+    }
+}
+
+/// `checked` reports static errors before the compiler sees a program. This
+/// is a backstop for any the compiler still finds itself, by panicking.
+fn catch_compile_errors<T>(f: impl FnOnce() -> T) -> Result<T, Error> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let msg = payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "Invalid".to_string());
+        Error::Compile(msg)
+    })
+}
+
+/// Machine code for one program. Each run gets a fresh heap, so a program
+/// can be run any number of times, from any number of threads.
+pub struct CompiledProgram {
+    code: ExecutableBuffer,
+    entry: AssemblyOffset,
+    // Indexed by error site id
+    error_sites: Vec<String>,
+    input_type: Option<Type>,
+    peephole_saved: usize,
+}
+
+impl CompiledProgram {
+    pub fn run(&self, input: impl Into<Value>) -> Result<Output, Error> {
+        let input = input.into();
+        if let Some(expected) = &self.input_type {
+            if !input.type_of().is_subtype(expected) {
+                return Err(Error::Input(format!(
+                    "input has type {:?}, expected {:?}",
+                    input.type_of(),
+                    expected
+                )));
+            }
+        }
+        let raw_input = input.encode()?;
+
+        let mut heap = vec![0i64; HEAP_WORDS];
+        let entry: extern "C" fn(i64, *mut i64) -> i64 =
+            unsafe { std::mem::transmute(self.code.ptr(self.entry)) };
+
+        begin_capture();
+        let result = entry(raw_input, heap.as_mut_ptr());
+        let capture = end_capture();
+
+        if let Some((errcode, site)) = capture.error {
+            let msg = error_name(errcode);
+            let message = match usize::try_from(site).ok().and_then(|site| self.error_sites.get(site)) {
+                Some(description) => format!("{} in {}", msg, description),
+                None => msg,
+            };
+            return Err(Error::Runtime(RuntimeError { message, printed: capture.printed }));
+        }
+
+        match Value::decode(result, &heap, &mut Vec::new()) {
+            Ok(value) => Ok(Output { value, printed: capture.printed }),
+            Err(message) => Err(Error::Runtime(RuntimeError { message, printed: capture.printed })),
+        }
+    }
+
+    /// The generated machine code, for disassembly
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Instructions the peephole optimizer removed from the code
+    pub fn peephole_saved(&self) -> usize {
+        self.peephole_saved
+    }
+}
@@ -2,88 +2,157 @@
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::atomic::AtomicI64;
-use std::panic;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Mutex;
+use std::collections::HashMap;
+use std::cell::RefCell;
 
 pub const TRUE_VAL: i64 = 1;
 pub const FALSE_VAL: i64 = 3;
+/// The low three bits of a pointer to a tuple
+pub const TUPLE_TAG: i64 = 5;
 
 pub static REPL: AtomicBool = AtomicBool::new(false);
 pub static HAS_ERROR: AtomicBool = AtomicBool::new(false);
 pub static ERROR_CODE: AtomicI64 = AtomicI64::new(0);
+pub static ERROR_SITE: AtomicI64 = AtomicI64::new(-1);
 
-pub fn parse_input(input: &str) -> i64 {
-    match input {
-        "true" => TRUE_VAL,
-        "false" => FALSE_VAL,
-        _ => {
-            let num = input.parse::<i64>().unwrap_or_else(|_| {
-                eprintln!("Invalid input: {}", input);
-                std::process::exit(1);
-            });
-            if num < -4611686018427387904 || num > 4611686018427387903 {
-                eprintln!("Input number out of range");
-                std::process::exit(1);
-            }
-            num << 1
-        }
+// Site id -> "(+ x y) at fac.snek:4:12", filled in as JIT code is compiled.
+// One table serves the whole process, so the ids come from one counter.
+static ERROR_SITES: Mutex<Option<HashMap<i64, String>>> = Mutex::new(None);
+static NEXT_ERROR_SITE: AtomicUsize = AtomicUsize::new(0);
+
+/// A site id no other code in this process has been given
+pub fn reserve_error_site() -> usize {
+    NEXT_ERROR_SITE.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn register_error_site(id: usize, description: String) {
+    ERROR_SITES.lock().unwrap().get_or_insert_with(HashMap::new).insert(id as i64, description);
+}
+
+/// What a run of embedded JIT code printed, and the (errcode, site) of the
+/// runtime error that stopped it, if any
+#[derive(Debug, Default)]
+pub struct Capture {
+    pub printed: String,
+    pub error: Option<(i64, i64)>,
+}
+
+// Set while an Engine run is executing on this thread. snek_print and
+// snek_error write here instead of stdout and the process-wide flags, so
+// separate threads can run programs at the same time.
+thread_local! {
+    static CAPTURE: RefCell<Option<Capture>> = const { RefCell::new(None) };
+}
+
+pub fn begin_capture() {
+    CAPTURE.with(|c| *c.borrow_mut() = Some(Capture::default()));
+}
+
+pub fn end_capture() -> Capture {
+    CAPTURE.with(|c| c.borrow_mut().take()).unwrap_or_default()
+}
+
+/// Appends to the active capture; returns false if there is none
+fn capture_with(f: impl FnOnce(&mut Capture)) -> bool {
+    CAPTURE.with(|c| c.borrow_mut().as_mut().map(f).is_some())
+}
+
+pub fn error_name(errcode: i64) -> String {
+    match errcode {
+        1 => "overflow".to_string(),
+        2 => "invalid argument".to_string(),
+        3 => "bad cast".to_string(),
+        4 => "division by zero".to_string(),
+        5 => "index out of bounds".to_string(),
+        6 => "out of memory".to_string(),
+        _ => format!("unknown error code: {}", errcode),
     }
 }
 
-pub fn print_result(val: i64) {
-    if val & 1 == 1 {
-        if val == 1 {
-            println!("true");
-        } else if val == 3 {
-            println!("false");
-        } else {
-            eprintln!("Invalid boolean value: {}", val);
-            std::process::exit(1);
-        }
-    } else {
-        println!("{}", val >> 1);
+fn error_message(errcode: i64, site: i64) -> String {
+    let msg = error_name(errcode);
+    let sites = ERROR_SITES.lock().unwrap();
+    match sites.as_ref().and_then(|sites| sites.get(&site)) {
+        Some(description) => format!("{} in {}", msg, description),
+        None => msg,
     }
 }
 
-#[export_name = "\x01snek_error"]
-pub extern "C" fn snek_error(errcode: i64) {
+#[cfg_attr(target_os = "macos", export_name = "\x01snek_error")]
+#[cfg_attr(not(target_os = "macos"), no_mangle)]
+pub extern "C" fn snek_error(errcode: i64, site: i64) {
+    if capture_with(|c| c.error = Some((errcode, site))) {
+        return;
+    }
     ERROR_CODE.store(errcode, Ordering::SeqCst);
+    ERROR_SITE.store(site, Ordering::SeqCst);
     HAS_ERROR.store(true, Ordering::SeqCst);
     if !REPL.load(Ordering::SeqCst) {
-        match errcode {
-            1 => eprintln!("overflow"),
-            2 => eprintln!("invalid argument"),
-            3 => eprintln!("bad cast"),
-            _ => eprintln!("unknown error code: {}", errcode),
-        }
+        eprintln!("{}", error_message(errcode, site));
         std::process::exit(1);
     }
     println!("Runtime error in REPL check for code review here in helpers.rs:snek_error");
 }
 
-#[export_name = "\x01snek_print"]
+#[cfg_attr(target_os = "macos", export_name = "\x01snek_print")]
+#[cfg_attr(not(target_os = "macos"), no_mangle)]
 pub extern "C" fn snek_print(val: i64) -> i64 {
-    println!("{}", if val & 1 == 0 {
-        format!("{}", val >> 1)
-    } else if val == 1 {
-        "true".to_string()
-    } else if val == 3 {
-        "false".to_string()
-    } else {
-        format!("Unknown value: {}", val)
-    });
+    let text = format_value(val);
+    if !capture_with(|c| {
+        c.printed.push_str(&text);
+        c.printed.push('\n');
+    }) {
+        println!("{}", text);
+    }
     val
 }
 
+/// A value as snek prints it, with tuples as `(tuple 1 (tuple true 2))`.
+/// Tuples are read through their pointers, so `val` must come from code
+/// whose heap is still alive. A tuple inside itself prints as `...`.
+pub fn format_value(val: i64) -> String {
+    let mut text = String::new();
+    write_value(&mut text, val, &mut Vec::new());
+    text
+}
+
+// `enclosing` holds the tuples being printed around this one
+fn write_value(text: &mut String, val: i64, enclosing: &mut Vec<i64>) {
+    if val & 1 == 0 {
+        text.push_str(&(val >> 1).to_string());
+    } else if val == TRUE_VAL {
+        text.push_str("true");
+    } else if val == FALSE_VAL {
+        text.push_str("false");
+    } else if val & 7 == TUPLE_TAG {
+        if enclosing.contains(&val) {
+            text.push_str("...");
+            return;
+        }
+        enclosing.push(val);
+        // A length word, tagged like a number, then the elements
+        let words = (val - TUPLE_TAG) as *const i64;
+        let len = unsafe { *words } >> 1;
+        text.push_str("(tuple");
+        for k in 1..=len {
+            text.push(' ');
+            write_value(text, unsafe { *words.offset(k as isize) }, enclosing);
+        }
+        text.push(')');
+        enclosing.pop();
+    } else {
+        text.push_str(&format!("Unknown value: {}", val));
+    }
+}
+
 pub fn check_error() -> Option<String> {
     if HAS_ERROR.load(Ordering::SeqCst) {
         HAS_ERROR.store(false, Ordering::SeqCst);
         let code = ERROR_CODE.load(Ordering::SeqCst);
-        Some(match code {
-            1 => "overflow".to_string(),
-            2 => "invalid argument".to_string(),
-            3 => "bad cast".to_string(),
-            _ => format!("unknown error code: {}", code),
-        })
+        let site = ERROR_SITE.load(Ordering::SeqCst);
+        Some(error_message(code, site))
     } 
     else {
         None
@@ -0,0 +1,237 @@
+// src/inline.rs
+// Inlining of small, non-recursive functions into their callers' IR.
+//
+// A call's arguments are already immediates, evaluated in order, so the
+// callee's body goes in place of the call behind a `let` per parameter.
+// Parameters are fresh locals of the caller: a `set!` on one changes only
+// the inlined copy, just as it would have changed only the callee's frame.
+// Every other variable of the callee is renamed apart from the caller's too.
+use std::collections::HashMap;
+
+use crate::compiler::FunContext;
+use crate::ir::{flatten, lower_function, visit_vars, wrap, AExpr, CExpr, Function, Imm, Stmt, Var};
+use crate::optimize::fold_constants;
+
+/// Most operations a callee body may have and still be inlined
+pub const INLINE_LIMIT: usize = 8;
+
+fn size(a: &AExpr) -> usize {
+    let (c, rest) = match a {
+        AExpr::Let(_, c, body) | AExpr::Seq(c, body) => (c, size(body)),
+        AExpr::Value(c) => (c, 0),
+    };
+    let nested = match c {
+        CExpr::If(_, then_branch, else_branch) => size(then_branch) + size(else_branch),
+        CExpr::Loop(_, body) => size(body),
+        _ => 0,
+    };
+    1 + nested + rest
+}
+
+fn callees(a: &AExpr, out: &mut Vec<String>) {
+    let c = match a {
+        AExpr::Let(_, c, body) | AExpr::Seq(c, body) => {
+            callees(body, out);
+            c
+        }
+        AExpr::Value(c) => c,
+    };
+    match c {
+        CExpr::Call(name, _) => out.push(name.clone()),
+        CExpr::If(_, then_branch, else_branch) => {
+            callees(then_branch, out);
+            callees(else_branch, out);
+        }
+        CExpr::Loop(_, body) => callees(body, out),
+        _ => {}
+    }
+}
+
+/// `a` with every variable passed through `f`
+fn map_vars(a: AExpr, f: &mut impl FnMut(Var) -> Var) -> AExpr {
+    let (stmts, c) = flatten(a);
+    let stmts = stmts
+        .into_iter()
+        .map(|stmt| match stmt {
+            Stmt::Let(var, c) => {
+                let c = map_cexpr_vars(c, f);
+                Stmt::Let(f(var), c)
+            }
+            Stmt::Do(c) => Stmt::Do(map_cexpr_vars(c, f)),
+        })
+        .collect();
+    wrap(stmts, AExpr::Value(map_cexpr_vars(c, f)))
+}
+
+fn map_imm_vars(imm: Imm, f: &mut impl FnMut(Var) -> Var) -> Imm {
+    match imm {
+        Imm::Var(var) => Imm::Var(f(var)),
+        _ => imm,
+    }
+}
+
+fn map_cexpr_vars(c: CExpr, f: &mut impl FnMut(Var) -> Var) -> CExpr {
+    match c {
+        CExpr::Imm(a) => CExpr::Imm(map_imm_vars(a, f)),
+        CExpr::Prim1(op, a, span) => CExpr::Prim1(op, map_imm_vars(a, f), span),
+        CExpr::Prim2(op, a, b, span) => {
+            let a = map_imm_vars(a, f);
+            CExpr::Prim2(op, a, map_imm_vars(b, f), span)
+        }
+        CExpr::Cast(a, t, span) => CExpr::Cast(map_imm_vars(a, f), t, span),
+        CExpr::Call(name, args) => CExpr::Call(name, args.into_iter().map(|a| map_imm_vars(a, f)).collect()),
+        CExpr::Tuple(elements, span) => CExpr::Tuple(elements.into_iter().map(|a| map_imm_vars(a, f)).collect(), span),
+        CExpr::TupleSet(tuple, index, value, span) => {
+            let tuple = map_imm_vars(tuple, f);
+            let index = map_imm_vars(index, f);
+            CExpr::TupleSet(tuple, index, map_imm_vars(value, f), span)
+        }
+        CExpr::Set(var, a) => {
+            let var = f(var);
+            CExpr::Set(var, map_imm_vars(a, f))
+        }
+        CExpr::If(cond, then_branch, else_branch) => {
+            let cond = map_imm_vars(cond, f);
+            let then_branch = map_vars(*then_branch, f);
+            CExpr::If(cond, Box::new(then_branch), Box::new(map_vars(*else_branch, f)))
+        }
+        CExpr::Loop(label, body) => CExpr::Loop(label, Box::new(map_vars(*body, f))),
+        CExpr::Break(label, a) => CExpr::Break(label, map_imm_vars(a, f)),
+        CExpr::Continue | CExpr::Fail(..) => c,
+    }
+}
+
+/// Names not yet used in the routine being inlined into
+#[derive(Default)]
+struct Fresh {
+    next_local: HashMap<String, usize>,
+    next_temp: usize,
+}
+
+impl Fresh {
+    fn reserve(&mut self, var: &Var) {
+        match var {
+            Var::Local(name, n) => {
+                let next = self.next_local.entry(name.clone()).or_insert(0);
+                *next = (*next).max(n + 1);
+            }
+            Var::Temp(n) => self.next_temp = self.next_temp.max(n + 1),
+            Var::Global(_) => {}
+        }
+    }
+
+    fn rename(&mut self, var: &Var) -> Var {
+        match var {
+            Var::Local(name, _) => {
+                let next = self.next_local.entry(name.clone()).or_insert(0);
+                *next += 1;
+                Var::Local(name.clone(), *next - 1)
+            }
+            Var::Temp(_) => {
+                self.next_temp += 1;
+                Var::Temp(self.next_temp - 1)
+            }
+            Var::Global(_) => var.clone(),
+        }
+    }
+}
+
+struct Inliner<'a> {
+    fun_ctx: &'a FunContext,
+    // Lowered and folded callees, and whether each may be inlined
+    callees: HashMap<String, Function>,
+    inlinable: HashMap<String, bool>,
+    fresh: Fresh,
+}
+
+impl<'a> Inliner<'a> {
+    fn lowered(&mut self, name: &str) -> &Function {
+        if !self.callees.contains_key(name) {
+            let defn = self.fun_ctx.functions.get(name).expect("calls are checked when lowering");
+            let mut function = lower_function(defn, self.fun_ctx);
+            function.body = fold_constants(function.body);
+            self.callees.insert(name.to_string(), function);
+        }
+        &self.callees[name]
+    }
+
+    fn reaches(&mut self, from: &str, target: &str, seen: &mut Vec<String>) -> bool {
+        let mut next = Vec::new();
+        callees(&self.lowered(from).body, &mut next);
+        next.into_iter().any(|callee| {
+            if callee == target {
+                return true;
+            }
+            if seen.contains(&callee) {
+                return false;
+            }
+            seen.push(callee.clone());
+            self.reaches(&callee, target, seen)
+        })
+    }
+
+    fn inlinable(&mut self, name: &str) -> bool {
+        if !self.inlinable.contains_key(name) {
+            let small = size(&self.lowered(name).body) <= INLINE_LIMIT;
+            let inlinable = small && !self.reaches(name, name, &mut Vec::new());
+            self.inlinable.insert(name.to_string(), inlinable);
+        }
+        self.inlinable[name]
+    }
+
+    fn aexpr(&mut self, a: AExpr) -> AExpr {
+        let (stmts, c) = flatten(a);
+        let mut out = Vec::new();
+        for stmt in stmts {
+            match stmt {
+                Stmt::Let(var, c) => {
+                    let c = self.cexpr(c, &mut out);
+                    out.push(Stmt::Let(var, c));
+                }
+                Stmt::Do(c) => {
+                    let c = self.cexpr(c, &mut out);
+                    out.push(Stmt::Do(c));
+                }
+            }
+        }
+        let c = self.cexpr(c, &mut out);
+        wrap(out, AExpr::Value(c))
+    }
+
+    /// `c` with calls inlined; an inlined body's steps go on the end of `out`
+    fn cexpr(&mut self, c: CExpr, out: &mut Vec<Stmt>) -> CExpr {
+        match c {
+            CExpr::Call(name, args) if self.inlinable(&name) => {
+                let callee = self.lowered(&name).clone();
+                let mut renamed = HashMap::new();
+                for (param, arg) in callee.params.iter().zip(args) {
+                    let local = self.fresh.rename(param);
+                    renamed.insert(param.clone(), local.clone());
+                    out.push(Stmt::Let(local, CExpr::Imm(arg)));
+                }
+                let fresh = &mut self.fresh;
+                let body = map_vars(callee.body, &mut |var| {
+                    renamed.entry(var.clone()).or_insert_with(|| fresh.rename(&var)).clone()
+                });
+                // Callees are never recursive, so this terminates
+                let (stmts, c) = flatten(self.aexpr(body));
+                out.extend(stmts);
+                c
+            }
+            CExpr::If(cond, then_branch, else_branch) => {
+                CExpr::If(cond, Box::new(self.aexpr(*then_branch)), Box::new(self.aexpr(*else_branch)))
+            }
+            CExpr::Loop(label, body) => CExpr::Loop(label, Box::new(self.aexpr(*body))),
+            _ => c,
+        }
+    }
+}
+
+/// Inline calls to small, non-recursive functions in one routine's body.
+/// `params` are the routine's own parameters, if it has any.
+pub fn inline_calls(body: AExpr, params: &[Var], fun_ctx: &FunContext) -> AExpr {
+    let mut fresh = Fresh::default();
+    params.iter().for_each(|param| fresh.reserve(param));
+    visit_vars(&body, &mut |var| fresh.reserve(var));
+    Inliner { fun_ctx, callees: HashMap::new(), inlinable: HashMap::new(), fresh }.aexpr(body)
+}
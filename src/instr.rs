@@ -1,5 +1,5 @@
 // src/instr.rs
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Reg {
     RAX,
     RSP,
@@ -11,16 +11,23 @@ pub enum Reg {
     R8,
     R9,
     RBP,
+    // Callee-saved, handed out by the register allocator
+    RBX,
+    R12,
+    R13,
+    R14,
 }
 
 #[derive(Debug, Clone)]
 pub enum Val {
     Reg(Reg),
     Imm(i32),
+    // Only `mov` into a register takes a full 64-bit immediate
+    Imm64(i64),
     RegOffset(Reg, i32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Instr {
     IMov(Val, Val),
     IAdd(Val, Val),
@@ -41,14 +48,25 @@ pub enum Instr {
     IJmp(String),
     IJe(String),   // Jump if equal
     IJne(String),  // Jump if not equal
+    IJl(String),   // Jump if less
+    IJle(String),  // Jump if less or equal
+    IJg(String),   // Jump if greater
+    IJge(String),  // Jump if greater or equal
+    IJz(String),   // Jump if zero
     // NEW - Overflow checking
     IJo(String),   // Jump if overflow
     // NEW - Comments for debugging
     IComment(String),
+    // Sign-extend RAX into RDX:RAX, then divide it, leaving the quotient in
+    // RAX and the remainder in RDX
+    ICqo,
+    IIdiv(Val),
 
     IOr(Val, Val),
+    IAnd(Val, Val),
     IXor(Val, Val),
     ISar(Val, Val),  // Shift arithmetic right
+    IShl(Val, Val),  // Shift left
 
     ICall(String),
     IRet,
@@ -71,8 +89,13 @@ pub fn val_to_str(v: &Val) -> String {
             Reg::R8 => "r8".to_string(),    // ADD THIS
             Reg::R9 => "r9".to_string(),    // ADD THIS
             Reg::R15 => "r15".to_string(),
+            Reg::RBX => "rbx".to_string(),
+            Reg::R12 => "r12".to_string(),
+            Reg::R13 => "r13".to_string(),
+            Reg::R14 => "r14".to_string(),
         },
         Val::Imm(n) => format!("{}", n),
+        Val::Imm64(n) => format!("{}", n),
         Val::RegOffset(reg, offset) => {
             let reg_str = match reg {
                 Reg::RAX => "rax",
@@ -85,6 +108,10 @@ pub fn val_to_str(v: &Val) -> String {
                 Reg::R8 => "r8",    // ADD THIS
                 Reg::R9 => "r9",    // ADD THIS
                 Reg::R15 => "r15",
+                Reg::RBX => "rbx",
+                Reg::R12 => "r12",
+                Reg::R13 => "r13",
+                Reg::R14 => "r14",
             };
             // Use + for positive offsets (heap/parameters), - for negative (locals)
             if *offset >= 0 {
@@ -108,8 +135,36 @@ pub fn reg_to_str(reg: &Reg) -> &str {
         Reg::R8 => "r8",
         Reg::R9 => "r9",
         Reg::R15 => "r15",
+        Reg::RBX => "rbx",
+        Reg::R12 => "r12",
+        Reg::R13 => "r13",
+        Reg::R14 => "r14",
+    }
+}
+/// A variable shift count is always in CL
+fn shift_count_to_str(v: &Val) -> String {
+    match v {
+        Val::Reg(Reg::RCX) => "cl".to_string(),
+        _ => val_to_str(v),
     }
 }
+
+/// The label a jump may transfer control to
+pub fn jump_target(instr: &Instr) -> Option<&str> {
+    match instr {
+        Instr::IJmp(label)
+        | Instr::IJe(label)
+        | Instr::IJne(label)
+        | Instr::IJl(label)
+        | Instr::IJle(label)
+        | Instr::IJg(label)
+        | Instr::IJge(label)
+        | Instr::IJz(label)
+        | Instr::IJo(label) => Some(label),
+        _ => None,
+    }
+}
+
 pub fn instr_to_str(i: &Instr) -> String {
     match i {
         Instr::IMov(dest, src) => format!("  mov {}, {}", val_to_str(dest), val_to_str(src)),
@@ -128,14 +183,23 @@ pub fn instr_to_str(i: &Instr) -> String {
         Instr::IJmp(label) => format!("  jmp {}", label),
         Instr::IJe(label) => format!("  je {}", label),
         Instr::IJne(label) => format!("  jne {}", label),
+        Instr::IJl(label) => format!("  jl {}", label),
+        Instr::IJle(label) => format!("  jle {}", label),
+        Instr::IJg(label) => format!("  jg {}", label),
+        Instr::IJge(label) => format!("  jge {}", label),
+        Instr::IJz(label) => format!("  jz {}", label),
         Instr::IJo(label) => format!("  jo {}", label),
         Instr::IComment(comment) => format!("  ; {}", comment),
         Instr::IOr(dest, src) => format!("  or {}, {}", val_to_str(dest), val_to_str(src)),
+        Instr::IAnd(dest, src) => format!("  and {}, {}", val_to_str(dest), val_to_str(src)),
         Instr::IXor(dest, src) => format!("  xor {}, {}", val_to_str(dest), val_to_str(src)),
-        Instr::ISar(dest, src) => format!("  sar {}, {}", val_to_str(dest), val_to_str(src)),
+        Instr::ISar(dest, src) => format!("  sar {}, {}", val_to_str(dest), shift_count_to_str(src)),
+        Instr::IShl(dest, src) => format!("  shl {}, {}", val_to_str(dest), shift_count_to_str(src)),
         Instr::ICall(label) => format!("  call {}", label),
         Instr::IRet => "  ret".to_string(),
         Instr::IPush(val) => format!("  push {}", val_to_str(val)),
         Instr::IPop(val) => format!("  pop {}", val_to_str(val)),
+        Instr::ICqo => "  cqo".to_string(),
+        Instr::IIdiv(val) => format!("  idiv {}", val_to_str(val)),
     }
 }
\ No newline at end of file
@@ -0,0 +1,602 @@
+// src/ir.rs
+// A-normal form: the flat intermediate representation between Expr and
+// Instr. Every operation takes immediates (constants and variables), and
+// every intermediate result is bound to a variable of its own, so evaluation
+// order is explicit and code generation never has to juggle nested
+// subexpressions. Control flow (if, loop) stays structured.
+//
+//     (+ (add1 x) (* y 2))   ==>   let %1 = add1 x;
+//                                  let %2 = y * 2;
+//                                  %1 + %2
+use std::fmt;
+
+use im::HashMap;
+
+use crate::ast::*;
+use crate::compiler::FunContext;
+use crate::span::Span;
+
+/// A variable. Lowering numbers every binding in a routine apart, so a name
+/// means the same slot wherever it appears and blocks can be flattened
+/// without one `let` capturing another's uses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Var {
+    /// A parameter or `let` binding; the number tells apart bindings that
+    /// share a name
+    Local(String, usize),
+    /// An intermediate result introduced by lowering
+    Temp(usize),
+    /// Not bound in the routine: a REPL define, or an unbound identifier
+    /// reported when code is generated
+    Global(String),
+}
+
+/// An operand that needs no computation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Imm {
+    Num(i64),
+    Bool(bool),
+    Input,
+    Var(Var),
+}
+
+/// A single operation on immediates. Operations that can fail at runtime
+/// keep the span of the expression they came from for the error message.
+#[derive(Debug, Clone)]
+pub enum CExpr {
+    Imm(Imm),
+    Prim1(Op1, Imm, Span),
+    Prim2(Op2, Imm, Imm, Span),
+    Cast(Imm, Type, Span),
+    Call(String, Vec<Imm>),
+    Set(Var, Imm),
+    /// A new tuple on the heap, which can run out of memory
+    Tuple(Vec<Imm>, Span),
+    /// `tuple[index] = value`, giving the value
+    TupleSet(Imm, Imm, Imm, Span),
+    If(Imm, Box<AExpr>, Box<AExpr>),
+    /// Value is whatever a `break` inside the body passes out. A `break`
+    /// with a label leaves the enclosing loop of that name.
+    Loop(Option<String>, Box<AExpr>),
+    Break(Option<String>, Imm),
+    /// Back to the top of the innermost loop
+    Continue,
+    /// A check known to fail: jumps straight to the error
+    Fail(Failure, Span),
+}
+
+/// Runtime errors an operation can be proven to raise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Overflow,
+    InvalidArgument,
+    BadCast,
+    DivideByZero,
+}
+
+/// A sequence of operations ending in the one that gives its value
+#[derive(Debug, Clone)]
+pub enum AExpr {
+    Let(Var, CExpr, Box<AExpr>),
+    /// Evaluate for effect only, as in all but the last expression of a block
+    Seq(CExpr, Box<AExpr>),
+    Value(CExpr),
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Var>,
+    pub body: AExpr,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub main: AExpr,
+}
+
+/// One step of a flattened sequence, before it is folded into an AExpr
+pub(crate) enum Stmt {
+    Let(Var, CExpr),
+    Do(CExpr),
+}
+
+/// Fold `stmts` in front of `last`
+pub(crate) fn wrap(stmts: Vec<Stmt>, last: AExpr) -> AExpr {
+    stmts.into_iter().rev().fold(last, |body, stmt| match stmt {
+        Stmt::Let(var, c) => AExpr::Let(var, c, Box::new(body)),
+        Stmt::Do(c) => AExpr::Seq(c, Box::new(body)),
+    })
+}
+
+/// Undo `wrap`: the steps of `a` and the operation giving its value
+pub(crate) fn flatten(a: AExpr) -> (Vec<Stmt>, CExpr) {
+    let mut stmts = Vec::new();
+    let mut a = a;
+    loop {
+        match a {
+            AExpr::Let(var, c, body) => {
+                stmts.push(Stmt::Let(var, c));
+                a = *body;
+            }
+            AExpr::Seq(c, body) => {
+                stmts.push(Stmt::Do(c));
+                a = *body;
+            }
+            AExpr::Value(c) => return (stmts, c),
+        }
+    }
+}
+
+/// Calls `f` on every variable in `a`, bound or used
+pub(crate) fn visit_vars(a: &AExpr, f: &mut impl FnMut(&Var)) {
+    let c = match a {
+        AExpr::Let(var, c, body) => {
+            f(var);
+            visit_vars(body, f);
+            c
+        }
+        AExpr::Seq(c, body) => {
+            visit_vars(body, f);
+            c
+        }
+        AExpr::Value(c) => c,
+    };
+    let imm = |imm: &Imm, f: &mut dyn FnMut(&Var)| {
+        if let Imm::Var(var) = imm {
+            f(var);
+        }
+    };
+    match c {
+        CExpr::Imm(a) | CExpr::Prim1(_, a, _) | CExpr::Cast(a, _, _) | CExpr::Break(_, a) => imm(a, f),
+        CExpr::Prim2(_, a, b, _) => {
+            imm(a, f);
+            imm(b, f);
+        }
+        CExpr::Call(_, args) | CExpr::Tuple(args, _) => args.iter().for_each(|a| imm(a, f)),
+        CExpr::TupleSet(tuple, index, value, _) => {
+            imm(tuple, f);
+            imm(index, f);
+            imm(value, f);
+        }
+        CExpr::Set(var, a) => {
+            f(var);
+            imm(a, f);
+        }
+        CExpr::If(cond, then_branch, else_branch) => {
+            imm(cond, f);
+            visit_vars(then_branch, f);
+            visit_vars(else_branch, f);
+        }
+        CExpr::Loop(_, body) => visit_vars(body, f),
+        CExpr::Continue | CExpr::Fail(..) => {}
+    }
+}
+
+/// Whether `e` can only evaluate to a boolean, if it evaluates at all
+fn is_boolean(e: &Expr) -> bool {
+    matches!(
+        e.kind,
+        ExprKind::Boolean(_)
+            | ExprKind::UnOp(Op1::IsNum | Op1::IsBool | Op1::Not, _)
+            | ExprKind::BinOp(Op2::Less | Op2::Greater | Op2::LessEqual | Op2::GreaterEqual | Op2::Equal, _, _)
+            | ExprKind::And(_)
+            | ExprKind::Or(_)
+    )
+}
+
+/// Lowering state for one routine. Also reports the static errors that only
+/// need the program's shape: duplicate bindings, stray breaks and bad calls.
+struct Lowering<'a> {
+    fun_ctx: &'a FunContext,
+    next_temp: usize,
+    // Bindings made so far for each name
+    bound: HashMap<String, usize>,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(fun_ctx: &'a FunContext) -> Self {
+        Lowering { fun_ctx, next_temp: 0, bound: HashMap::new() }
+    }
+
+    fn temp(&mut self) -> Var {
+        self.next_temp += 1;
+        Var::Temp(self.next_temp)
+    }
+
+    fn local(&mut self, name: &str) -> Var {
+        let n = self.bound.get(name).copied().unwrap_or(0);
+        self.bound.insert(name.to_string(), n + 1);
+        Var::Local(name.to_string(), n)
+    }
+
+    // `loops` are the labels of the loops around `e`, innermost last
+    fn expr(&mut self, e: &Expr, scope: &HashMap<String, Var>, loops: &[Option<String>]) -> AExpr {
+        let (stmts, c) = self.cexpr(e, scope, loops);
+        wrap(stmts, AExpr::Value(c))
+    }
+
+    fn imm(&mut self, e: &Expr, scope: &HashMap<String, Var>, loops: &[Option<String>]) -> (Vec<Stmt>, Imm) {
+        match &e.kind {
+            ExprKind::Number(n) => (vec![], Imm::Num(*n)),
+            ExprKind::Boolean(b) => (vec![], Imm::Bool(*b)),
+            ExprKind::Input => (vec![], Imm::Input),
+            ExprKind::Id(name) => (vec![], Imm::Var(lookup(scope, name))),
+            _ => {
+                let (mut stmts, c) = self.cexpr(e, scope, loops);
+                let var = self.temp();
+                stmts.push(Stmt::Let(var.clone(), c));
+                (stmts, Imm::Var(var))
+            }
+        }
+    }
+
+    /// Immediates for operands evaluated left to right. A variable read
+    /// before a later operand runs code is copied first, in case that code
+    /// assigns to it.
+    fn operands(&mut self, es: &[&Expr], scope: &HashMap<String, Var>, loops: &[Option<String>]) -> (Vec<Stmt>, Vec<Imm>) {
+        let lowered: Vec<(Vec<Stmt>, Imm)> = es.iter().map(|e| self.imm(e, scope, loops)).collect();
+        let runs_code: Vec<bool> = lowered.iter().map(|(stmts, _)| !stmts.is_empty()).collect();
+        let mut stmts = Vec::new();
+        let mut imms = Vec::new();
+        for (i, (arg_stmts, imm)) in lowered.into_iter().enumerate() {
+            stmts.extend(arg_stmts);
+            match imm {
+                Imm::Var(_) if runs_code[i + 1..].contains(&true) => {
+                    let copy = self.temp();
+                    stmts.push(Stmt::Let(copy.clone(), CExpr::Imm(imm)));
+                    imms.push(Imm::Var(copy));
+                }
+                _ => imms.push(imm),
+            }
+        }
+        (stmts, imms)
+    }
+
+    /// `and` (or `or`) of `es` as an `if` on the first around the rest, so
+    /// only operands that are reached run. An operand that might not be a
+    /// boolean goes through `not`, which checks it, and the branches swap.
+    fn connective(
+        &mut self,
+        es: &[Expr],
+        and: bool,
+        span: Span,
+        scope: &HashMap<String, Var>,
+        loops: &[Option<String>],
+    ) -> (Vec<Stmt>, CExpr) {
+        let Some((first, rest)) = es.split_first() else {
+            return (vec![], CExpr::Imm(Imm::Bool(and)));
+        };
+        let (mut stmts, arg) = self.imm(first, scope, loops);
+        let (rest_stmts, rest_c) = self.connective(rest, and, span, scope, loops);
+        let rest = wrap(rest_stmts, AExpr::Value(rest_c));
+        let decided = AExpr::Value(CExpr::Imm(Imm::Bool(!and)));
+        let (if_true, if_false) = if and { (rest, decided) } else { (decided, rest) };
+        if is_boolean(first) {
+            return (stmts, CExpr::If(arg, Box::new(if_true), Box::new(if_false)));
+        }
+        let negated = self.temp();
+        stmts.push(Stmt::Let(negated.clone(), CExpr::Prim1(Op1::Not, arg, span)));
+        (stmts, CExpr::If(Imm::Var(negated), Box::new(if_false), Box::new(if_true)))
+    }
+
+    fn cexpr(&mut self, e: &Expr, scope: &HashMap<String, Var>, loops: &[Option<String>]) -> (Vec<Stmt>, CExpr) {
+        match &e.kind {
+            ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Input | ExprKind::Id(_) => {
+                let (stmts, imm) = self.imm(e, scope, loops);
+                (stmts, CExpr::Imm(imm))
+            }
+            ExprKind::UnOp(op, expr) => {
+                let (stmts, arg) = self.imm(expr, scope, loops);
+                (stmts, CExpr::Prim1(op.clone(), arg, e.span))
+            }
+            ExprKind::BinOp(op, left, right) => {
+                let (stmts, mut args) = self.operands(&[&**left, &**right], scope, loops);
+                let right = args.pop().unwrap();
+                let left = args.pop().unwrap();
+                (stmts, CExpr::Prim2(op.clone(), left, right, e.span))
+            }
+            ExprKind::Cast(expr, t) => {
+                let (stmts, arg) = self.imm(expr, scope, loops);
+                (stmts, CExpr::Cast(arg, t.clone(), e.span))
+            }
+            ExprKind::Set(name, expr) => {
+                let (stmts, value) = self.imm(expr, scope, loops);
+                (stmts, CExpr::Set(lookup(scope, name), value))
+            }
+            ExprKind::Tuple(exprs) => {
+                let exprs: Vec<&Expr> = exprs.iter().collect();
+                let (stmts, elements) = self.operands(&exprs, scope, loops);
+                (stmts, CExpr::Tuple(elements, e.span))
+            }
+            ExprKind::TupleSet(tuple, index, value) => {
+                let (stmts, mut args) = self.operands(&[&**tuple, &**index, &**value], scope, loops);
+                let value = args.pop().unwrap();
+                let index = args.pop().unwrap();
+                let tuple = args.pop().unwrap();
+                (stmts, CExpr::TupleSet(tuple, index, value, e.span))
+            }
+            ExprKind::Let(bindings, body) => {
+                let mut seen = std::collections::HashSet::new();
+                for binding in bindings {
+                    if !seen.insert(&binding.name) {
+                        panic!("Duplicate binding");
+                    }
+                }
+
+                let mut stmts = Vec::new();
+                let mut scope = scope.clone();
+                for binding in bindings {
+                    let (binding_stmts, c) = self.cexpr(&binding.expr, &scope, loops);
+                    stmts.extend(binding_stmts);
+                    let var = self.local(&binding.name);
+                    stmts.push(Stmt::Let(var.clone(), c));
+                    scope.insert(binding.name.clone(), var);
+                }
+                let (body_stmts, c) = self.cexpr(body, &scope, loops);
+                stmts.extend(body_stmts);
+                (stmts, c)
+            }
+            ExprKind::Block(exprs) => {
+                let mut stmts = Vec::new();
+                let (last, init) = exprs.split_last().expect("empty block");
+                for expr in init {
+                    let (expr_stmts, c) = self.cexpr(expr, scope, loops);
+                    stmts.extend(expr_stmts);
+                    stmts.push(Stmt::Do(c));
+                }
+                let (last_stmts, c) = self.cexpr(last, scope, loops);
+                stmts.extend(last_stmts);
+                (stmts, c)
+            }
+            ExprKind::If(cond, then_expr, else_expr) => {
+                let (stmts, cond) = self.imm(cond, scope, loops);
+                let then_branch = self.expr(then_expr, scope, loops);
+                let else_branch = self.expr(else_expr, scope, loops);
+                (stmts, CExpr::If(cond, Box::new(then_branch), Box::new(else_branch)))
+            }
+            ExprKind::And(exprs) => self.connective(exprs, true, e.span, scope, loops),
+            ExprKind::Or(exprs) => self.connective(exprs, false, e.span, scope, loops),
+            ExprKind::Loop(label, body) => {
+                let body = self.expr(body, scope, &enter(loops, label.clone()));
+                (vec![], CExpr::Loop(label.clone(), Box::new(body)))
+            }
+            ExprKind::While(cond, body) => {
+                let inner = enter(loops, None);
+                let (stmts, cond) = self.imm(cond, scope, &inner);
+                let body = self.expr(body, scope, &inner);
+                let done = AExpr::Value(CExpr::Break(None, Imm::Bool(false)));
+                let test = wrap(stmts, AExpr::Value(CExpr::If(cond, Box::new(body), Box::new(done))));
+                (vec![], CExpr::Loop(None, Box::new(test)))
+            }
+            ExprKind::For(name, lo, hi, body) => {
+                // Count in a hidden variable stepped before the body runs,
+                // so `continue` can jump straight back to the test
+                let (mut stmts, bounds) = self.operands(&[&**lo, &**hi], scope, loops);
+                let (next, end, more, stepped) = (self.temp(), self.temp(), self.temp(), self.temp());
+                for (var, bound) in [next.clone(), end.clone()].into_iter().zip(bounds) {
+                    stmts.push(Stmt::Let(var, CExpr::Imm(bound)));
+                }
+                let var = self.local(name);
+                let mut scope = scope.clone();
+                scope.insert(name.clone(), var.clone());
+                let step = vec![
+                    Stmt::Let(var, CExpr::Imm(Imm::Var(next.clone()))),
+                    Stmt::Let(stepped.clone(), CExpr::Prim2(Op2::Plus, Imm::Var(next.clone()), Imm::Num(1), e.span)),
+                    Stmt::Do(CExpr::Set(next.clone(), Imm::Var(stepped))),
+                ];
+                let body = wrap(step, self.expr(body, &scope, &enter(loops, None)));
+                let done = AExpr::Value(CExpr::Break(None, Imm::Bool(false)));
+                let test = AExpr::Let(
+                    more.clone(),
+                    CExpr::Prim2(Op2::Less, Imm::Var(next), Imm::Var(end), e.span),
+                    Box::new(AExpr::Value(CExpr::If(Imm::Var(more), Box::new(body), Box::new(done)))),
+                );
+                (stmts, CExpr::Loop(None, Box::new(test)))
+            }
+            ExprKind::Break(label, expr) => {
+                // typechecker::check_loops has already rejected any other
+                debug_assert!(if label.is_some() { loops.contains(label) } else { !loops.is_empty() });
+                let (stmts, value) = self.imm(expr, scope, loops);
+                (stmts, CExpr::Break(label.clone(), value))
+            }
+            ExprKind::Continue => {
+                debug_assert!(!loops.is_empty());
+                (vec![], CExpr::Continue)
+            }
+            ExprKind::Call(name, args) => {
+                if !self.fun_ctx.check_function_exists(name) {
+                    panic!("Undefined function: {}", name);
+                }
+                let expected = self.fun_ctx.get_param_count(name);
+                if args.len() != expected {
+                    panic!("Wrong number of arguments for {}: expected {}, got {}", name, expected, args.len());
+                }
+                let args: Vec<&Expr> = args.iter().collect();
+                let (stmts, args) = self.operands(&args, scope, loops);
+                (stmts, CExpr::Call(name.clone(), args))
+            }
+        }
+    }
+}
+
+/// `loops` with one more loop, labeled `label`, nested inside
+fn enter(loops: &[Option<String>], label: Option<String>) -> Vec<Option<String>> {
+    let mut inner = loops.to_vec();
+    inner.push(label);
+    inner
+}
+
+fn lookup(scope: &HashMap<String, Var>, name: &str) -> Var {
+    scope.get(name).cloned().unwrap_or_else(|| Var::Global(name.to_string()))
+}
+
+pub fn lower_function(defn: &FunDefn, fun_ctx: &FunContext) -> Function {
+    let mut lowering = Lowering::new(fun_ctx);
+    let params: Vec<Var> = defn.params.iter().map(|p| lowering.local(p)).collect();
+    let scope = defn.params.iter().cloned().zip(params.iter().cloned()).collect();
+    let body = lowering.expr(&defn.body, &scope, &[]);
+    Function { name: defn.name.clone(), params, body }
+}
+
+/// Lower a top-level expression: a program's main, or a REPL entry
+pub fn lower_expr(e: &Expr, fun_ctx: &FunContext) -> AExpr {
+    Lowering::new(fun_ctx).expr(e, &HashMap::new(), &[])
+}
+
+pub fn lower_program(program: &crate::ast::Program, fun_ctx: &FunContext) -> Program {
+    Program {
+        functions: program.defns.iter().map(|defn| lower_function(defn, fun_ctx)).collect(),
+        main: lower_expr(&program.main, fun_ctx),
+    }
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Var::Local(name, 0) => write!(f, "{}", name),
+            Var::Local(name, n) => write!(f, "{}.{}", name, n),
+            Var::Temp(n) => write!(f, "%{}", n),
+            Var::Global(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+impl fmt::Display for Imm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Imm::Num(n) => write!(f, "{}", n),
+            Imm::Bool(b) => write!(f, "{}", b),
+            Imm::Input => write!(f, "input"),
+            Imm::Var(var) => write!(f, "{}", var),
+        }
+    }
+}
+
+fn op1_name(op: &Op1) -> &'static str {
+    match op {
+        Op1::Add1 => "add1",
+        Op1::Sub1 => "sub1",
+        Op1::IsNum => "isnum",
+        Op1::IsBool => "isbool",
+        Op1::Not => "not",
+        Op1::Print => "print",
+    }
+}
+
+fn op2_name(op: &Op2) -> &'static str {
+    match op {
+        Op2::Plus => "+",
+        Op2::Minus => "-",
+        Op2::Times => "*",
+        Op2::Divide => "/",
+        Op2::Remainder => "%",
+        Op2::BitAnd => "&",
+        Op2::BitOr => "|",
+        Op2::BitXor => "^",
+        Op2::Shl => "<<",
+        Op2::Shr => ">>",
+        Op2::Equal => "=",
+        Op2::Greater => ">",
+        Op2::GreaterEqual => ">=",
+        Op2::Less => "<",
+        Op2::LessEqual => "<=",
+        Op2::Index => "index",
+    }
+}
+
+fn indent(f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    write!(f, "{:width$}", "", width = 2 * depth)
+}
+
+/// Nested bodies go on their own lines, one step further in than `depth`
+fn fmt_cexpr(c: &CExpr, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    match c {
+        CExpr::Imm(imm) => write!(f, "{}", imm),
+        CExpr::Prim1(op, arg, _) => write!(f, "{} {}", op1_name(op), arg),
+        CExpr::Prim2(Op2::Index, tuple, index, _) => write!(f, "{}[{}]", tuple, index),
+        CExpr::Prim2(op, left, right, _) => write!(f, "{} {} {}", left, op2_name(op), right),
+        CExpr::Cast(arg, t, _) => write!(f, "cast {} {:?}", arg, t),
+        CExpr::Call(name, args) => {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            write!(f, "{}({})", name, args.join(", "))
+        }
+        CExpr::Set(var, value) => write!(f, "set {} = {}", var, value),
+        CExpr::Tuple(elements, _) => {
+            let elements: Vec<String> = elements.iter().map(|element| element.to_string()).collect();
+            write!(f, "tuple({})", elements.join(", "))
+        }
+        CExpr::TupleSet(tuple, index, value, _) => write!(f, "set {}[{}] = {}", tuple, index, value),
+        CExpr::If(cond, then_branch, else_branch) => {
+            writeln!(f, "if {} {{", cond)?;
+            fmt_aexpr(then_branch, f, depth + 1)?;
+            indent(f, depth)?;
+            writeln!(f, "}} else {{")?;
+            fmt_aexpr(else_branch, f, depth + 1)?;
+            indent(f, depth)?;
+            write!(f, "}}")
+        }
+        CExpr::Loop(label, body) => {
+            match label {
+                Some(label) => writeln!(f, "loop :{} {{", label)?,
+                None => writeln!(f, "loop {{")?,
+            }
+            fmt_aexpr(body, f, depth + 1)?;
+            indent(f, depth)?;
+            write!(f, "}}")
+        }
+        CExpr::Break(Some(label), value) => write!(f, "break :{} {}", label, value),
+        CExpr::Break(None, value) => write!(f, "break {}", value),
+        CExpr::Continue => write!(f, "continue"),
+        CExpr::Fail(Failure::Overflow, _) => write!(f, "fail overflow"),
+        CExpr::Fail(Failure::InvalidArgument, _) => write!(f, "fail invalid argument"),
+        CExpr::Fail(Failure::BadCast, _) => write!(f, "fail bad cast"),
+        CExpr::Fail(Failure::DivideByZero, _) => write!(f, "fail division by zero"),
+    }
+}
+
+/// One line per operation, each indented to `depth`
+fn fmt_aexpr(a: &AExpr, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    let mut a = a;
+    loop {
+        indent(f, depth)?;
+        match a {
+            AExpr::Let(var, c, body) => {
+                write!(f, "let {} = ", var)?;
+                fmt_cexpr(c, f, depth)?;
+                writeln!(f, ";")?;
+                a = body;
+            }
+            AExpr::Seq(c, body) => {
+                fmt_cexpr(c, f, depth)?;
+                writeln!(f, ";")?;
+                a = body;
+            }
+            AExpr::Value(c) => {
+                fmt_cexpr(c, f, depth)?;
+                return writeln!(f);
+            }
+        }
+    }
+}
+
+impl fmt::Display for AExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_aexpr(self, f, 0)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for function in &self.functions {
+            let params: Vec<String> = function.params.iter().map(|p| p.to_string()).collect();
+            writeln!(f, "fun {}({}) {{", function.name, params.join(", "))?;
+            fmt_aexpr(&function.body, f, 1)?;
+            writeln!(f, "}}\n")?;
+        }
+        writeln!(f, "main {{")?;
+        fmt_aexpr(&self.main, f, 1)?;
+        writeln!(f, "}}")
+    }
+}
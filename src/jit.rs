@@ -1,18 +1,43 @@
 // src/jit.rs
-use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
-use dynasmrt::x64::Assembler;
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi, VecAssembler};
+use dynasmrt::x64::{Assembler, X64Relocation};
 use im::HashMap;
 use crate::ast::*;
 use crate::instr::*;
-use crate::compiler::compile_to_instrs;
+use crate::compiler::{check_no_input, compile_function, compile_main, error_site_stubs, CompilerContext};
 use std::collections::HashMap as StdHashMap;
 use crate::compiler::get_input_heap_offset;
+use crate::span::describe_span;
+
+/// Anything `instr_to_dynasm` can emit into: the JIT's executable buffer,
+/// or the plain byte buffer the ELF object writer uses
+pub trait InstrAssembler: DynasmLabelApi<Relocation = X64Relocation> {
+    fn new_label(&mut self) -> DynamicLabel;
+}
+
+impl InstrAssembler for Assembler {
+    fn new_label(&mut self) -> DynamicLabel {
+        self.new_dynamic_label()
+    }
+}
+
+impl InstrAssembler for VecAssembler<X64Relocation> {
+    fn new_label(&mut self) -> DynamicLabel {
+        self.new_dynamic_label()
+    }
+}
 
 /// Compile error handlers for JIT execution
-/// This includes print handler and runtime error handlers (overflow, invalid argument, bad cast)
+/// This includes print handler and runtime error handlers (overflow, invalid argument, bad cast,
+/// division by zero, index out of bounds, out of memory)
+///
+/// After reporting, the handlers reset RSP to the value the emit_entry
+/// entry stored at [r15 + unwind_slot] and return from the entry itself,
+/// restoring the registers it saved.
 pub fn compile_error_handlers(
     ops: &mut Assembler,
     label_map: &StdHashMap<String, dynasmrt::DynamicLabel>,
+    unwind_slot: i32,
 ) {
     let snek_error_addr = crate::helpers::snek_error as *const () as i64;
     let snek_print_addr = crate::helpers::snek_print as *const () as i64;
@@ -21,6 +46,10 @@ pub fn compile_error_handlers(
     let error_overflow = label_map["error_overflow"];
     let error_invalid_arg = label_map["error_invalid_argument"];
     let error_bad_cast = label_map["error_bad_cast"];
+    let error_divide_by_zero = label_map["error_divide_by_zero"];
+    let error_index_out_of_bounds = label_map["error_index_out_of_bounds"];
+    let error_out_of_memory = label_map["error_out_of_memory"];
+    let error_common = ops.new_dynamic_label();
 
     // Print handler - called via 'call' instruction, so 'ret' is correct
     dynasm!(ops
@@ -34,44 +63,98 @@ pub fn compile_error_handlers(
         ; ret
     );
     
-    // Error handlers - jumped to via 'jo', 'jne', so must restore stack frame
+    // Error handlers - jumped to via 'jo', 'jne', with the site id in RSI
     dynasm!(ops
         ; .arch x64
         ; =>error_overflow
         ; mov rdi, 1
-        ; mov rax, QWORD snek_error_addr as _
-        ; call rax
-        ; mov rax, 0          // Return sentinel value
-        ; mov rsp, rbp        // Restore stack pointer
-        ; pop rbp             // Restore base pointer
-        ; ret                 // Return to caller
+        ; jmp =>error_common
         
         ; =>error_invalid_arg
         ; mov rdi, 2
-        ; mov rax, QWORD snek_error_addr as _
-        ; call rax
-        ; mov rax, 0
-        ; mov rsp, rbp
-        ; pop rbp
-        ; ret
+        ; jmp =>error_common
         
         ; =>error_bad_cast
         ; mov rdi, 3
+        ; jmp =>error_common
+
+        ; =>error_divide_by_zero
+        ; mov rdi, 4
+        ; jmp =>error_common
+
+        ; =>error_index_out_of_bounds
+        ; mov rdi, 5
+        ; jmp =>error_common
+
+        ; =>error_out_of_memory
+        ; mov rdi, 6
+        ; jmp =>error_common
+
+        ; =>error_common
+        ; and rsp, -16        // Checks can fire mid-expression
         ; mov rax, QWORD snek_error_addr as _
         ; call rax
-        ; mov rax, 0
-        ; mov rsp, rbp
+
+        // The tail of emit_entry's code
+        ; mov rsp, [r15 + unwind_slot]
+        ; add rsp, 8
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbx
         ; pop rbp
         ; ret
     );
 }
 
+/// Emit the per-site stubs for every error site compiled since the last call.
+/// Returns each site's id and description for the runtime to report.
+pub fn emit_error_site_stubs(
+    ctx: &mut CompilerContext,
+    ops: &mut Assembler,
+    label_map: &mut StdHashMap<String, dynasmrt::DynamicLabel>,
+    file: &str,
+    source: &str,
+) -> Vec<(usize, String)> {
+    let sites = ctx.take_error_sites();
+    emit_instrs(&error_site_stubs(&sites), ops, label_map);
+    sites
+        .iter()
+        .map(|site| (site.id, describe_span(file, source, site.span)))
+        .collect()
+}
+
+/// Pre-create a dynamic label for every label the instructions define or
+/// jump to, then emit them
+pub fn emit_instrs<A: InstrAssembler>(
+    instrs: &[Instr],
+    ops: &mut A,
+    label_map: &mut StdHashMap<String, DynamicLabel>,
+) {
+    for instr in instrs {
+        let label = match instr {
+            Instr::ILabel(label) => Some(label.as_str()),
+            _ => jump_target(instr),
+        };
+        if let Some(label) = label {
+            label_map.entry(label.to_string()).or_insert_with(|| ops.new_label());
+        }
+    }
+
+    for instr in instrs {
+        instr_to_dynasm(instr, ops, label_map);
+    }
+}
+
 pub fn compile_functions_only(
+    ctx: &mut CompilerContext,
     program: &Program,
     ops: &mut Assembler,
     defines: &mut HashMap<String, i32>,
     fun_ctx: &crate::compiler::FunContext,
     label_map: &mut StdHashMap<String, dynasmrt::DynamicLabel>,
+    unwind_slot: i32,
 ) {
     // Pre-create labels for all functions
     for defn in &program.defns {
@@ -84,234 +167,119 @@ pub fn compile_functions_only(
     let error_overflow = ops.new_dynamic_label();
     let error_invalid_arg = ops.new_dynamic_label();
     let error_bad_cast = ops.new_dynamic_label();
+    let error_divide_by_zero = ops.new_dynamic_label();
+    let error_index_out_of_bounds = ops.new_dynamic_label();
+    let error_out_of_memory = ops.new_dynamic_label();
     label_map.insert("snek_print".to_string(), snek_print);
     label_map.insert("error_overflow".to_string(), error_overflow);
     label_map.insert("error_invalid_argument".to_string(), error_invalid_arg);
     label_map.insert("error_bad_cast".to_string(), error_bad_cast);
+    label_map.insert("error_divide_by_zero".to_string(), error_divide_by_zero);
+    label_map.insert("error_index_out_of_bounds".to_string(), error_index_out_of_bounds);
+    label_map.insert("error_out_of_memory".to_string(), error_out_of_memory);
     
     // Compile all function definitions
     for defn in &program.defns {
-        let fun_label = label_map[&format!("fun_{}", defn.name)];
-        
-        dynasm!(ops
-            ; .arch x64
-            ; =>fun_label
-            ; push rbp
-            ; mov rbp, rsp
-        );
-        
-        let mut env = HashMap::new();
-        for (i, param) in defn.params.iter().enumerate() {
-            let offset = 16 + (i as i32 * 8);
-            env = env.update(param.clone(), offset);
-        }
-
-        let (instrs, min_offset) = compile_to_instrs(
-            &defn.body,
-            -8,
-            &env,
-            defines,
-            fun_ctx,
-            false,
-            &None,
-        );
-        
-        if min_offset < 0 {
-            let needed = -min_offset;
-            let stack_space = ((needed + 15) / 16) * 16;
-            dynasm!(ops
-                ; .arch x64
-                ; sub rsp, stack_space as i32
-            );
-        }
-        
-        for instr in &instrs {
-            if let Instr::ILabel(label_name) = instr {
-                if !label_map.contains_key(label_name) {
-                    label_map.insert(label_name.clone(), ops.new_dynamic_label());
-                }
-            }
-            match instr {
-                Instr::IJmp(label) | Instr::IJe(label) | Instr::IJne(label) | Instr::IJo(label) => {
-                    if !label_map.contains_key(label) {
-                        label_map.insert(label.clone(), ops.new_dynamic_label());
-                    }
-                }
-                _ => {}
-            }
-        }
-        
-        for instr in &instrs {
-            instr_to_dynasm(instr, ops, &label_map);
-        }
-        
-        dynasm!(ops
-            ; .arch x64
-            ; mov rsp, rbp
-            ; pop rbp
-            ; ret
-        );
+        let instrs = compile_function(ctx, defn, defines, fun_ctx);
+        emit_instrs(&instrs, ops, label_map);
     }
     
     // Compile error handlers (now using shared function)
-    compile_error_handlers(ops, label_map);
+    compile_error_handlers(ops, label_map, unwind_slot);
 }
 
-pub fn compile_to_jit(
-    program: &Program, 
-    ops: &mut Assembler, 
-    defines: &mut HashMap<String, i32>, 
-    fun_ctx: &crate::compiler::FunContext,
-) {
-    let mut label_map: StdHashMap<String, dynasmrt::DynamicLabel> = StdHashMap::new();
-    
-    // Pre-create labels for all functions
-    for defn in &program.defns {
-        let fun_label = ops.new_dynamic_label();
-        label_map.insert(format!("fun_{}", defn.name), fun_label);
-    }
-    
-    // Pre-create error handler labels
-    let snek_print = ops.new_dynamic_label();
-    let error_overflow = ops.new_dynamic_label();
-    let error_invalid_arg = ops.new_dynamic_label();
-    let error_bad_cast = ops.new_dynamic_label();
-    label_map.insert("snek_print".to_string(), snek_print);
-    label_map.insert("error_overflow".to_string(), error_overflow);
-    label_map.insert("error_invalid_argument".to_string(), error_invalid_arg);
-    label_map.insert("error_bad_cast".to_string(), error_bad_cast);
-    
-    // Compile all function definitions
-    for defn in &program.defns {
-        let fun_label = label_map[&format!("fun_{}", defn.name)];
-        
-        dynasm!(ops
-            ; .arch x64
-            ; =>fun_label
-            ; push rbp
-            ; mov rbp, rsp
-        );
-        
-        let mut env = HashMap::new();
-        for (i, param) in defn.params.iter().enumerate() {
-            let offset = 16 + (i as i32 * 8);
-            env = env.update(param.clone(), offset);
-        }
-
-        let (instrs, min_offset) = compile_to_instrs(
-            &defn.body,
-            -8,
-            &env,
-            defines,
-            fun_ctx,
-            false,
-            &None,
-        );
-        
-        if min_offset < 0 {
-            let needed = -min_offset;
-            let stack_space = ((needed + 15) / 16) * 16;
-            dynasm!(ops
-                ; .arch x64
-                ; sub rsp, stack_space as i32
-            );
-        }
-        
-        for instr in &instrs {
-            if let Instr::ILabel(label_name) = instr {
-                if !label_map.contains_key(label_name) {
-                    label_map.insert(label_name.clone(), ops.new_dynamic_label());
-                }
-            }
-            match instr {
-                Instr::IJmp(label) | Instr::IJe(label) | Instr::IJne(label) | Instr::IJo(label) => {
-                    if !label_map.contains_key(label) {
-                        label_map.insert(label.clone(), ops.new_dynamic_label());
-                    }
-                }
-                _ => {}
-            }
-        }
-        
-        for instr in &instrs {
-            instr_to_dynasm(instr, ops, &label_map);
-        }
-        
-        dynasm!(ops
-            ; .arch x64
-            ; mov rsp, rbp
-            ; pop rbp
-            ; ret
-        );
-    }
+/// Emit an `extern "C" fn(input: i64, heap: *mut i64) -> i64` entry that
+/// runs the routine at `body` and returns its result.
+///
+/// The entry saves the callee-saved registers and records its stack pointer
+/// at [heap + unwind_slot], so a runtime error anywhere below it unwinds
+/// straight back out (see compile_error_handlers) instead of returning into
+/// the failed computation.
+pub fn emit_entry(
+    ops: &mut Assembler,
+    unwind_slot: i32,
+    input_heap_offset: i32,
+    body: DynamicLabel,
+) -> dynasmrt::AssemblyOffset {
+    let start = ops.offset();
 
-    // Compile main
+    // RDI = input, RSI = heap base. Six pushes plus the pad keep RSP
+    // 16-byte aligned at the call to the body.
     dynasm!(ops
         ; .arch x64
         ; push rbp
         ; mov rbp, rsp
-    );
-    
-    let input_heap_offset = get_input_heap_offset();
-    dynasm!(ops
-        ; .arch x64
+        ; push rbx
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; sub rsp, 8
+        ; mov r15, rsi
+        ; mov [r15 + unwind_slot], rsp
         ; mov [r15 + input_heap_offset], rdi
+        ; call =>body
+        ; add rsp, 8
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbx
+        ; pop rbp
+        ; ret
     );
-    
-    let (instrs, min_offset) = compile_to_instrs(
-        &program.main, 
-        -8, 
-        &HashMap::new(), 
-        defines, 
-        fun_ctx, 
-        true, 
-        &None
-    );
-    
-    if min_offset <= -16 {
-        let needed = -min_offset - 8;
-        let stack_space = ((needed + 15) / 16) * 16;
-        dynasm!(ops
-            ; .arch x64
-            ; sub rsp, stack_space as i32
-        );
-    }
 
-    // Collect all labels from main instructions
-    for instr in &instrs {
-        if let Instr::ILabel(label_name) = instr {
-            if !label_map.contains_key(label_name) {
-                label_map.insert(label_name.clone(), ops.new_dynamic_label());
-            }
-        }
-        match instr {
-            Instr::IJmp(label) | Instr::IJe(label) | Instr::IJne(label) | Instr::IJo(label) => {
-                if !label_map.contains_key(label) {
-                    label_map.insert(label.clone(), ops.new_dynamic_label());
-                }
-            }
-            _ => {}
-        }
+    start
+}
+
+/// Compile a whole program for in-process execution. Returns the offset of
+/// its emit_entry entry point.
+pub fn compile_to_jit(
+    ctx: &mut CompilerContext,
+    program: &Program,
+    ops: &mut Assembler,
+    fun_ctx: &crate::compiler::FunContext,
+    label_map: &mut StdHashMap<String, dynasmrt::DynamicLabel>,
+) -> dynasmrt::AssemblyOffset {
+    for defn in &program.defns {
+        check_no_input(&defn.body);
     }
-    
-    // Emit main instructions
-    for instr in &instrs {
-        instr_to_dynasm(instr, ops, &label_map);
+
+    let unwind_slot = ctx.alloc_heap_slot();
+    compile_functions_only(ctx, program, ops, &mut HashMap::new(), fun_ctx, label_map, unwind_slot);
+
+    let main_label = ops.new_dynamic_label();
+    let input_heap_offset = get_input_heap_offset(ctx);
+    let start = emit_entry(ops, unwind_slot, input_heap_offset, main_label);
+
+    dynasm!(ops; .arch x64; =>main_label);
+    let instrs = compile_main(ctx, &program.main, &HashMap::new(), fun_ctx);
+    emit_instrs(&instrs, ops, label_map);
+
+    start
+}
+
+/// Hardware encoding of a register, for dynasm's `Rq(n)` operands
+fn reg_num(reg: &Reg) -> u8 {
+    match reg {
+        Reg::RAX => 0,
+        Reg::RCX => 1,
+        Reg::RDX => 2,
+        Reg::RBX => 3,
+        Reg::RSP => 4,
+        Reg::RBP => 5,
+        Reg::RSI => 6,
+        Reg::RDI => 7,
+        Reg::R8 => 8,
+        Reg::R9 => 9,
+        Reg::R12 => 12,
+        Reg::R13 => 13,
+        Reg::R14 => 14,
+        Reg::R15 => 15,
     }
-    
-    // Main epilogue
-    dynasm!(ops
-        ; .arch x64
-        ; mov rsp, rbp
-        ; pop rbp
-        ; ret
-    );
-    
-    // Compile error handlers (now using shared function)
-    compile_error_handlers(ops, &label_map);
 }
 
-pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMap<String, dynasmrt::DynamicLabel>) {
+pub fn instr_to_dynasm<A: InstrAssembler>(instr: &Instr, ops: &mut A, label_map: &StdHashMap<String, DynamicLabel>) {
     // Helper macros for signed RBP offsets
     macro_rules! load_rbp {
         ($reg:ident, $offset:expr) => {
@@ -395,6 +363,9 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
                 (Val::Reg(Reg::R9), Val::Imm(n)) => dynasm!(ops; .arch x64; mov r9, QWORD *n as i64),
 
                 (Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; mov rax, rcx),
+                (Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)) => dynasm!(ops; .arch x64; mov rbp, rsp),
+                (Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)) => dynasm!(ops; .arch x64; mov rsp, rbp),
+                (Val::Reg(Reg::R15), Val::Reg(Reg::RSI)) => dynasm!(ops; .arch x64; mov r15, rsi),
                 (Val::Reg(Reg::RAX), Val::Reg(Reg::RDI)) => dynasm!(ops; .arch x64; mov rax, rdi),
                 (Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)) => dynasm!(ops; .arch x64; mov rcx, rax),
                 (Val::Reg(Reg::RDI), Val::Reg(Reg::RAX)) => dynasm!(ops; .arch x64; mov rdi, rax),
@@ -426,6 +397,24 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
                 (Val::RegOffset(Reg::R15, offset), Val::Reg(Reg::RDI)) =>
                     dynasm!(ops; .arch x64; mov [r15 + *offset], rdi),
 
+                // Anything else the register allocator produces
+                (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; mov Rq(reg_num(d)), Rq(reg_num(s))),
+                (Val::Reg(d), Val::RegOffset(Reg::RBP, offset)) =>
+                    dynasm!(ops; .arch x64; mov Rq(reg_num(d)), [rbp + *offset]),
+                (Val::RegOffset(Reg::RBP, offset), Val::Reg(s)) =>
+                    dynasm!(ops; .arch x64; mov [rbp + *offset], Rq(reg_num(s))),
+                (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; mov Rq(reg_num(d)), QWORD *n as i64),
+                (Val::Reg(d), Val::Imm64(n)) => dynasm!(ops; .arch x64; mov Rq(reg_num(d)), QWORD *n),
+                (Val::Reg(d), Val::RegOffset(Reg::R15, offset)) =>
+                    dynasm!(ops; .arch x64; mov Rq(reg_num(d)), [r15 + *offset]),
+                (Val::RegOffset(Reg::R15, offset), Val::Reg(s)) =>
+                    dynasm!(ops; .arch x64; mov [r15 + *offset], Rq(reg_num(s))),
+                // Tuple elements
+                (Val::Reg(d), Val::RegOffset(b, offset)) =>
+                    dynasm!(ops; .arch x64; mov Rq(reg_num(d)), [Rq(reg_num(b)) + *offset]),
+                (Val::RegOffset(b, offset), Val::Reg(s)) =>
+                    dynasm!(ops; .arch x64; mov [Rq(reg_num(b)) + *offset], Rq(reg_num(s))),
+
                 _ => panic!("Unsupported mov pattern in JIT: {:?} <- {:?}", dest, src),
             }
         }
@@ -435,6 +424,8 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
             (Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; add rax, rcx),
             (Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, offset)) => add_rbp!(rax, offset),
             (Val::Reg(Reg::RSP), Val::Imm(n)) => dynasm!(ops; .arch x64; add rsp, *n as i32),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; add Rq(reg_num(d)), Rq(reg_num(s))),
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; add Rq(reg_num(d)), *n),
             _ => panic!("Unsupported add pattern in JIT: {:?} += {:?}", dest, src),
         },
 
@@ -443,12 +434,15 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
             (Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; sub rax, rcx),
             (Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, offset)) => sub_rbp!(rax, offset),
             (Val::Reg(Reg::RSP), Val::Imm(n)) => dynasm!(ops; .arch x64; sub rsp, *n as i32),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; sub Rq(reg_num(d)), Rq(reg_num(s))),
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; sub Rq(reg_num(d)), *n),
             _ => panic!("Unsupported sub pattern in JIT: {:?} -= {:?}", dest, src),
         },
 
         Instr::IMul(dest, src) => match (dest, src) {
             (Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; imul rax, rcx),
             (Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, offset)) => imul_rbp!(rax, offset),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; imul Rq(reg_num(d)), Rq(reg_num(s))),
             _ => panic!("Unsupported imul pattern in JIT: {:?} *= {:?}", dest, src),
         },
 
@@ -457,25 +451,51 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
             (Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; cmp rax, rcx),
             (Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)) => dynasm!(ops; .arch x64; cmp rcx, rax),
             (Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, offset)) => cmp_rbp!(rax, offset),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; cmp Rq(reg_num(d)), Rq(reg_num(s))),
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; cmp Rq(reg_num(d)), *n),
             _ => panic!("Unsupported cmp pattern in JIT: {:?} cmp {:?}", dest, src),
         },
 
         Instr::ITest(dest, src) => match (dest, src) {
             (Val::Reg(Reg::RAX), Val::Imm(n)) => dynasm!(ops; .arch x64; test rax, *n as i32),
             (Val::Reg(Reg::RCX), Val::Imm(n)) => dynasm!(ops; .arch x64; test rcx, *n as i32),
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; test Rq(reg_num(d)), *n),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; test Rq(reg_num(d)), Rq(reg_num(s))),
             _ => panic!("Unsupported test pattern in JIT: {:?} test {:?}", dest, src),
         },
 
         Instr::IOr(dest, src) => match (dest, src) {
             (Val::Reg(Reg::RCX), Val::RegOffset(Reg::RBP, offset)) => or_rbp!(rcx, offset),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; or Rq(reg_num(d)), Rq(reg_num(s))),
             _ => panic!("Unsupported or pattern in JIT: {:?} | {:?}", dest, src),
         },
 
+        Instr::IAnd(dest, src) => match (dest, src) {
+            (Val::Reg(Reg::RSP), Val::Imm(n)) => dynasm!(ops; .arch x64; and rsp, *n),
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; and Rq(reg_num(d)), *n),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; and Rq(reg_num(d)), Rq(reg_num(s))),
+            _ => panic!("Unsupported and pattern in JIT: {:?} & {:?}", dest, src),
+        },
+
+        Instr::IXor(dest, src) => match (dest, src) {
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; xor Rq(reg_num(d)), *n),
+            (Val::Reg(d), Val::Reg(s)) => dynasm!(ops; .arch x64; xor Rq(reg_num(d)), Rq(reg_num(s))),
+            _ => panic!("Unsupported xor pattern in JIT: {:?} ^ {:?}", dest, src),
+        },
+
         Instr::ISar(dest, src) => match (dest, src) {
             (Val::Reg(Reg::RAX), Val::Imm(n)) => dynasm!(ops; .arch x64; sar rax, *n as i8),
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; sar Rq(reg_num(d)), *n as i8),
+            (Val::Reg(d), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; sar Rq(reg_num(d)), cl),
             _ => panic!("Unsupported sar pattern in JIT: {:?} >> {:?}", dest, src),
         },
 
+        Instr::IShl(dest, src) => match (dest, src) {
+            (Val::Reg(d), Val::Imm(n)) => dynasm!(ops; .arch x64; shl Rq(reg_num(d)), *n as i8),
+            (Val::Reg(d), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; shl Rq(reg_num(d)), cl),
+            _ => panic!("Unsupported shl pattern in JIT: {:?} << {:?}", dest, src),
+        },
+
         Instr::ICMovE(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; cmove rax, rcx),
         Instr::ICMovNE(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; cmovne rax, rcx),
         Instr::ICMovG(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)) => dynasm!(ops; .arch x64; cmovg rax, rcx),
@@ -497,6 +517,21 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
         Instr::IJne(label_name) => if let Some(&label) = label_map.get(label_name) {
             dynasm!(ops; .arch x64; jne =>label);
         },
+        Instr::IJl(label_name) => if let Some(&label) = label_map.get(label_name) {
+            dynasm!(ops; .arch x64; jl =>label);
+        },
+        Instr::IJle(label_name) => if let Some(&label) = label_map.get(label_name) {
+            dynasm!(ops; .arch x64; jle =>label);
+        },
+        Instr::IJg(label_name) => if let Some(&label) = label_map.get(label_name) {
+            dynasm!(ops; .arch x64; jg =>label);
+        },
+        Instr::IJge(label_name) => if let Some(&label) = label_map.get(label_name) {
+            dynasm!(ops; .arch x64; jge =>label);
+        },
+        Instr::IJz(label_name) => if let Some(&label) = label_map.get(label_name) {
+            dynasm!(ops; .arch x64; jz =>label);
+        },
         Instr::IJo(label_name) => if let Some(&label) = label_map.get(label_name) {
             dynasm!(ops; .arch x64; jo =>label);
         },
@@ -521,6 +556,8 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
             Val::Reg(Reg::RAX) => dynasm!(ops; .arch x64; push rax),
             Val::Reg(Reg::RCX) => dynasm!(ops; .arch x64; push rcx),
             Val::Reg(Reg::RDI) => dynasm!(ops; .arch x64; push rdi),
+            Val::Reg(Reg::RBP) => dynasm!(ops; .arch x64; push rbp),
+            Val::Reg(Reg::R15) => dynasm!(ops; .arch x64; push r15),
             _ => panic!("Unsupported push: {:?}", val),
         },
 
@@ -528,12 +565,17 @@ pub fn instr_to_dynasm(instr: &Instr, ops: &mut Assembler, label_map: &StdHashMa
             Val::Reg(Reg::RAX) => dynasm!(ops; .arch x64; pop rax),
             Val::Reg(Reg::RCX) => dynasm!(ops; .arch x64; pop rcx),
             Val::Reg(Reg::RDI) => dynasm!(ops; .arch x64; pop rdi),
+            Val::Reg(Reg::RBP) => dynasm!(ops; .arch x64; pop rbp),
+            Val::Reg(Reg::R15) => dynasm!(ops; .arch x64; pop r15),
             _ => panic!("Unsupported pop: {:?}", val),
         },
 
         Instr::IRet => dynasm!(ops; .arch x64; ret),
         Instr::IComment(_) => {},
 
+        Instr::ICqo => dynasm!(ops; .arch x64; cqo),
+        Instr::IIdiv(Val::Reg(r)) => dynasm!(ops; .arch x64; idiv Rq(reg_num(r))),
+
         _ => panic!("Unsupported instruction in JIT: {:?}", instr),
     }
 }
\ No newline at end of file
@@ -0,0 +1,21 @@
+// src/lib.rs
+pub mod ast;
+pub mod instr;
+pub mod span;
+pub mod reader;
+pub mod parser;
+pub mod ir;
+pub mod compiler;
+pub mod optimize;
+pub mod inline;
+pub mod regalloc;
+pub mod peephole;
+pub mod jit;
+pub mod repl;
+pub mod helpers;
+pub mod typechecker;
+pub mod engine;
+pub mod build;
+pub mod elf;
+
+pub use engine::{CompiledProgram, Engine, Error, Output, RuntimeError, Value};
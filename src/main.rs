@@ -1,46 +1,49 @@
 // src/main.rs
-mod ast;
-mod instr;
-mod parser;
-mod compiler;
-mod jit;
-mod repl;
-mod helpers;
-mod typechecker;
-
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
-use im::HashMap;
-use dynasmrt::*;
+use std::path::{Path, PathBuf};
 use capstone::prelude::*;
 
-use crate::compiler::FunContext;
-use crate::compiler::get_input_heap_offset;
-use crate::instr::Instr;
-use crate::jit::compile_functions_only;
-use crate::compiler::compile;
-use crate::parser::parse_program;
-use crate::helpers::*;
-use crate::repl::run_repl;
-use crate::typechecker::*;
-use crate::ast::*;
+use cobra::ast::Type;
+use cobra::optimize::OptLevel;
+use cobra::repl::run_repl;
+use cobra::{Engine, Error, Value};
 
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // The optimization level may go anywhere on the command line
+    let mut opt_level = OptLevel::default();
+    args.retain(|arg| {
+        let level = match arg.as_str() {
+            "-O0" => OptLevel::O0,
+            "-O1" => OptLevel::O1,
+            "-O2" => OptLevel::O2,
+            _ => return true,
+        };
+        opt_level = level;
+        false
+    });
 
     if args.len() < 2 {
         eprintln!("Usage: {} <flag> <input.snek> [output.s/input]", args[0]);
         eprintln!("Flags:");
         eprintln!("  -c: Compile to assembly file (requires output file)");
+        eprintln!("  -o: Compile to an ELF object file (requires output file)");
         eprintln!("  -e: Execute directly using JIT compilation");
         eprintln!("  -g: Do both - execute and generate assembly");
         eprintln!("  -i: Interactive REPL mode");
         eprintln!("  -t: Typecheck only and print type");
         eprintln!("  -tc: Typecheck and compile to assembly");
+        eprintln!("  -to: Typecheck and compile to an ELF object file");
         eprintln!("  -te: Typecheck and execute with JIT");
         eprintln!("  -tg: Typecheck and do both (execute + generate)");
         eprintln!("  -ti: Interactive REPL with typechecking");
+        eprintln!("  build: Compile and link an executable (build <input.snek> [-o <output>])");
+        eprintln!("  --emit ir: Print the program's intermediate representation (--emit ir <input.snek>)");
+        eprintln!("Options:");
+        eprintln!("  -O0, -O1, -O2: No optimization, constant folding, or that plus inlining (default)");
         std::process::exit(1);
     }
 
@@ -49,10 +52,10 @@ fn main() -> std::io::Result<()> {
     // Handle REPL modes
     match flag.as_str() {
         "-i" => {
-            return run_repl(false); // No typechecking
+            return run_repl(false, opt_level); // No typechecking
         }
         "-ti" => {
-            return run_repl(true); // With typechecking
+            return run_repl(true, opt_level); // With typechecking
         }
         _ => {}
     }
@@ -62,75 +65,69 @@ fn main() -> std::io::Result<()> {
         std::process::exit(1);
     }
 
+    if flag == "--emit" {
+        if args[2] != "ir" || args.len() < 4 {
+            eprintln!("Usage: {} --emit ir <input.snek>", args[0]);
+            std::process::exit(1);
+        }
+        let mut in_contents = String::new();
+        File::open(&args[3])?.read_to_string(&mut in_contents)?;
+        let engine = Engine::new().file_name(&args[3]).opt_level(opt_level);
+        print!("{}", or_exit(engine.compile_to_ir(&in_contents)));
+        return Ok(());
+    }
+
     let in_name = &args[2];
     
     let mut in_file = File::open(in_name)?;
     let mut in_contents = String::new();
     in_file.read_to_string(&mut in_contents)?;
     
-    // Trim leading/trailing whitespace
-    let trimmed = in_contents.trim();
-
-    // Detect if it starts and ends with '(' … ')' (i.e., already a top-level list)
-    let wrapped_source = 
-        if trimmed.starts_with("((") && trimmed.ends_with("))") {
-            trimmed.to_string()
-        } 
-        else {
-            format!("({})", trimmed)
-        };    
-
-    let sexp = sexp::parse(&wrapped_source).map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Parse error: {}", e))
-    })?;
-
-    let prog = parse_program(&sexp);
-
-    let mut defines: HashMap<String, Type> = HashMap::new();
+    let mut engine = Engine::new().file_name(in_name).opt_level(opt_level);
 
     // Determine if we're in typecheck mode and get input type if needed
     let typecheck_mode = flag.starts_with("-t");
     
     if typecheck_mode {
         let input_type = match flag.as_str() {
-            "-t" | "-tc" => {
-                // No input provided, input has type Any
-                None
-            }
             "-te" => {
                 // For -te: input is at args[3]
                 let input_str = if args.len() > 3 { &args[3] } else { "false" };
-                let input = parse_input(input_str);
-                Some(if input & 1 == 0 { Type::Num } else { Type::Bool })
+                input_value(input_str).type_of()
             }
             "-tg" => {
                 // For -tg: format is -tg <prog>.snek <prog>.s <input>
                 // So input is at args[4]
                 let input_str = if args.len() > 4 { &args[4] } else { "false" };
-                let input = parse_input(input_str);
-                Some(if input & 1 == 0 { Type::Num } else { Type::Bool })
+                input_value(input_str).type_of()
             }
-            _ => None
+            // -t and -tc have no input, so it has type Any
+            _ => Type::Any
         };
-    
-        // Run typechecker
-        match typecheck_program(&prog, input_type) {
-            Ok(t) => {
-                if flag == "-t" {
-                    // Just print the type and exit
-                    println!("{:?}", t);
-                    return Ok(());
-                }
-                // For other -t* flags, continue to compilation/execution
-            }
-            Err(e) => {
-                eprintln!("{}", e);
-                std::process::exit(1);
-            }
-        }
+        engine = engine.typecheck(input_type);
+    }
+
+    if flag == "-t" {
+        // Just print the type and exit
+        let t = engine.type_of(&in_contents);
+        println!("{:?}", or_exit(t));
+        return Ok(());
     }
     
     match flag.as_str() {
+        "build" => {
+            // Format: build <prog>.snek [-o <prog>], defaulting to the input
+            // name without its extension
+            let out_name = match args.get(3).map(String::as_str) {
+                Some("-o") if args.len() > 4 => PathBuf::from(&args[4]),
+                None => Path::new(in_name).with_extension(""),
+                Some(_) => {
+                    eprintln!("Usage: {} build <input.snek> [-o <output>]", args[0]);
+                    std::process::exit(1);
+                }
+            };
+            or_exit(engine.build(&in_contents, &out_name));
+        }
         "-c" | "-tc" => {
             // AOT compilation only
             if args.len() < 4 {
@@ -138,107 +135,29 @@ fn main() -> std::io::Result<()> {
                 std::process::exit(1);
             }
             let out_name = &args[3];
-            let result = compile(&prog);
-            let asm_program = format!(
-                "section .text
-global our_code_starts_here
-extern snek_error
-extern snek_print
-
-{}",
-                result
-            );
+            let asm_program = or_exit(engine.compile_to_asm(&in_contents));
             let mut out_file = File::create(out_name)?;
             out_file.write_all(asm_program.as_bytes())?;
         }
+        "-o" | "-to" => {
+            // AOT compilation straight to an object file, no nasm needed
+            if args.len() < 4 {
+                eprintln!("Error: output file required for -o/-to");
+                std::process::exit(1);
+            }
+            let out_name = &args[3];
+            let object = or_exit(engine.compile_to_object(&in_contents));
+            let mut out_file = File::create(out_name)?;
+            out_file.write_all(&object)?;
+        }
         "-e" | "-te" => {
             // JIT execution
             let input_str = if args.len() > 3 { &args[3] } else { "false" };
-            let input = parse_input(input_str);
-            
-            let mut __ops__ = dynasmrt::x64::Assembler::new().unwrap();
-            let heap: Vec<i64> = vec![0; 128 * 1024];
-            let heap_ptr = heap.as_ptr() as i64;
-            
-            let mut __fun_ctx__ = FunContext::new(&prog.defns);
-            let mut __label_map__ = std::collections::HashMap::new();
-            
-            // Compile function definitions and error handlers
-            compile_functions_only(&prog, &mut __ops__, &mut HashMap::new(), &mut __fun_ctx__, &mut __label_map__);
-            
-            // Capture the offset - this is where main starts
-            let start = __ops__.offset();
-            
-            // Set up heap pointer
-            dynasm!(__ops__
-                ; .arch x64
-                ; push rbp
-                ; mov rbp, rsp
-                ; mov r15, QWORD heap_ptr as _
-            );
-            
-            // Store input
-            let input_heap_offset = get_input_heap_offset();
-            dynasm!(__ops__
-                ; .arch x64
-                ; mov [r15 + input_heap_offset], rdi
-            );
-            
-            // Compile main
-            let (main_instrs, min_offset) = crate::compiler::compile_to_instrs(
-                &prog.main,
-                -8,
-                &HashMap::new(),
-                &mut HashMap::new(),
-                &mut __fun_ctx__,
-                true,
-                &None,
-            );
-            
-            // Allocate stack if needed
-            if min_offset <= -16 {
-                let needed = -min_offset - 8;
-                let stack_space = ((needed + 15) / 16) * 16;
-                dynasm!(__ops__; .arch x64; sub rsp, stack_space as i32);
-            }
-            
-            // Pre-create labels for main
-            for instr in &main_instrs {
-                if let Instr::ILabel(label_name) = instr {
-                    if !__label_map__.contains_key(label_name) {
-                        __label_map__.insert(label_name.clone(), __ops__.new_dynamic_label());
-                    }
-                }
-                match instr {
-                    Instr::IJmp(l) | Instr::IJe(l) | Instr::IJne(l) | Instr::IJo(l) => {
-                        if !__label_map__.contains_key(l) {
-                            __label_map__.insert(l.clone(), __ops__.new_dynamic_label());
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            
-            // Emit main instructions
-            for instr in &main_instrs {
-                crate::jit::instr_to_dynasm(instr, &mut __ops__, &__label_map__);
-            }
-            
-            // Epilogue
-            dynasm!(__ops__
-                ; .arch x64
-                ; mov rsp, rbp
-                ; pop rbp
-                ; ret
-            );
-            
-            let buf = __ops__.finalize().unwrap();
-            
-            let jitted_fn: extern "C" fn(i64) -> i64 = unsafe { std::mem::transmute(buf.ptr(start)) };
-            let result_val = jitted_fn(input);
-            
-            std::mem::forget(heap);
-            print_result(result_val);
+            let input = input_value(input_str);
+
+            let output = or_exit(engine.compile(&in_contents).and_then(|prog| prog.run(input)));
+            print!("{}", output.printed);
+            println!("{}", output.value);
         }
         "-g" | "-tg" => {
             // Both: JIT execution and write assembly to file
@@ -250,94 +169,15 @@ extern snek_print
 
             let out_name = &args[3];
             let input_str = if args.len() > 4 { &args[4] } else { "false" };
-            let input = parse_input(input_str);
+            let input = input_value(input_str);
 
             // === JIT COMPILATION AND EXECUTION ===
-            let mut __ops__ = dynasmrt::x64::Assembler::new().unwrap();
-            let heap: Vec<i64> = vec![0; 128 * 1024];
-            let heap_ptr = heap.as_ptr() as i64;
-
-            let mut __fun_ctx__ = FunContext::new(&prog.defns);
-            let mut __label_map__ = std::collections::HashMap::new();
-
-            // Compile function definitions and error handlers
-            compile_functions_only(&prog, &mut __ops__, &mut HashMap::new(), &mut __fun_ctx__, &mut __label_map__);
-
-            // Capture the offset - this is where main starts
-            let start = __ops__.offset();
-
-            // Set up heap pointer and function prologue
-            dynasm!(__ops__
-                ; .arch x64
-                ; push rbp
-                ; mov rbp, rsp
-                ; mov r15, QWORD heap_ptr as _
-            );
-
-            // Store input to heap
-            let input_heap_offset = get_input_heap_offset();
-            dynasm!(__ops__
-                ; .arch x64
-                ; mov [r15 + input_heap_offset], rdi
-            );
-
-            // Compile main expression
-            let (main_instrs, min_offset) = crate::compiler::compile_to_instrs(
-                &prog.main,
-                -8,
-                &HashMap::new(),
-                &mut HashMap::new(),
-                &mut __fun_ctx__,
-                true,
-                &None,
-            );
-
-            // Allocate stack space if needed
-            if min_offset <= -16 {
-                let needed = -min_offset - 8;
-                let stack_space = ((needed + 15) / 16) * 16;
-                dynasm!(__ops__; .arch x64; sub rsp, stack_space as i32);
-            }
-
-            // Pre-create labels for main
-            for instr in &main_instrs {
-                if let Instr::ILabel(label_name) = instr {
-                    if !__label_map__.contains_key(label_name) {
-                        __label_map__.insert(label_name.clone(), __ops__.new_dynamic_label());
-                    }
-                }
-                match instr {
-                    Instr::IJmp(l) | Instr::IJe(l) | Instr::IJne(l) | Instr::IJo(l) => {
-                        if !__label_map__.contains_key(l) {
-                            __label_map__.insert(l.clone(), __ops__.new_dynamic_label());
-                        }
-                    }
-                    _ => {}
-                }
-            }
-
-            // Emit main instructions
-            for instr in &main_instrs {
-                crate::jit::instr_to_dynasm(instr, &mut __ops__, &__label_map__);
-            }
-
-            // Main epilogue
-            dynasm!(__ops__
-                ; .arch x64
-                ; mov rsp, rbp
-                ; pop rbp
-                ; ret
-            );
-
-            // Execute JIT
-            let buf = __ops__.finalize().unwrap();
-            let jitted_fn: extern "C" fn(i64) -> i64 = unsafe { std::mem::transmute(buf.ptr(start)) };
-            let result_val = jitted_fn(input);
-
-            std::mem::forget(heap);
+            let compiled = or_exit(engine.compile(&in_contents));
+            let output = or_exit(compiled.run(input));
+            print!("{}", output.printed);
 
             println!("JIT Result: ");
-            print_result(result_val);
+            println!("{}", output.value);
             
             // Disassemble JITed code from capstone
             fn disassemble(buf: &[u8]) {
@@ -356,19 +196,11 @@ extern snek_print
                         i.op_str().unwrap_or(""));
                 }
             }
-            disassemble(&buf);
+            disassemble(compiled.code());
+            println!("Peephole optimizer saved {} instructions", compiled.peephole_saved());
 
             // === AOT COMPILATION OUTPUT ===
-            let result = compile(&prog);
-            let asm_program = format!(
-                "section .text
-global our_code_starts_here
-extern snek_error
-extern snek_print
-
-{}",
-                result
-            );
+            let asm_program = or_exit(engine.compile_to_asm(&in_contents));
             println!("\n=== Generated Assembly ===");
             println!("{}", asm_program);
             let mut out_file = File::create(out_name)?;
@@ -383,4 +215,24 @@ extern snek_print
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn input_value(input: &str) -> Value {
+    or_exit(input.parse())
+}
+
+/// Report an error the way the runtime would and exit
+fn or_exit<T>(result: Result<T, Error>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(Error::Runtime(e)) => {
+            print!("{}", e.printed);
+            eprintln!("{}", e.message);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
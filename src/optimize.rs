@@ -0,0 +1,289 @@
+// src/optimize.rs
+// Optimizations over the IR, run between lowering and code generation.
+//
+// Constant folding evaluates operations whose operands are known at compile
+// time, replaces `let`-bound constants and copies that are never `set!` with
+// their values, and keeps only the taken branch of an `if` on a constant. A
+// check that is certain to fail folds into a jump to the error it raises, so
+// `(* 2147483647 (* 2147483647 2))` compiles to the overflow error.
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Op1, Op2, Type, MAX_NUM, MIN_NUM};
+use crate::compiler::FunContext;
+use crate::inline::inline_calls;
+use crate::ir::{flatten, wrap, AExpr, CExpr, Failure, Imm, Program, Stmt, Var};
+
+/// How hard to optimize, as in `-O0` to `-O2`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Generate code straight from the lowered IR
+    O0,
+    /// Fold constants
+    O1,
+    /// Also inline small functions
+    #[default]
+    O2,
+}
+
+/// A value known at compile time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Const {
+    Num(i64),
+    Bool(bool),
+}
+
+fn to_imm(k: Const) -> Imm {
+    match k {
+        Const::Num(n) => Imm::Num(n),
+        Const::Bool(b) => Imm::Bool(b),
+    }
+}
+
+fn num(k: Const) -> Result<i64, Failure> {
+    match k {
+        Const::Num(n) => Ok(n),
+        Const::Bool(_) => Err(Failure::InvalidArgument),
+    }
+}
+
+fn checked(n: Option<i64>) -> Result<Const, Failure> {
+    match n {
+        Some(n) if (MIN_NUM..=MAX_NUM).contains(&n) => Ok(Const::Num(n)),
+        _ => Err(Failure::Overflow),
+    }
+}
+
+fn eval_prim1(op: &Op1, arg: Const) -> Option<Result<Const, Failure>> {
+    Some(match op {
+        Op1::Add1 => num(arg).and_then(|n| checked(n.checked_add(1))),
+        Op1::Sub1 => num(arg).and_then(|n| checked(n.checked_sub(1))),
+        Op1::IsNum => Ok(Const::Bool(matches!(arg, Const::Num(_)))),
+        Op1::IsBool => Ok(Const::Bool(matches!(arg, Const::Bool(_)))),
+        Op1::Not => match arg {
+            Const::Bool(b) => Ok(Const::Bool(!b)),
+            Const::Num(_) => Err(Failure::InvalidArgument),
+        },
+        Op1::Print => return None,
+    })
+}
+
+/// With only one operand known, arithmetic and comparisons still fail if it
+/// isn't a number
+fn eval_prim2(op: &Op2, left: Option<Const>, right: Option<Const>) -> Option<Result<Const, Failure>> {
+    if let Op2::Equal = op {
+        return Some(Ok(Const::Bool(left? == right?)));
+    }
+    if let (Some(Const::Bool(_)), _) | (_, Some(Const::Bool(_))) = (left, right) {
+        return Some(Err(Failure::InvalidArgument));
+    }
+    let (l, r) = (num(left?).ok()?, num(right?).ok()?);
+    Some(match op {
+        Op2::Plus => checked(l.checked_add(r)),
+        Op2::Minus => checked(l.checked_sub(r)),
+        Op2::Times => checked(l.checked_mul(r)),
+        Op2::Divide | Op2::Remainder if r == 0 => Err(Failure::DivideByZero),
+        // Both truncate toward zero, as `idiv` does
+        Op2::Divide => checked(l.checked_div(r)),
+        Op2::Remainder => checked(l.checked_rem(r)),
+        Op2::BitAnd => Ok(Const::Num(l & r)),
+        Op2::BitOr => Ok(Const::Num(l | r)),
+        Op2::BitXor => Ok(Const::Num(l ^ r)),
+        Op2::Shl | Op2::Shr if r < 0 => Err(Failure::InvalidArgument),
+        // Past 62 every bit of a nonzero number is shifted out
+        Op2::Shl if r > 62 => checked((l == 0).then_some(0)),
+        Op2::Shl => checked(i64::try_from(i128::from(l) << r).ok()),
+        Op2::Shr => Ok(Const::Num(l >> r.min(63))),
+        Op2::Greater => Ok(Const::Bool(l > r)),
+        Op2::GreaterEqual => Ok(Const::Bool(l >= r)),
+        Op2::Less => Ok(Const::Bool(l < r)),
+        Op2::LessEqual => Ok(Const::Bool(l <= r)),
+        // A constant is never a tuple
+        Op2::Index => Err(Failure::InvalidArgument),
+        Op2::Equal => unreachable!(),
+    })
+}
+
+fn eval_cast(arg: Const, t: &Type) -> Result<Const, Failure> {
+    match (arg, t) {
+        (_, Type::Any) | (Const::Num(_), Type::Num) | (Const::Bool(_), Type::Bool) => Ok(arg),
+        _ => Err(Failure::BadCast),
+    }
+}
+
+/// Variables some `set!` in `a` assigns to
+fn assigned_vars(a: &AExpr, vars: &mut HashSet<Var>) {
+    let c = match a {
+        AExpr::Let(_, c, body) | AExpr::Seq(c, body) => {
+            assigned_vars(body, vars);
+            c
+        }
+        AExpr::Value(c) => c,
+    };
+    match c {
+        CExpr::Set(var, _) => {
+            vars.insert(var.clone());
+        }
+        CExpr::If(_, then_branch, else_branch) => {
+            assigned_vars(then_branch, vars);
+            assigned_vars(else_branch, vars);
+        }
+        CExpr::Loop(_, body) => assigned_vars(body, vars),
+        _ => {}
+    }
+}
+
+struct Folder {
+    assigned: HashSet<Var>,
+    // Variables bound once to a known value. Lowering numbered every
+    // binding apart, so these need no scoping.
+    known: HashMap<Var, Const>,
+    // Variables bound once to a copy of `input` or of another local that is
+    // never assigned either
+    copies: HashMap<Var, Imm>,
+}
+
+impl Folder {
+    fn value(&self, imm: &Imm) -> Option<Const> {
+        match imm {
+            Imm::Num(n) => Some(Const::Num(*n)),
+            Imm::Bool(b) => Some(Const::Bool(*b)),
+            Imm::Var(var) => self.known.get(var).copied(),
+            Imm::Input => None,
+        }
+    }
+
+    /// `imm` with a known variable replaced by its value, and a copy by
+    /// what it copies
+    fn imm(&self, imm: Imm) -> Imm {
+        if let Some(k) = self.value(&imm) {
+            return to_imm(k);
+        }
+        match &imm {
+            Imm::Var(var) => self.copies.get(var).cloned().unwrap_or(imm),
+            _ => imm,
+        }
+    }
+
+    /// What `c` evaluates to, if its operands are known
+    fn eval(&self, c: &CExpr) -> Option<Result<Const, Failure>> {
+        match c {
+            CExpr::Imm(imm) => self.value(imm).map(Ok),
+            CExpr::Prim1(op, arg, _) => eval_prim1(op, self.value(arg)?),
+            CExpr::Prim2(op, left, right, _) => eval_prim2(op, self.value(left), self.value(right)),
+            CExpr::Cast(arg, t, _) => Some(eval_cast(self.value(arg)?, t)),
+            _ => None,
+        }
+    }
+
+    fn aexpr(&mut self, a: AExpr) -> AExpr {
+        match a {
+            AExpr::Let(var, c, body) => {
+                let (mut stmts, c) = self.cexpr(c);
+                if let CExpr::Fail(..) = c {
+                    return wrap(stmts, AExpr::Value(c));
+                }
+                if !self.assigned.contains(&var) {
+                    if let Some(Ok(k)) = self.eval(&c) {
+                        self.known.insert(var.clone(), k);
+                        return wrap(stmts, self.aexpr(*body));
+                    }
+                    if let CExpr::Imm(imm @ (Imm::Input | Imm::Var(_))) = &c {
+                        // Functions may assign to globals behind our back
+                        let unchanging = match imm {
+                            Imm::Var(Var::Global(_)) => false,
+                            Imm::Var(copied) => !self.assigned.contains(copied),
+                            _ => true,
+                        };
+                        if unchanging {
+                            self.copies.insert(var, imm.clone());
+                            return wrap(stmts, self.aexpr(*body));
+                        }
+                    }
+                }
+                stmts.push(Stmt::Let(var, c));
+                wrap(stmts, self.aexpr(*body))
+            }
+            AExpr::Seq(c, body) => {
+                let (mut stmts, c) = self.cexpr(c);
+                match c {
+                    CExpr::Fail(..) => return wrap(stmts, AExpr::Value(c)),
+                    // Nothing to evaluate for effect
+                    CExpr::Imm(_) => {}
+                    _ => stmts.push(Stmt::Do(c)),
+                }
+                wrap(stmts, self.aexpr(*body))
+            }
+            AExpr::Value(c) => {
+                let (stmts, c) = self.cexpr(c);
+                wrap(stmts, AExpr::Value(c))
+            }
+        }
+    }
+
+    /// Folded `c`, plus the steps of an inlined `if` branch to run first
+    fn cexpr(&mut self, c: CExpr) -> (Vec<Stmt>, CExpr) {
+        let c = match c {
+            CExpr::Imm(imm) => CExpr::Imm(self.imm(imm)),
+            CExpr::Prim1(op, arg, span) => CExpr::Prim1(op, self.imm(arg), span),
+            CExpr::Prim2(op, left, right, span) => CExpr::Prim2(op, self.imm(left), self.imm(right), span),
+            CExpr::Cast(arg, t, span) => CExpr::Cast(self.imm(arg), t, span),
+            CExpr::Call(name, args) => CExpr::Call(name, args.into_iter().map(|arg| self.imm(arg)).collect()),
+            CExpr::Tuple(elements, span) => CExpr::Tuple(elements.into_iter().map(|arg| self.imm(arg)).collect(), span),
+            CExpr::TupleSet(tuple, index, value, span) => {
+                CExpr::TupleSet(self.imm(tuple), self.imm(index), self.imm(value), span)
+            }
+            CExpr::Set(var, value) => CExpr::Set(var, self.imm(value)),
+            CExpr::Break(label, value) => CExpr::Break(label, self.imm(value)),
+            CExpr::If(cond, then_branch, else_branch) => match self.value(&cond) {
+                Some(k) => {
+                    let taken = if k == Const::Bool(false) { else_branch } else { then_branch };
+                    return flatten(self.aexpr(*taken));
+                }
+                None => CExpr::If(cond, Box::new(self.aexpr(*then_branch)), Box::new(self.aexpr(*else_branch))),
+            },
+            CExpr::Loop(label, body) => CExpr::Loop(label, Box::new(self.aexpr(*body))),
+            CExpr::Continue | CExpr::Fail(..) => c,
+        };
+
+        let span = match &c {
+            CExpr::Prim1(_, _, span) | CExpr::Prim2(_, _, _, span) | CExpr::Cast(_, _, span) => *span,
+            _ => return (vec![], c),
+        };
+        match self.eval(&c) {
+            Some(Ok(k)) => (vec![], CExpr::Imm(to_imm(k))),
+            Some(Err(failure)) => (vec![], CExpr::Fail(failure, span)),
+            None => (vec![], c),
+        }
+    }
+}
+
+/// Fold constants in one routine's body
+pub fn fold_constants(body: AExpr) -> AExpr {
+    let mut assigned = HashSet::new();
+    assigned_vars(&body, &mut assigned);
+    Folder { assigned, known: HashMap::new(), copies: HashMap::new() }.aexpr(body)
+}
+
+/// Optimize one routine's body; `params` are its parameters, if any
+pub fn optimize(body: AExpr, params: &[Var], fun_ctx: &FunContext, level: OptLevel) -> AExpr {
+    match level {
+        OptLevel::O0 => body,
+        OptLevel::O1 => fold_constants(body),
+        // Fold again to propagate constant arguments through inlined bodies
+        OptLevel::O2 => fold_constants(inline_calls(fold_constants(body), params, fun_ctx)),
+    }
+}
+
+pub fn optimize_program(program: Program, fun_ctx: &FunContext, level: OptLevel) -> Program {
+    Program {
+        functions: program
+            .functions
+            .into_iter()
+            .map(|mut function| {
+                function.body = optimize(function.body, &function.params, fun_ctx, level);
+                function
+            })
+            .collect(),
+        main: optimize(program.main, &[], fun_ctx, level),
+    }
+}
@@ -1,123 +1,228 @@
 // src/parser.rs
-use sexp::*;
-use sexp::Atom::*;
+use crate::reader::Sexp;
+use crate::reader::Atom::*;
 use crate::ast::*;
+use crate::span::{Span, render_error};
 use im::HashMap;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    Syntax,
+    Keyword,
+    Arity,
+    InvalidBinding,
+    DuplicateBinding,
+    InvalidType,
+    InvalidDefinition,
+    UnknownOperation,
+    Unsupported,
+    OutOfRange,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Span, message: impl Into<String>) -> Self {
+        ParseError { kind, span, message: message.into() }
+    }
+
+    /// `file:line:col: error: message` plus the source line with a caret
+    pub fn render(&self, file: &str, source: &str) -> String {
+        render_error(file, source, self.span, &self.message)
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn err<T>(kind: ParseErrorKind, span: Span, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError::new(kind, span, message))
+}
+
 pub fn is_keyword(s: &str) -> bool {
     matches!(s, 
         "let" | "add1" | "sub1" | "isnum" | "isbool" | 
-        "+" | "-" | "*" | "<" | ">" | ">=" | "<=" | "=" |
-        "if" | "block" | "loop" | "break" | "set!" | 
+        "and" | "or" | "not" |
+        "+" | "-" | "*" | "/" | "%" | "quotient" | "remainder" |
+        "bitand" | "bitor" | "bitxor" | "shl" | "shr" |
+        "<" | ">" | ">=" | "<=" | "=" |
+        "if" | "cond" | "else" | "when" | "unless" |
+        "block" | "loop" | "break" | "set!" |
+        "while" | "for" | "continue" |
+        "tuple" | "index" | "tuple-set!" |
         "true" | "false" | "input" | "define" | "fun" | "print"
     )
 }
 
-pub fn parse_type(s: &Sexp) -> Type {
+/// Atoms the reader couldn't read as a `0x` or `0b` literal, like `0x` or
+/// `0b102`
+fn is_bad_radix_literal(s: &str) -> bool {
+    let unsigned = s.strip_prefix(['-', '+']).unwrap_or(s);
+    ["0x", "0X", "0b", "0B"].iter().any(|prefix| unsigned.starts_with(prefix))
+}
+
+pub fn parse_type(s: &Sexp) -> Result<Type, ParseError> {
     match s {
-        Sexp::Atom(S(t)) => match t.as_str() {
-            "Num" => Type::Num,
-            "Bool" => Type::Bool,
-            "Any" => Type::Any,
-            "Nothing" => Type::Nothing,
-            _ => panic!("Invalid type: {}", t),
+        Sexp::Atom(S(t), span) => match t.as_str() {
+            "Num" => Ok(Type::Num),
+            "Bool" => Ok(Type::Bool),
+            "Any" => Ok(Type::Any),
+            "Nothing" => Ok(Type::Nothing),
+            "Tuple" => Ok(Type::Tuple),
+            _ => err(ParseErrorKind::InvalidType, *span, format!("Invalid type: {}", t)),
         },
-        _ => panic!("Invalid type"),
+        _ => err(ParseErrorKind::InvalidType, s.span(), "Invalid type"),
     }
 }
 
-pub fn parse_bind(s: &Sexp) -> (String, Expr) {
+pub fn parse_bind(s: &Sexp) -> Result<Binding, ParseError> {
     match s {
-        Sexp::List(vec) => {
+        Sexp::List(vec, span) => {
             if vec.len() != 2 {
-                panic!("Invalid binding");
+                return err(ParseErrorKind::InvalidBinding, *span, "Invalid binding");
             }
             let name = match &vec[0] {
-                Sexp::Atom(S(s)) => {
+                Sexp::Atom(S(s), name_span) => {
                     if is_keyword(s) {
-                        panic!("keyword");
+                        return err(ParseErrorKind::Keyword, *name_span, "keyword");
                     }
                     s.clone()
                 },
-                _ => panic!("Invalid binding: expected identifier"),
+                other => return err(ParseErrorKind::InvalidBinding, other.span(), "Invalid binding: expected identifier"),
             };
-            let expr = parse_expr(&vec[1]);
-            (name, expr)
+            let expr = parse_expr(&vec[1])?;
+            Ok(Binding { name, expr, span: *span })
         }
-        _ => panic!("Invalid binding: expected list"),
+        _ => err(ParseErrorKind::InvalidBinding, s.span(), "Invalid binding: expected list"),
     }
 }
 
-pub fn parse_expr(s: &Sexp) -> Expr {
+/// A leading `:name` loop label, and the arguments after it
+fn split_label(args: &[Sexp]) -> (Option<String>, &[Sexp]) {
+    match args.split_first() {
+        Some((Sexp::Atom(S(s), _), rest)) if s.len() > 1 && s.starts_with(':') => (Some(s[1..].to_string()), rest),
+        _ => (None, args),
+    }
+}
+
+/// Body of a `cond` clause, `when` or `unless`: one expression, or several
+/// run in sequence as an implicit block
+fn parse_body(form: &str, exprs: &[Sexp], span: Span) -> Result<Expr, ParseError> {
+    match exprs {
+        [] => err(ParseErrorKind::Arity, span, format!("Invalid: {} requires at least one expression", form)),
+        [e] => parse_expr(e),
+        _ => {
+            let exprs = exprs.iter().map(parse_expr).collect::<Result<Vec<_>, _>>()?;
+            Ok(Expr::new(ExprKind::Block(exprs), span))
+        }
+    }
+}
+
+pub fn parse_expr(s: &Sexp) -> Result<Expr, ParseError> {
     match s {
-        Sexp::Atom(I(n)) => {
-            let n_i32 = i32::try_from(*n).unwrap();
-            Expr::Number(n_i32)
+        Sexp::Atom(I(n), span) => {
+            if !(MIN_NUM..=MAX_NUM).contains(n) {
+                return err(ParseErrorKind::OutOfRange, *span, "number out of range");
+            }
+            Ok(Expr::new(ExprKind::Number(*n), *span))
         }
-        Sexp::Atom(F(_)) => {
-            panic!("floats not supported yet :)")
+        Sexp::Atom(F(_), span) => {
+            err(ParseErrorKind::Unsupported, *span, "floats not supported yet :)")
         }
-        Sexp::Atom(S(name)) => {
+        Sexp::Atom(S(name), span) => {
             // reserved words
             match name.as_str() {
-                "true" => Expr::Boolean(true),
-                "false" => Expr::Boolean(false),
-                "input" => Expr::Input,
+                "true" => Ok(Expr::new(ExprKind::Boolean(true), *span)),
+                "false" => Ok(Expr::new(ExprKind::Boolean(false), *span)),
+                "input" => Ok(Expr::new(ExprKind::Input, *span)),
                 keyword if is_keyword(keyword) => {
-                    panic!("keyword");
+                    err(ParseErrorKind::Keyword, *span, "keyword")
                 }
-                _ => Expr::Id(name.to_string()),
+                literal if is_bad_radix_literal(literal) => {
+                    err(ParseErrorKind::Syntax, *span, "invalid number literal")
+                }
+                _ => Ok(Expr::new(ExprKind::Id(name.to_string()), *span)),
             }
         }
-        Sexp::List(vec) => {
+        Sexp::List(vec, span) => {
+            let span = *span;
             if vec.is_empty() {
-                panic!("empty expr");
+                return err(ParseErrorKind::Syntax, span, "Invalid: empty expr");
             }
             
             match &vec[0] {
-                Sexp::Atom(S(op)) => match op.as_str() {
+                Sexp::Atom(S(op), _) => match op.as_str() {
                     "add1" | "sub1" => {
                         if vec.len() != 2 {
-                            panic!("Invalid: {} takes exactly one argument", op);
+                            return err(ParseErrorKind::Arity, span, format!("Invalid: {} takes exactly one argument", op));
                         }
                         let op_enum = match op.as_str() {
                             "add1" => Op1::Add1,
                             "sub1" => Op1::Sub1,
                             _ => unreachable!(),
                         };
-                        Expr::UnOp(op_enum, Box::new(parse_expr(&vec[1])))
+                        Ok(Expr::new(ExprKind::UnOp(op_enum, Box::new(parse_expr(&vec[1])?)), span))
                     }
                     "isnum" => {
                         if vec.len() != 2 {
-                            panic!("Invalid: isnum takes exactly one argument");
+                            return err(ParseErrorKind::Arity, span, "Invalid: isnum takes exactly one argument");
                         }
-                        Expr::UnOp(Op1::IsNum, Box::new(parse_expr(&vec[1])))
+                        Ok(Expr::new(ExprKind::UnOp(Op1::IsNum, Box::new(parse_expr(&vec[1])?)), span))
                     }
                     "isbool" => {
                         if vec.len() != 2 {
-                            panic!("Invalid: isbool takes exactly one argument");
+                            return err(ParseErrorKind::Arity, span, "Invalid: isbool takes exactly one argument");
+                        }
+                        Ok(Expr::new(ExprKind::UnOp(Op1::IsBool, Box::new(parse_expr(&vec[1])?)), span))
+                    }
+                    "not" => {
+                        if vec.len() != 2 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: not takes exactly one argument");
+                        }
+                        Ok(Expr::new(ExprKind::UnOp(Op1::Not, Box::new(parse_expr(&vec[1])?)), span))
+                    }
+                    "and" | "or" => {
+                        let mut exprs = Vec::new();
+                        for expr_sexp in &vec[1..] {
+                            exprs.push(parse_expr(expr_sexp)?);
                         }
-                        Expr::UnOp(Op1::IsBool, Box::new(parse_expr(&vec[1])))
+                        let kind = if op == "and" { ExprKind::And(exprs) } else { ExprKind::Or(exprs) };
+                        Ok(Expr::new(kind, span))
                     }
-                    "+" | "-" | "*" => {
+                    "+" | "-" | "*" | "/" | "%" | "quotient" | "remainder" | "bitand" | "bitor" | "bitxor" | "shl" | "shr" => {
                         if vec.len() != 3 {
-                            panic!("{} takes exactly two arguments", op);
+                            return err(ParseErrorKind::Arity, span, format!("Invalid: {} takes exactly two arguments", op));
                         }
                         let op_enum = match op.as_str() {
                             "+" => Op2::Plus,
                             "-" => Op2::Minus,
                             "*" => Op2::Times,
+                            "/" | "quotient" => Op2::Divide,
+                            "%" | "remainder" => Op2::Remainder,
+                            "bitand" => Op2::BitAnd,
+                            "bitor" => Op2::BitOr,
+                            "bitxor" => Op2::BitXor,
+                            "shl" => Op2::Shl,
+                            "shr" => Op2::Shr,
                             _ => unreachable!(),
                         };
-                        Expr::BinOp(
+                        Ok(Expr::new(ExprKind::BinOp(
                             op_enum,
-                            Box::new(parse_expr(&vec[1])),
-                            Box::new(parse_expr(&vec[2])),
-                        )
+                            Box::new(parse_expr(&vec[1])?),
+                            Box::new(parse_expr(&vec[2])?),
+                        ), span))
                     }
                     "<" | ">" | ">=" | "<=" | "=" => {
                         if vec.len() != 3 {
-                            panic!("Invalid: {} takes exactly two arguments", op);
+                            return err(ParseErrorKind::Arity, span, format!("Invalid: {} takes exactly two arguments", op));
                         }
                         let op_enum = match op.as_str() {
                             "<" => Op2::Less,
@@ -127,19 +232,19 @@ pub fn parse_expr(s: &Sexp) -> Expr {
                             "=" => Op2::Equal,
                             _ => unreachable!(),
                         };
-                        Expr::BinOp(
+                        Ok(Expr::new(ExprKind::BinOp(
                             op_enum,
-                            Box::new(parse_expr(&vec[1])),
-                            Box::new(parse_expr(&vec[2])),
-                        )
+                            Box::new(parse_expr(&vec[1])?),
+                            Box::new(parse_expr(&vec[2])?),
+                        ), span))
                     }
                     "let" => {
                         if vec.len() != 3 {
-                            panic!("let takes exactly two arguments");
+                            return err(ParseErrorKind::Arity, span, "Invalid: let takes exactly two arguments");
                         }
                         let bindings_list = match &vec[1] {
-                            Sexp::List(list) => list,
-                            _ => panic!("let bindings must be a list"),
+                            Sexp::List(list, _) => list,
+                            other => return err(ParseErrorKind::InvalidBinding, other.span(), "Invalid: let bindings must be a list"),
                         };
                         
                         let mut bindings = Vec::new();
@@ -148,161 +253,255 @@ pub fn parse_expr(s: &Sexp) -> Expr {
                         if bindings_list.len() == 2 {
                             // Could be either (let (x 1) body) or (let ((x 1)) body)
                             // Check if first element is an atom (single binding) or list (multiple bindings)
-                            if let Sexp::Atom(S(_)) = &bindings_list[0] {
+                            if let Sexp::Atom(S(_), _) = &bindings_list[0] {
                                 // Single binding without extra parens: (let (x 1) body)
-                                bindings.push(parse_bind(&vec[1]));
+                                bindings.push(parse_bind(&vec[1])?);
                             } else {
                                 // Multiple bindings: (let ((x 1) (y 2)) body)
                                 for binding_sexp in bindings_list {
-                                    bindings.push(parse_bind(binding_sexp));
+                                    bindings.push(parse_bind(binding_sexp)?);
                                 }
                             }
                         } else {
                             // Multiple bindings: (let ((x 1) (y 2) (z 3)) body)
                             for binding_sexp in bindings_list {
-                                bindings.push(parse_bind(binding_sexp));
+                                bindings.push(parse_bind(binding_sexp)?);
                             }
                         }
                         
                         if bindings.is_empty() {
-                            panic!("let requires at least one binding");
+                            return err(ParseErrorKind::InvalidBinding, vec[1].span(), "Invalid: let requires at least one binding");
                         }
-                        Expr::Let(bindings, Box::new(parse_expr(&vec[2])))
+                        Ok(Expr::new(ExprKind::Let(bindings, Box::new(parse_expr(&vec[2])?)), span))
                     }                   
                     "if" => { 
                             if vec.len() != 4 {
-                                panic!("Invalid: if takes exactly three arguments");
+                                return err(ParseErrorKind::Arity, span, "Invalid: if takes exactly three arguments");
                             }
-                            Expr::If(
-                                Box::new(parse_expr(&vec[1])),
-                                Box::new(parse_expr(&vec[2])),
-                                Box::new(parse_expr(&vec[3])),
-                            )
+                            Ok(Expr::new(ExprKind::If(
+                                Box::new(parse_expr(&vec[1])?),
+                                Box::new(parse_expr(&vec[2])?),
+                                Box::new(parse_expr(&vec[3])?),
+                            ), span))
+                    }
+                    "cond" => {
+                        let Some((last, clauses)) = vec[1..].split_last() else {
+                            return err(ParseErrorKind::Arity, span, "Invalid: cond requires an else clause");
+                        };
+                        let mut result = match last {
+                            Sexp::List(parts, clause_span) if matches!(parts.first(), Some(Sexp::Atom(S(e), _)) if e == "else") => {
+                                parse_body("else", &parts[1..], *clause_span)?
+                            }
+                            other => return err(ParseErrorKind::Syntax, other.span(), "Invalid: cond must end with an else clause"),
+                        };
+                        // Fold from the last clause so each test guards the ones after it
+                        for clause in clauses.iter().rev() {
+                            let (test, body, clause_span) = match clause {
+                                Sexp::List(parts, clause_span) if !parts.is_empty() => (&parts[0], &parts[1..], *clause_span),
+                                other => return err(ParseErrorKind::Syntax, other.span(), "Invalid: cond clause must be a list (test body ...)"),
+                            };
+                            if matches!(test, Sexp::Atom(S(e), _) if e == "else") {
+                                return err(ParseErrorKind::Syntax, clause_span, "Invalid: else must be the last cond clause");
+                            }
+                            result = Expr::new(ExprKind::If(
+                                Box::new(parse_expr(test)?),
+                                Box::new(parse_body("cond clause", body, clause_span)?),
+                                Box::new(result),
+                            ), clause_span);
+                        }
+                        Ok(result)
+                    }
+                    "when" | "unless" => {
+                        if vec.len() < 3 {
+                            return err(ParseErrorKind::Arity, span, format!("Invalid: {} requires a test and at least one expression", op));
+                        }
+                        let test = parse_expr(&vec[1])?;
+                        let body = parse_body(op, &vec[2..], span)?;
+                        // Not taking the body yields false
+                        let skip = Expr::new(ExprKind::Boolean(false), span);
+                        let (then_e, else_e) = if op == "when" { (body, skip) } else { (skip, body) };
+                        Ok(Expr::new(ExprKind::If(Box::new(test), Box::new(then_e), Box::new(else_e)), span))
                     }
                     "block" => {
                         if vec.len() < 2 {
-                            panic!("Invalid: block requires at least one expression");
+                            return err(ParseErrorKind::Arity, span, "Invalid: block requires at least one expression");
                         }
                         let mut exprs = Vec::new();
                         for expr_sexp in &vec[1..] {
-                            exprs.push(parse_expr(expr_sexp));
+                            exprs.push(parse_expr(expr_sexp)?);
                         }
-                        Expr::Block(exprs)
+                        Ok(Expr::new(ExprKind::Block(exprs), span))
                     }
                     "set!" => {
                         if vec.len() != 3 {
-                            panic!("Invalid: set! requires exactly two arguments");
+                            return err(ParseErrorKind::Arity, span, "Invalid: set! requires exactly two arguments");
                         }
                         let name = match &vec[1] {
-                            Sexp::Atom(S(s)) => {
+                            Sexp::Atom(S(s), name_span) => {
                                 if is_keyword(s) {
-                                    panic!("keyword");
+                                    return err(ParseErrorKind::Keyword, *name_span, "keyword");
                                 }
                                 s.clone()
                             }
-                            _ => panic!("Invalid: first argument to set! must be an identifier"),
+                            other => return err(ParseErrorKind::InvalidBinding, other.span(), "Invalid: first argument to set! must be an identifier"),
                         };
-                        let value_expr = parse_expr(&vec[2]);
-                        Expr::Set(name, Box::new(value_expr))
+                        let value_expr = parse_expr(&vec[2])?;
+                        Ok(Expr::new(ExprKind::Set(name, Box::new(value_expr)), span))
                     }
                     "loop" => {
-                        if vec.len() != 2 {
-                            panic!("Invalid: loop requires exactly one argument");
+                        let (label, args) = split_label(&vec[1..]);
+                        if args.len() != 1 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: loop requires exactly one argument");
                         }
-                        Expr::Loop(Box::new(parse_expr(&vec[1])))
+                        Ok(Expr::new(ExprKind::Loop(label, Box::new(parse_expr(&args[0])?)), span))
                     }
                     
+                    "while" => {
+                        if vec.len() != 3 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: while takes exactly two arguments");
+                        }
+                        Ok(Expr::new(ExprKind::While(
+                            Box::new(parse_expr(&vec[1])?),
+                            Box::new(parse_expr(&vec[2])?),
+                        ), span))
+                    }
+                    "for" => {
+                        if vec.len() != 3 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: for takes exactly two arguments");
+                        }
+                        let (name, lo, hi) = match &vec[1] {
+                            Sexp::List(range, _) if range.len() == 3 => match &range[0] {
+                                Sexp::Atom(S(name), name_span) => {
+                                    if is_keyword(name) {
+                                        return err(ParseErrorKind::Keyword, *name_span, "keyword");
+                                    }
+                                    (name.clone(), &range[1], &range[2])
+                                }
+                                other => return err(ParseErrorKind::InvalidBinding, other.span(), "Invalid binding: expected identifier"),
+                            },
+                            other => return err(ParseErrorKind::InvalidBinding, other.span(), "Invalid: for range must be (name lo hi)"),
+                        };
+                        Ok(Expr::new(ExprKind::For(
+                            name,
+                            Box::new(parse_expr(lo)?),
+                            Box::new(parse_expr(hi)?),
+                            Box::new(parse_expr(&vec[2])?),
+                        ), span))
+                    }
+                    "continue" => {
+                        if vec.len() != 1 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: continue takes no arguments");
+                        }
+                        Ok(Expr::new(ExprKind::Continue, span))
+                    }
                     "break" => {
-                        if vec.len() != 2 {
-                            panic!("Invalid: break requires exactly one argument");
+                        let (label, args) = split_label(&vec[1..]);
+                        if args.len() != 1 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: break requires exactly one argument");
                         }
-                        Expr::Break(Box::new(parse_expr(&vec[1])))
+                        Ok(Expr::new(ExprKind::Break(label, Box::new(parse_expr(&args[0])?)), span))
                     }
 
                     "print" => {
                         if vec.len() != 2 {
-                            panic!("Invalid: print takes exactly one argument");
+                            return err(ParseErrorKind::Arity, span, "Invalid: print takes exactly one argument");
+                        }
+                        Ok(Expr::new(ExprKind::UnOp(Op1::Print, Box::new(parse_expr(&vec[1])?)), span))
+                    }
+                    "tuple" => {
+                        let mut exprs = Vec::new();
+                        for expr_sexp in &vec[1..] {
+                            exprs.push(parse_expr(expr_sexp)?);
+                        }
+                        Ok(Expr::new(ExprKind::Tuple(exprs), span))
+                    }
+                    "index" => {
+                        if vec.len() != 3 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: index takes exactly two arguments");
                         }
-                        Expr::UnOp(Op1::Print, Box::new(parse_expr(&vec[1])))
+                        Ok(Expr::new(ExprKind::BinOp(
+                            Op2::Index,
+                            Box::new(parse_expr(&vec[1])?),
+                            Box::new(parse_expr(&vec[2])?),
+                        ), span))
+                    }
+                    "tuple-set!" => {
+                        if vec.len() != 4 {
+                            return err(ParseErrorKind::Arity, span, "Invalid: tuple-set! takes exactly three arguments");
+                        }
+                        Ok(Expr::new(ExprKind::TupleSet(
+                            Box::new(parse_expr(&vec[1])?),
+                            Box::new(parse_expr(&vec[2])?),
+                            Box::new(parse_expr(&vec[3])?),
+                        ), span))
                     }
                     "cast" => {
                         if vec.len() != 3 {
-                            panic!("Invalid: cast takes exactly two arguments");
+                            return err(ParseErrorKind::Arity, span, "Invalid: cast takes exactly two arguments");
                         }
-                        let typ = parse_type(&vec[1]);
-                        let expr = parse_expr(&vec[2]);
-                        Expr::Cast(Box::new(expr), typ)
+                        let typ = parse_type(&vec[1])?;
+                        let expr = parse_expr(&vec[2])?;
+                        Ok(Expr::new(ExprKind::Cast(Box::new(expr), typ), span))
                     }
-                    // At the end of parse_expr's Sexp::List match, before the final _ => panic!
-                    _ => {
-                        // Try to parse as function call
-                        if let Sexp::Atom(S(name)) = &vec[0] {
-                            if is_keyword(name) {
-                                panic!("unknown operation {}", name);
-                            }
-                            // It's a function call
-                            let mut args = Vec::new();
-                            for arg in &vec[1..] {
-                                args.push(parse_expr(arg));
-                            }
-                            return Expr::Call(name.clone(), args);
-                        } 
-                        else {
-                            panic!("expected operation or function name");
+                    name => {
+                        // Anything else is a function call
+                        if is_keyword(name) {
+                            return err(ParseErrorKind::UnknownOperation, vec[0].span(), format!("Invalid: unknown operation {}", name));
                         }
+                        let mut args = Vec::new();
+                        for arg in &vec[1..] {
+                            args.push(parse_expr(arg)?);
+                        }
+                        Ok(Expr::new(ExprKind::Call(name.to_string(), args), span))
                     }
-                    // _ => panic!("unknown operation {}", op),
                 },
-                _ => panic!("expected operation"),
+                other => err(ParseErrorKind::UnknownOperation, other.span(), "Invalid: expected operation"),
             }
         }
     }
 }
-pub fn parse_repl_entry(s: &Sexp, depth: usize) -> Result<ReplEntry, String> {
-    match s {
-        Sexp::List(vec) if !vec.is_empty() => {
-            if let Sexp::Atom(S(op)) = &vec[0] {
-                match op.as_str() {
-                    "define" => {
-                        if depth > 0 {
-                            return Err("Invalid".to_string());
-                        }
-                        if vec.len() != 3 {
-                            return Err("Invalid: define takes exactly two arguments".to_string());
-                        }
-                        let name = match &vec[1] {
-                            Sexp::Atom(S(s)) => s.clone(),
-                            _ => return Err("Invalid: define name must be identifier".to_string()),
-                        };
-                        let expr = parse_expr(&vec[2]);
-                        return Ok(ReplEntry::Define(name, Box::new(expr)));
+pub fn parse_repl_entry(s: &Sexp, depth: usize) -> Result<ReplEntry, ParseError> {
+    if let Sexp::List(vec, span) = s {
+        if let Some(Sexp::Atom(S(op), _)) = vec.first() {
+            match op.as_str() {
+                "define" => {
+                    if depth > 0 {
+                        return err(ParseErrorKind::InvalidDefinition, *span, "Invalid");
                     }
-                    "fun" => {
-                        if depth > 0 {
-                            return Err("Invalid".to_string());
-                        }
-                        // Just parse it as a FunDefn and wrap it
-                        let defn = parse_defn(s);
-                        return Ok(ReplEntry::FunDefn(defn));
+                    if vec.len() != 3 {
+                        return err(ParseErrorKind::Arity, *span, "Invalid: define takes exactly two arguments");
                     }
-                    _ => {} // Not a special form, fall through to expression
+                    let name = match &vec[1] {
+                        Sexp::Atom(S(s), _) => s.clone(),
+                        other => return err(ParseErrorKind::InvalidDefinition, other.span(), "Invalid: define name must be identifier"),
+                    };
+                    let expr = parse_expr(&vec[2])?;
+                    return Ok(ReplEntry::Define(name, Box::new(expr)));
+                }
+                "fun" => {
+                    if depth > 0 {
+                        return err(ParseErrorKind::InvalidDefinition, *span, "Invalid");
+                    }
+                    // Just parse it as a FunDefn and wrap it
+                    let defn = parse_defn(s)?;
+                    return Ok(ReplEntry::FunDefn(defn));
                 }
+                _ => {} // Not a special form, fall through to expression
             }
         }
-        _ => {}
     }
     
     // If we get here, it's a regular expression
-    Ok(ReplEntry::Expr(parse_expr(s)))
+    Ok(ReplEntry::Expr(parse_expr(s)?))
 }
 
 
 // diamondback stuff
 
-pub fn parse_program(s: &Sexp) -> Program {
+pub fn parse_program(s: &Sexp) -> Result<Program, ParseError> {
     let list = match s {
-        Sexp::List(vec) => vec,
-        _ => panic!("Program must be a list of definitions and expression"),
+        Sexp::List(vec, _) => vec,
+        _ => return err(ParseErrorKind::Syntax, s.span(), "Invalid: program must be a list of definitions and expression"),
     };
     
     let mut defns = Vec::new();
@@ -311,133 +510,117 @@ pub fn parse_program(s: &Sexp) -> Program {
     for (i, item) in list.iter().enumerate() {
         if i == list.len() - 1 {
             // Last item is the main expression
-            main_expr = Some(parse_expr(item));
+            main_expr = Some(parse_expr(item)?);
         } else {
             // Everything else should be a function definition
-            defns.push(parse_defn(item));
+            defns.push(parse_defn(item)?);
         }
     }
     
-    if main_expr.is_none() {
-        panic!("Program must have at least one expression");
-    }
-    
-    Program {
-        defns,
-        main: main_expr.unwrap(),
+    match main_expr {
+        Some(main) => Ok(Program { defns, main }),
+        None => err(ParseErrorKind::Syntax, s.span(), "Invalid: program must have at least one expression"),
     }
 }
 
-fn parse_defn(s: &Sexp) -> FunDefn {
-    match s {
-        Sexp::List(vec) => {
-            if vec.len() != 3 && vec.len() != 5 {
-                panic!("Invalid function definition");
+fn parse_param(param: &Sexp, seen: &mut HashMap<String, ()>) -> Result<(String, Option<Type>), ParseError> {
+    let (name, name_span, typ) = match param {
+        Sexp::List(p_vec, span) if p_vec.len() == 3 => {
+            match (&p_vec[0], &p_vec[1], &p_vec[2]) {
+                (Sexp::Atom(S(p), name_span), Sexp::Atom(S(colon), colon_span), typ) => {
+                    if colon != ":" {
+                        return err(ParseErrorKind::InvalidDefinition, *colon_span, "Invalid: expected ':'");
+                    }
+                    (p, *name_span, Some(parse_type(typ)?))
+                }
+                _ => return err(ParseErrorKind::InvalidDefinition, *span, "Invalid parameter annotation"),
             }
-            
-            // Check first element is "fun"
-            match &vec[0] {
-                Sexp::Atom(S(op)) if op == "fun" => {}
-                _ => panic!("Expected 'fun'"),
+        }
+        Sexp::Atom(S(p), name_span) => (p, *name_span, None),
+        other => return err(ParseErrorKind::InvalidDefinition, other.span(), "Invalid: parameter must be identifier"),
+    };
+    if is_keyword(name) {
+        return err(ParseErrorKind::Keyword, name_span, "keyword");
+    }
+    if seen.contains_key(name) {
+        return err(ParseErrorKind::DuplicateBinding, name_span, "Duplicate binding");
+    }
+    seen.insert(name.clone(), ());
+    Ok((name.clone(), typ))
+}
+
+fn parse_defn(s: &Sexp) -> Result<FunDefn, ParseError> {
+    let (vec, span) = match s {
+        Sexp::List(vec, span) => (vec, *span),
+        _ => return err(ParseErrorKind::InvalidDefinition, s.span(), "Invalid: function definition must be a list"),
+    };
+    if vec.len() != 3 && vec.len() != 5 {
+        return err(ParseErrorKind::InvalidDefinition, span, "Invalid function definition");
+    }
+    
+    // Check first element is "fun"
+    match &vec[0] {
+        Sexp::Atom(S(op), _) if op == "fun" => {}
+        other => return err(ParseErrorKind::InvalidDefinition, other.span(), "Invalid: expected 'fun'"),
+    }
+    
+    // Parse (name param1 param2 ...)
+    let (name, params, param_types) = match &vec[1] {
+        Sexp::List(sig, sig_span) => {
+            if sig.is_empty() {
+                return err(ParseErrorKind::InvalidDefinition, *sig_span, "Invalid: function signature cannot be empty");
             }
+            let name = match &sig[0] {
+                Sexp::Atom(S(n), _) => n.clone(),
+                other => return err(ParseErrorKind::InvalidDefinition, other.span(), "Invalid: function name must be identifier"),
+            };
             
-            // Parse (name param1 param2 ...)
-            let (name, params, param_types) = match &vec[1] {
-                Sexp::List(sig) => {
-                    if sig.is_empty() {
-                        panic!("Function signature cannot be empty");
-                    }
-                    let name = match &sig[0] {
-                        Sexp::Atom(S(n)) => n.clone(),
-                        _ => panic!("Function name must be identifier"),
-                    };
-                    
-                    let mut params = Vec::new();
-                    let mut seen = HashMap::new();
-                    let mut param_types = Vec::new();
-                    let mut has_types = false;
+            let mut params = Vec::new();
+            let mut seen = HashMap::new();
+            let mut param_types = Vec::new();
+            let mut has_types = false;
 
-                    for param in &sig[1..] {
-                        match param {
-                            Sexp::List(p_vec) if p_vec.len() == 3 => {
-                                if let (Sexp::Atom(S(p)), Sexp::Atom(S(colon)), typ) = 
-                                    (&p_vec[0], &p_vec[1], &p_vec[2]) {
-                                    if colon != ":" {
-                                        panic!("Expected ':'");
-                                    }
-                                    if is_keyword(p) {
-                                        panic!("keyword");
-                                    }
-                                    if seen.contains_key(p) {
-                                        panic!("Duplicate binding");
-                                    }
-                                    seen = seen.update(p.clone(), ());
-                                    params.push(p.clone());
-                                    param_types.push(parse_type(typ));
-                                    has_types = true;
-                                } else {
-                                    panic!("Invalid parameter annotation");
-                                }
-                            }
-                            Sexp::Atom(S(p)) => {
-                                if is_keyword(p) {
-                                    panic!("keyword");
-                                }
-                                if seen.contains_key(p) {
-                                    panic!("Duplicate binding");
-                                }
-                                seen = seen.update(p.clone(), ());
-                                params.push(p.clone());
-                            }
-                            _ => panic!("Parameter must be identifier"),
-                        }
-                    }
-                    let types = if has_types { Some(param_types) } else { None };
-                    (name, params, types)
+            for param in &sig[1..] {
+                let (p, typ) = parse_param(param, &mut seen)?;
+                params.push(p);
+                if let Some(t) = typ {
+                    param_types.push(t);
+                    has_types = true;
                 }
-                _ => panic!("Invalid function signature"),
-            };
+            }
+            let types = if has_types { Some(param_types) } else { None };
+            (name, params, types)
+        }
+        other => return err(ParseErrorKind::InvalidDefinition, other.span(), "Invalid function signature"),
+    };
 
-            let (body_sexp, return_type) = if vec.len() == 5 {
-                // Flat syntax: (fun (name params...) -> Type body)
-                if let Sexp::Atom(S(arrow)) = &vec[2] {
-                    if arrow == "->" {
-                        (&vec[4], Some(parse_type(&vec[3])))
-                    } else {
-                        panic!("Expected '->' in function definition");
-                    }
-                } else {
-                    panic!("Expected '->' in function definition");
-                }
-            } else {
-                // Old nested syntax or no annotation: (fun (name params...) body)
-                match &vec[2] {
-                    Sexp::List(body_list) if body_list.len() == 3 => {
-                        if let (Sexp::Atom(S(arrow)), typ, body) = 
-                            (&body_list[0], &body_list[1], &body_list[2]) {
-                            if arrow == "->" {
-                                (body, Some(parse_type(typ)))
-                            } else {
-                                (&vec[2], None)
-                            }
-                        } else {
-                            (&vec[2], None)
-                        }
-                    }
-                    _ => (&vec[2], None)
+    let (body_sexp, return_type) = if vec.len() == 5 {
+        // Flat syntax: (fun (name params...) -> Type body)
+        match &vec[2] {
+            Sexp::Atom(S(arrow), _) if arrow == "->" => (&vec[4], Some(parse_type(&vec[3])?)),
+            other => return err(ParseErrorKind::InvalidDefinition, other.span(), "Invalid: expected '->' in function definition"),
+        }
+    } else {
+        // Old nested syntax or no annotation: (fun (name params...) body)
+        match &vec[2] {
+            Sexp::List(body_list, _) if body_list.len() == 3 => {
+                match (&body_list[0], &body_list[1], &body_list[2]) {
+                    (Sexp::Atom(S(arrow), _), typ, body) if arrow == "->" => (body, Some(parse_type(typ)?)),
+                    _ => (&vec[2], None),
                 }
-            };
-            
-            let body = parse_expr(body_sexp);
-            
-            FunDefn {
-                name,
-                params,
-                body: Box::new(body),
-                param_types,
-                return_type,
             }
+            _ => (&vec[2], None)
         }
-        _ => panic!("Function definition must be a list"),
-    }
-}
\ No newline at end of file
+    };
+    
+    let body = parse_expr(body_sexp)?;
+    
+    Ok(FunDefn {
+        name,
+        params,
+        body: Box::new(body),
+        param_types,
+        return_type,
+        span,
+    })
+}
@@ -0,0 +1,144 @@
+// src/peephole.rs
+// Peephole optimization: rewrite short windows of adjacent instructions in
+// a finished routine, after register allocation, so both the assembly text
+// and the machine code backends get the shorter sequence.
+use crate::instr::{jump_target, Instr, Reg, Val};
+
+fn same(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::Reg(x), Val::Reg(y)) => x == y,
+        (Val::Imm(x), Val::Imm(y)) => x == y,
+        (Val::Imm64(x), Val::Imm64(y)) => x == y,
+        (Val::RegOffset(r, x), Val::RegOffset(s, y)) => r == s && x == y,
+        _ => false,
+    }
+}
+
+fn mentions(val: &Val, reg: Reg) -> bool {
+    matches!(val, Val::Reg(r) | Val::RegOffset(r, _) if *r == reg)
+}
+
+fn reads_flags(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::IJe(_)
+            | Instr::IJne(_)
+            | Instr::IJl(_)
+            | Instr::IJle(_)
+            | Instr::IJg(_)
+            | Instr::IJge(_)
+            | Instr::IJz(_)
+            | Instr::IJo(_)
+            | Instr::ICMovE(..)
+            | Instr::ICMovNE(..)
+            | Instr::ICMovG(..)
+            | Instr::ICMovGE(..)
+            | Instr::ICMovL(..)
+            | Instr::ICMovLE(..)
+    )
+}
+
+fn writes_flags(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::IAdd(..)
+            | Instr::ISub(..)
+            | Instr::IMul(..)
+            | Instr::ICmp(..)
+            | Instr::ITest(..)
+            | Instr::IOr(..)
+            | Instr::IAnd(..)
+            | Instr::IXor(..)
+            | Instr::ISar(..)
+            | Instr::IShl(..)
+            | Instr::IIdiv(_)
+    )
+}
+
+/// Whether anything after `code[i]` may look at the flags it sets. Control
+/// flow other than `ret` could lead anywhere, so it counts as a reader.
+fn flags_live_after(code: &[Instr], i: usize) -> bool {
+    for instr in &code[i + 1..] {
+        if reads_flags(instr) {
+            return true;
+        }
+        if writes_flags(instr) || matches!(instr, Instr::IRet) {
+            return false;
+        }
+        if matches!(instr, Instr::ILabel(_) | Instr::IJmp(_) | Instr::ICall(_)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// One pass over `code`, or None if no window matched
+fn rewrite(code: &[Instr]) -> Option<Vec<Instr>> {
+    let mut out: Vec<Instr> = Vec::with_capacity(code.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        // How many instructions the window starting at i covered
+        let consumed = match (&code[i], code.get(i + 1)) {
+            // mov x, x
+            (Instr::IMov(dest, src), _) if same(dest, src) => 1,
+            // mov a, b; mov b, a  =>  mov a, b
+            (Instr::IMov(a, b), Some(Instr::IMov(c, d))) if same(a, d) && same(b, c) => {
+                out.push(code[i].clone());
+                2
+            }
+            // mov r, x; mov r, y  =>  mov r, y, unless y reads r
+            (Instr::IMov(Val::Reg(r), _), Some(Instr::IMov(Val::Reg(s), src))) if r == s && !mentions(src, *r) => 1,
+            // mov r, n; add r, m  =>  mov r, n+m, if nothing checks the add's flags
+            (Instr::IMov(Val::Reg(r), Val::Imm(n)), Some(Instr::IAdd(Val::Reg(s), Val::Imm(m))))
+                if r == s && n.checked_add(*m).is_some() && !flags_live_after(code, i + 1) =>
+            {
+                out.push(Instr::IMov(Val::Reg(*r), Val::Imm(n + m)));
+                2
+            }
+            // add r, n; add r, m  =>  add r, n+m, under the same condition
+            (Instr::IAdd(Val::Reg(r), Val::Imm(n)), Some(Instr::IAdd(Val::Reg(s), Val::Imm(m))))
+                if r == s && n.checked_add(*m).is_some() && !flags_live_after(code, i + 1) =>
+            {
+                out.push(Instr::IAdd(Val::Reg(*r), Val::Imm(n + m)));
+                2
+            }
+            // A jump to a label that immediately follows it
+            (instr, _)
+                if jump_target(instr).is_some_and(|target| {
+                    code[i + 1..]
+                        .iter()
+                        .take_while(|instr| matches!(instr, Instr::ILabel(_)))
+                        .any(|instr| matches!(instr, Instr::ILabel(label) if label == target))
+                }) =>
+            {
+                1
+            }
+            // Nothing falls through a jmp or ret: drop everything up to the
+            // next label
+            (Instr::IJmp(_) | Instr::IRet, Some(next)) if !matches!(next, Instr::ILabel(_)) => {
+                out.push(code[i].clone());
+                1 + code[i + 1..].iter().take_while(|instr| !matches!(instr, Instr::ILabel(_))).count()
+            }
+            (instr, _) => {
+                out.push(instr.clone());
+                i += 1;
+                continue;
+            }
+        };
+        changed = true;
+        i += consumed;
+    }
+    changed.then_some(out)
+}
+
+/// Rewrite `code` until no window can be improved. Returns the new code and
+/// how many instructions that saved.
+pub fn peephole(mut code: Vec<Instr>) -> (Vec<Instr>, usize) {
+    let before = code.len();
+    while let Some(shorter) = rewrite(&code) {
+        code = shorter;
+    }
+    let saved = before - code.len();
+    (code, saved)
+}
@@ -0,0 +1,192 @@
+// src/reader.rs
+// S-expression reader that keeps track of where every atom and list came
+// from, so later passes can point back into the .snek text.
+use crate::parser::{ParseError, ParseErrorKind};
+use crate::span::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    S(String),
+    I(i64),
+    F(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(Atom, Span),
+    List(Vec<Sexp>, Span),
+}
+
+impl Sexp {
+    pub fn span(&self) -> Span {
+        match self {
+            Sexp::Atom(_, span) | Sexp::List(_, span) => *span,
+        }
+    }
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    pos: usize,
+    // Start of the outermost list still open, which is the one an
+    // unexpected end of input reports
+    outermost_open: Option<usize>,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.as_bytes().get(self.pos).copied()
+    }
+
+    /// Skips whitespace and `;` line comments
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else if c == b';' {
+                while !matches!(self.peek(), None | Some(b'\n')) {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn read_sexp(&mut self) -> Result<Sexp, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.peek() {
+            None => Err(ParseError::new(
+                ParseErrorKind::Syntax,
+                Span::new(start, start),
+                "Invalid: unexpected end of input",
+            )),
+            Some(b')') => Err(ParseError::new(
+                ParseErrorKind::Syntax,
+                Span::new(start, start + 1),
+                "Invalid: unexpected ')'",
+            )),
+            Some(b'(') => {
+                self.pos += 1;
+                let outermost = *self.outermost_open.get_or_insert(start);
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        None => {
+                            return Err(ParseError::new(
+                                ParseErrorKind::Syntax,
+                                Span::new(outermost, outermost + 1),
+                                "Invalid: unclosed '('",
+                            ))
+                        }
+                        Some(b')') => {
+                            self.pos += 1;
+                            if outermost == start {
+                                self.outermost_open = None;
+                            }
+                            return Ok(Sexp::List(items, Span::new(start, self.pos)));
+                        }
+                        Some(_) => items.push(self.read_sexp()?),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_whitespace() || c == b'(' || c == b')' || c == b';' {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let text = &self.src[start..self.pos];
+                Ok(Sexp::Atom(parse_atom(text), Span::new(start, self.pos)))
+            }
+        }
+    }
+}
+
+/// An integer literal: an optional sign, then decimal digits, `0x` hex or
+/// `0b` binary, with `_` allowed after the first digit. Values too big for
+/// an i64 saturate, so the parser reports them as out of range.
+fn parse_int(text: &str) -> Option<i64> {
+    let (negative, rest) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (radix, digits) = if let Some(digits) = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+        (16, digits)
+    } else if let Some(digits) = rest.strip_prefix("0b").or_else(|| rest.strip_prefix("0B")) {
+        (2, digits)
+    } else {
+        (10, rest)
+    };
+    if !digits.starts_with(|c: char| c.is_digit(radix)) || !digits.chars().all(|c| c == '_' || c.is_digit(radix)) {
+        return None;
+    }
+    let saturated = if negative { i64::MIN } else { i64::MAX };
+    let mut n: i64 = 0;
+    for digit in digits.chars().filter_map(|c| c.to_digit(radix)) {
+        let digit = i64::from(digit);
+        // Accumulate with the sign applied so i64::MIN is reachable
+        let next = n.checked_mul(i64::from(radix)).and_then(|n| if negative { n.checked_sub(digit) } else { n.checked_add(digit) });
+        match next {
+            Some(next) => n = next,
+            None => return Some(saturated),
+        }
+    }
+    Some(n)
+}
+
+fn parse_atom(text: &str) -> Atom {
+    if let Some(n) = parse_int(text) {
+        return Atom::I(n);
+    }
+    let numeric = text
+        .trim_start_matches(['-', '+'])
+        .starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    if numeric {
+        if let Ok(f) = text.parse::<f64>() {
+            return Atom::F(f);
+        }
+    }
+    Atom::S(text.to_string())
+}
+
+/// Reads exactly one s-expression from `src`.
+pub fn read(src: &str) -> Result<Sexp, ParseError> {
+    let mut reader = Reader { src, pos: 0, outermost_open: None };
+    let sexp = reader.read_sexp()?;
+    reader.skip_whitespace();
+    if reader.pos < src.len() {
+        let start = reader.pos;
+        let trailing = reader.read_sexp().map(|s| s.span()).unwrap_or(Span::new(start, start + 1));
+        return Err(ParseError::new(
+            ParseErrorKind::Syntax,
+            trailing,
+            "Invalid: unexpected input after expression",
+        ));
+    }
+    Ok(sexp)
+}
+
+/// Reads a whole .snek file. Files that are already a single top-level list
+/// of forms (starting with `((` and ending with `))`) are read as-is, any
+/// other file has its forms collected into one list.
+pub fn read_program(src: &str) -> Result<Sexp, ParseError> {
+    let trimmed = src.trim();
+    if trimmed.starts_with("((") && trimmed.ends_with("))") {
+        return read(src);
+    }
+
+    let mut reader = Reader { src, pos: 0, outermost_open: None };
+    let mut forms = Vec::new();
+    loop {
+        reader.skip_whitespace();
+        if reader.peek().is_none() {
+            break;
+        }
+        forms.push(reader.read_sexp()?);
+    }
+    Ok(Sexp::List(forms, Span::new(0, src.len())))
+}
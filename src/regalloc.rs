@@ -0,0 +1,208 @@
+// src/regalloc.rs
+// Linear-scan register allocation. Code generation gives every local and
+// temporary a stack slot `[rbp - n]`; this pass treats the slots of one
+// compiled routine as virtual registers, moves as many as fit into
+// callee-saved registers, and leaves the rest in memory.
+use std::collections::HashMap;
+
+use crate::instr::{jump_target, Instr, Reg, Val};
+
+/// Registers slots can be assigned to. They are callee-saved, so values
+/// survive calls to other functions and into the runtime; each routine saves
+/// the ones it uses in its own frame.
+pub const ALLOCATABLE: [Reg; 4] = [Reg::RBX, Reg::R12, Reg::R13, Reg::R14];
+
+/// Instructions from the first to the last mention of a slot
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    slot: i32,
+    start: usize,
+    end: usize,
+}
+
+fn operands(instr: &Instr) -> Vec<&Val> {
+    match instr {
+        Instr::IMov(a, b)
+        | Instr::IAdd(a, b)
+        | Instr::ISub(a, b)
+        | Instr::IMul(a, b)
+        | Instr::ICmp(a, b)
+        | Instr::ITest(a, b)
+        | Instr::ICMovE(a, b)
+        | Instr::ICMovNE(a, b)
+        | Instr::ICMovG(a, b)
+        | Instr::ICMovGE(a, b)
+        | Instr::ICMovL(a, b)
+        | Instr::ICMovLE(a, b)
+        | Instr::IOr(a, b)
+        | Instr::IAnd(a, b)
+        | Instr::IXor(a, b)
+        | Instr::ISar(a, b)
+        | Instr::IShl(a, b) => vec![a, b],
+        Instr::IPush(v) | Instr::IPop(v) | Instr::IIdiv(v) => vec![v],
+        _ => vec![],
+    }
+}
+
+fn operands_mut(instr: &mut Instr) -> Vec<&mut Val> {
+    match instr {
+        Instr::IMov(a, b)
+        | Instr::IAdd(a, b)
+        | Instr::ISub(a, b)
+        | Instr::IMul(a, b)
+        | Instr::ICmp(a, b)
+        | Instr::ITest(a, b)
+        | Instr::ICMovE(a, b)
+        | Instr::ICMovNE(a, b)
+        | Instr::ICMovG(a, b)
+        | Instr::ICMovGE(a, b)
+        | Instr::ICMovL(a, b)
+        | Instr::ICMovLE(a, b)
+        | Instr::IOr(a, b)
+        | Instr::IAnd(a, b)
+        | Instr::IXor(a, b)
+        | Instr::ISar(a, b)
+        | Instr::IShl(a, b) => vec![a, b],
+        Instr::IPush(v) | Instr::IPop(v) | Instr::IIdiv(v) => vec![v],
+        _ => vec![],
+    }
+}
+
+/// Locals and temporaries; parameters above the frame stay where they are
+fn slot_of(val: &Val) -> Option<i32> {
+    match val {
+        Val::RegOffset(Reg::RBP, offset) if *offset < 0 => Some(*offset),
+        _ => None,
+    }
+}
+
+fn live_intervals(code: &[Instr]) -> Vec<Interval> {
+    let mut by_slot: HashMap<i32, Interval> = HashMap::new();
+    for (i, instr) in code.iter().enumerate() {
+        for slot in operands(instr).into_iter().filter_map(slot_of) {
+            by_slot
+                .entry(slot)
+                .and_modify(|interval| interval.end = i)
+                .or_insert(Interval { slot, start: i, end: i });
+        }
+    }
+
+    // A value live anywhere in a loop may be needed on the next trip round,
+    // so it has to survive the whole loop. Repeat until nested loops settle.
+    let labels: HashMap<&str, usize> = code
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instr)| match instr {
+            Instr::ILabel(label) => Some((label.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+    let loops: Vec<(usize, usize)> = code
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instr)| {
+            let target = *labels.get(jump_target(instr)?)?;
+            (target < i).then_some((target, i))
+        })
+        .collect();
+
+    let mut intervals: Vec<Interval> = by_slot.into_values().collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for interval in intervals.iter_mut() {
+            for &(head, back_edge) in &loops {
+                let overlaps = interval.start <= back_edge && interval.end >= head;
+                if overlaps && (interval.start > head || interval.end < back_edge) {
+                    interval.start = interval.start.min(head);
+                    interval.end = interval.end.max(back_edge);
+                    changed = true;
+                }
+            }
+        }
+    }
+    intervals
+}
+
+/// Classic linear scan: when every register is taken, whichever interval
+/// ends last stays on the stack
+fn linear_scan(mut intervals: Vec<Interval>) -> HashMap<i32, Reg> {
+    intervals.sort_by_key(|interval| (interval.start, interval.slot));
+    let mut assigned = HashMap::new();
+    let mut active: Vec<(Interval, Reg)> = Vec::new();
+    let mut free: Vec<Reg> = ALLOCATABLE.iter().rev().copied().collect();
+
+    for interval in intervals {
+        active.retain(|(other, reg)| {
+            let expired = other.end < interval.start;
+            if expired {
+                free.push(*reg);
+            }
+            !expired
+        });
+
+        if let Some(reg) = free.pop() {
+            assigned.insert(interval.slot, reg);
+            active.push((interval, reg));
+            continue;
+        }
+
+        let (i, &(furthest, reg)) = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (other, _))| other.end)
+            .expect("no free register means some are active");
+        if furthest.end > interval.end {
+            assigned.remove(&furthest.slot);
+            assigned.insert(interval.slot, reg);
+            active[i] = (interval, reg);
+        }
+    }
+    assigned
+}
+
+/// Allocate registers for one routine: `push rbp; mov rbp, rsp;
+/// sub rsp, N` followed by code that leaves only through `mov rsp, rbp`.
+/// Registers it takes are saved below the routine's own slots and restored
+/// before every exit.
+pub fn allocate_registers(mut code: Vec<Instr>) -> Vec<Instr> {
+    let frame_size = match code.get(2) {
+        Some(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(n))) => *n,
+        _ => return code,
+    };
+
+    let assigned = linear_scan(live_intervals(&code));
+    if assigned.is_empty() {
+        return code;
+    }
+    for instr in code.iter_mut() {
+        for val in operands_mut(instr) {
+            if let Some(reg) = slot_of(val).and_then(|slot| assigned.get(&slot)) {
+                *val = Val::Reg(*reg);
+            }
+        }
+    }
+
+    let used: Vec<Reg> = ALLOCATABLE.iter().copied().filter(|reg| assigned.values().any(|r| r == reg)).collect();
+    let saves: Vec<(Reg, Val)> = used
+        .iter()
+        .enumerate()
+        .map(|(i, reg)| (*reg, Val::RegOffset(Reg::RBP, -frame_size - 8 * (i as i32 + 1))))
+        .collect();
+    let saved_size = frame_size + 8 * used.len() as i32;
+    code[2] = Instr::ISub(Val::Reg(Reg::RSP), Val::Imm((saved_size + 15) / 16 * 16));
+
+    let mut body = code.split_off(3);
+    for (reg, slot) in &saves {
+        code.push(Instr::IMov(slot.clone(), Val::Reg(*reg)));
+    }
+    for instr in body.drain(..) {
+        if let Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)) = instr {
+            for (reg, slot) in &saves {
+                code.push(Instr::IMov(Val::Reg(*reg), slot.clone()));
+            }
+        }
+        code.push(instr);
+    }
+    code
+}
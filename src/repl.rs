@@ -5,52 +5,52 @@ use std::mem;
 use dynasmrt::*;
 use std::collections::HashMap as StdHashMap;
 use crate::ast::*;
-use crate::helpers::REPL;
+use crate::helpers::{format_value, REPL};
 use crate::parser::*;
 use crate::jit::*;
-use crate::compiler::{FunContext, get_input_heap_offset};
+use crate::compiler::{FunContext, CompilerContext, compile_function, compile_main, get_input_heap_offset, HEAP_WORDS};
+use crate::optimize::OptLevel;
+use crate::helpers::FALSE_VAL;
 use crate::typechecker::*;
 use std::sync::atomic::Ordering;
 
 fn print_result(val: i64) {
-    if val & 1 == 0 {
-        println!("{}", val >> 1);
-    } 
-    else if val == 1 {
-        println!("true");
-    } 
-    else if val == 3 {
-        println!("false");
-    } 
-    else {
-        println!("Unknown value: {}", val);
-    }
+    println!("{}", format_value(val));
 }
 
-pub fn run_repl(typecheck: bool) -> io::Result<()> {
+pub fn run_repl(typecheck: bool, opt_level: OptLevel) -> io::Result<()> {
     REPL.store(true, Ordering::SeqCst);
     let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    let mut ctx = CompilerContext::with_registered_sites();
+    ctx.opt_level = opt_level;
     let mut defines: HashMap<String, i32> = HashMap::new();
     let mut define_types: HashMap<String, Type> = HashMap::new(); 
     let mut functions: Vec<FunDefn> = Vec::new();
     let mut label_map: StdHashMap<String, dynasmrt::DynamicLabel> = StdHashMap::new();
     
     // Allocate heap once at the start
-    let heap: Vec<i64> = vec![0; 128 * 1024];
-    let heap_ptr = heap.as_ptr() as i64;
+    let mut heap: Vec<i64> = vec![0; HEAP_WORDS];
+    let heap_ptr = heap.as_mut_ptr();
+    let unwind_slot = ctx.alloc_heap_slot();
     
     // Pre-create error handler labels
     let snek_print = ops.new_dynamic_label();
     let error_overflow = ops.new_dynamic_label();
     let error_invalid_arg = ops.new_dynamic_label();
     let error_bad_cast = ops.new_dynamic_label();
+    let error_divide_by_zero = ops.new_dynamic_label();
+    let error_index_out_of_bounds = ops.new_dynamic_label();
+    let error_out_of_memory = ops.new_dynamic_label();
     label_map.insert("snek_print".to_string(), snek_print);
     label_map.insert("error_overflow".to_string(), error_overflow);
     label_map.insert("error_invalid_argument".to_string(), error_invalid_arg);
     label_map.insert("error_bad_cast".to_string(), error_bad_cast);
+    label_map.insert("error_divide_by_zero".to_string(), error_divide_by_zero);
+    label_map.insert("error_index_out_of_bounds".to_string(), error_index_out_of_bounds);
+    label_map.insert("error_out_of_memory".to_string(), error_out_of_memory);
     
     // Compile error handlers once at the start using shared function
-    compile_error_handlers(&mut ops, &label_map);
+    compile_error_handlers(&mut ops, &label_map, unwind_slot);
     
     ops.commit().unwrap();
     
@@ -75,29 +75,25 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
             continue;
         }
         
-        // Parse the input
-        let sexp = match sexp::parse(input) {
-            Ok(s) => s,
-            Err(_e) => {
-                println!("Invalid: parse error");
+        // Parse the input into a ReplEntry
+        let entry = match crate::reader::read(input).and_then(|sexp| parse_repl_entry(&sexp, 0)) {
+            Ok(e) => e,
+            Err(e) => {
+                println!("{}", e.render("<repl>", input));
                 continue;
             }
         };
-        
-        // Parse into ReplEntry - catch panics from parser
-        let entry = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            parse_repl_entry(&sexp, 0)
-        })) {
-            Ok(Ok(e)) => e,
-            Ok(Err(msg)) => {
-                println!("{}", msg);
-                continue;
-            }
-            Err(_) => {
-                println!("Invalid");
-                continue;
-            }
+
+        // Typechecked or not, breaks must name a loop around them
+        let loops_checked = match &entry {
+            ReplEntry::FunDefn(defn) => check_loops(&defn.body, &mut Vec::new()),
+            ReplEntry::Fun(_, _, body, _, _) | ReplEntry::Expr(body) => check_loops(body, &mut Vec::new()),
+            ReplEntry::Define(_, body) => check_loops(body, &mut Vec::new()),
         };
+        if let Err(e) = loops_checked {
+            println!("{}", e.render("<repl>", input));
+            continue;
+        }
         
         match entry {
             ReplEntry::FunDefn(defn) => {
@@ -142,35 +138,10 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                 let fun_label = ops.new_dynamic_label();
                 label_map.insert(format!("fun_{}", defn.name), fun_label);
                 
-                // Compile function prologue
-                dynasm!(ops
-                    ; .arch x64
-                    ; =>fun_label
-                    ; push rbp
-                    ; mov rbp, rsp
-                );
-                
-                // Build environment: parameters are on caller's stack at [rbp+16], [rbp+24], etc.
-                let mut env = HashMap::new();
-                for (i, param) in defn.params.iter().enumerate() {
-                    let offset = 16 + (i as i32 * 8);
-                    env = env.update(param.clone(), offset);
-                }
-                
-                // Compile function body
-                let (instrs, min_offset) = match std::panic::catch_unwind(
-                    std::panic::AssertUnwindSafe(|| {
-                        crate::compiler::compile_to_instrs(
-                            &defn.body,
-                            -8,
-                            &env,
-                            &defines,
-                            &fun_ctx,
-                            false,
-                            &None,
-                        )
-                    })
-                ) {
+                // Compile the whole function, frame included
+                let instrs = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    compile_function(&mut ctx, &defn, &defines, &fun_ctx)
+                })) {
                     Ok(result) => result,
                     Err(_) => {
                         println!("Invalid");
@@ -180,46 +151,9 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                     }
                 };
                 
-                // Allocate stack space if needed
-                if min_offset < 0 {
-                    let needed = -min_offset;
-                    let stack_space = ((needed + 15) / 16) * 16;
-                    dynasm!(ops
-                        ; .arch x64
-                        ; sub rsp, stack_space as i32
-                    );
-                }
+                emit_instrs(&instrs, &mut ops, &mut label_map);
                 
-                // Pre-create labels for function body
-                for instr in &instrs {
-                    if let crate::instr::Instr::ILabel(label_name) = instr {
-                        if !label_map.contains_key(label_name) {
-                            label_map.insert(label_name.clone(), ops.new_dynamic_label());
-                        }
-                    }
-                    match instr {
-                        crate::instr::Instr::IJmp(l) | crate::instr::Instr::IJe(l) | 
-                        crate::instr::Instr::IJne(l) | crate::instr::Instr::IJo(l) => {
-                            if !label_map.contains_key(l) {
-                                label_map.insert(l.clone(), ops.new_dynamic_label());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                
-                // Emit function body instructions
-                for instr in &instrs {
-                    instr_to_dynasm(instr, &mut ops, &label_map);
-                }
-                
-                // Function epilogue
-                dynasm!(ops
-                    ; .arch x64
-                    ; mov rsp, rbp
-                    ; pop rbp
-                    ; ret
-                );
+                register_error_sites(emit_error_site_stubs(&mut ctx, &mut ops, &mut label_map, "<repl>", input));
                 
                 match ops.commit() {
                     Ok(_) => {
@@ -235,12 +169,14 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
             }
             ReplEntry::Fun(name, params, body, param_types, return_type) => {
                 // Convert to FunDefn and process
+                let span = body.span;
                 let defn = FunDefn {
                     name: name.clone(),
                     params: params.clone(),
                     body: Box::new(body),
                     param_types,
                     return_type,
+                    span,
                 };
                 
                 // Check for duplicate function definition
@@ -283,32 +219,9 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                 let fun_label = ops.new_dynamic_label();
                 label_map.insert(format!("fun_{}", name), fun_label);
                 
-                dynasm!(ops
-                    ; .arch x64
-                    ; =>fun_label
-                    ; push rbp
-                    ; mov rbp, rsp
-                );
-                
-                let mut env = HashMap::new();
-                for (i, param) in params.iter().enumerate() {
-                    let offset = 16 + (i as i32 * 8);
-                    env = env.update(param.clone(), offset);
-                }
-                
-                let (instrs, min_offset) = match std::panic::catch_unwind(
-                    std::panic::AssertUnwindSafe(|| {
-                        crate::compiler::compile_to_instrs(
-                            &defn.body,
-                            -8,
-                            &env,
-                            &defines,
-                            &fun_ctx,
-                            false,
-                            &None,
-                        )
-                    })
-                ) {
+                let instrs = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    compile_function(&mut ctx, &defn, &defines, &fun_ctx)
+                })) {
                     Ok(result) => result,
                     Err(_) => {
                         println!("Invalid");
@@ -318,42 +231,9 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                     }
                 };
                 
-                if min_offset < 0 {
-                    let needed = -min_offset;
-                    let stack_space = ((needed + 15) / 16) * 16;
-                    dynasm!(ops
-                        ; .arch x64
-                        ; sub rsp, stack_space as i32
-                    );
-                }
-                
-                for instr in &instrs {
-                    if let crate::instr::Instr::ILabel(label_name) = instr {
-                        if !label_map.contains_key(label_name) {
-                            label_map.insert(label_name.clone(), ops.new_dynamic_label());
-                        }
-                    }
-                    match instr {
-                        crate::instr::Instr::IJmp(l) | crate::instr::Instr::IJe(l) | 
-                        crate::instr::Instr::IJne(l) | crate::instr::Instr::IJo(l) => {
-                            if !label_map.contains_key(l) {
-                                label_map.insert(l.clone(), ops.new_dynamic_label());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                
-                for instr in &instrs {
-                    instr_to_dynasm(instr, &mut ops, &label_map);
-                }
+                emit_instrs(&instrs, &mut ops, &mut label_map);
                 
-                dynasm!(ops
-                    ; .arch x64
-                    ; mov rsp, rbp
-                    ; pop rbp
-                    ; ret
-                );
+                register_error_sites(emit_error_site_stubs(&mut ctx, &mut ops, &mut label_map, "<repl>", input));
                 
                 match ops.commit() {
                     Ok(_) => {
@@ -401,7 +281,7 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                 // Compile the define
                 let (heap_offset, instrs) = match std::panic::catch_unwind(
                     std::panic::AssertUnwindSafe(|| {
-                        crate::compiler::compile_define(&name, &expr, &defines, &fun_ctx)
+                        crate::compiler::compile_define(&mut ctx, &expr, &defines, &fun_ctx)
                     })
                 ) {
                     Ok(result) => result,
@@ -411,44 +291,13 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                     }
                 };
                 
-                let start = ops.offset();
-                
-                // Set up heap pointer
-                dynasm!(ops
-                    ; .arch x64
-                    ; push rbp
-                    ; mov rbp, rsp
-                    ; mov r15, QWORD heap_ptr as _
-                );
-                
-                // Pre-create any labels needed
-                for instr in &instrs {
-                    if let crate::instr::Instr::ILabel(label_name) = instr {
-                        if !label_map.contains_key(label_name) {
-                            label_map.insert(label_name.clone(), ops.new_dynamic_label());
-                        }
-                    }
-                    match instr {
-                        crate::instr::Instr::IJmp(l) | crate::instr::Instr::IJe(l) | 
-                        crate::instr::Instr::IJne(l) | crate::instr::Instr::IJo(l) => {
-                            if !label_map.contains_key(l) {
-                                label_map.insert(l.clone(), ops.new_dynamic_label());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                
-                // Emit the instructions
-                for instr in &instrs {
-                    instr_to_dynasm(instr, &mut ops, &label_map);
-                }
+                let body_label = ops.new_dynamic_label();
+                let input_heap_offset = get_input_heap_offset(&mut ctx);
+                let start = emit_entry(&mut ops, unwind_slot, input_heap_offset, body_label);
+                dynasm!(ops; .arch x64; =>body_label);
+                emit_instrs(&instrs, &mut ops, &mut label_map);
                 
-                dynasm!(ops 
-                    ; .arch x64 
-                    ; pop rbp
-                    ; ret
-                );
+                register_error_sites(emit_error_site_stubs(&mut ctx, &mut ops, &mut label_map, "<repl>", input));
                 
                 match ops.commit() {
                     Ok(_) => {}
@@ -460,8 +309,8 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                 
                 let reader = ops.reader();
                 let buf = reader.lock();
-                let jitted_fn: extern "C" fn() -> i32 = unsafe { mem::transmute(buf.ptr(start)) };
-                jitted_fn(); // Execute to store the value
+                let jitted_fn: extern "C" fn(i64, *mut i64) -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
+                jitted_fn(FALSE_VAL, heap_ptr); // Execute to store the value
                 
                 // Store the heap offset
                 defines = defines.update(name.clone(), heap_offset);
@@ -492,34 +341,9 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                     main: expr,
                 };
                 
-                let start = ops.offset();
-                
-                dynasm!(ops
-                    ; .arch x64
-                    ; push rbp
-                    ; mov rbp, rsp
-                    ; mov r15, QWORD heap_ptr as _
-                );
-                
-                let input_heap_offset = get_input_heap_offset();
-                dynasm!(ops
-                    ; .arch x64
-                    ; mov QWORD [r15 + input_heap_offset], 3  // false
-                );
-                
-                let (instrs, min_offset) = match std::panic::catch_unwind(
-                    std::panic::AssertUnwindSafe(|| {
-                        crate::compiler::compile_to_instrs(
-                            &program.main,
-                            -8,
-                            &HashMap::new(),
-                            &defines,
-                            &fun_ctx,
-                            true,
-                            &None,
-                        )
-                    })
-                ) {
+                let instrs = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    compile_main(&mut ctx, &program.main, &defines, &fun_ctx)
+                })) {
                     Ok(result) => result,
                     Err(_) => {
                         println!("Invalid");
@@ -527,42 +351,13 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                     }
                 };
                 
-                if min_offset <= -16 {
-                    let needed = -min_offset - 8;
-                    let stack_space = ((needed + 15) / 16) * 16;
-                    dynasm!(ops
-                        ; .arch x64
-                        ; sub rsp, stack_space as i32
-                    );
-                }
-                
-                for instr in &instrs {
-                    if let crate::instr::Instr::ILabel(label_name) = instr {
-                        if !label_map.contains_key(label_name) {
-                            label_map.insert(label_name.clone(), ops.new_dynamic_label());
-                        }
-                    }
-                    match instr {
-                        crate::instr::Instr::IJmp(l) | crate::instr::Instr::IJe(l) | 
-                        crate::instr::Instr::IJne(l) | crate::instr::Instr::IJo(l) => {
-                            if !label_map.contains_key(l) {
-                                label_map.insert(l.clone(), ops.new_dynamic_label());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                
-                for instr in &instrs {
-                    instr_to_dynasm(instr, &mut ops, &label_map);
-                }
+                let body_label = ops.new_dynamic_label();
+                let input_heap_offset = get_input_heap_offset(&mut ctx);
+                let start = emit_entry(&mut ops, unwind_slot, input_heap_offset, body_label);
+                dynasm!(ops; .arch x64; =>body_label);
+                emit_instrs(&instrs, &mut ops, &mut label_map);
                 
-                dynasm!(ops 
-                    ; .arch x64 
-                    ; mov rsp, rbp
-                    ; pop rbp
-                    ; ret
-                );
+                register_error_sites(emit_error_site_stubs(&mut ctx, &mut ops, &mut label_map, "<repl>", input));
                 
                 match ops.commit() {
                     Ok(_) => {}
@@ -574,12 +369,12 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
                 
                 let reader = ops.reader();
                 let buf = reader.lock();
-                let jitted_fn: extern "C" fn() -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
+                let jitted_fn: extern "C" fn(i64, *mut i64) -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
                 
                 // Clear error flag before execution
                 crate::helpers::HAS_ERROR.store(false, Ordering::SeqCst);
                 
-                let result = jitted_fn();
+                let result = jitted_fn(FALSE_VAL, heap_ptr);
                 
                 // Check if there was an error
                 if let Some(error_msg) = crate::helpers::check_error() {
@@ -597,22 +392,33 @@ pub fn run_repl(typecheck: bool) -> io::Result<()> {
     Ok(())
 }
 
+fn register_error_sites(sites: Vec<(usize, String)>) {
+    for (id, description) in sites {
+        crate::helpers::register_error_site(id, description);
+    }
+}
+
 // Helper function to check if an expression contains input
 fn contains_input(expr: &Expr) -> bool {
-    match expr {
-        Expr::Input => true,
-        Expr::UnOp(_, e) => contains_input(e),
-        Expr::BinOp(_, e1, e2) => contains_input(e1) || contains_input(e2),
-        Expr::If(e1, e2, e3) => contains_input(e1) || contains_input(e2) || contains_input(e3),
-        Expr::Let(bindings, body) => {
-            bindings.iter().any(|(_, e)| contains_input(e)) || contains_input(body)
+    match &expr.kind {
+        ExprKind::Input => true,
+        ExprKind::UnOp(_, e) => contains_input(e),
+        ExprKind::BinOp(_, e1, e2) => contains_input(e1) || contains_input(e2),
+        ExprKind::If(e1, e2, e3) => contains_input(e1) || contains_input(e2) || contains_input(e3),
+        ExprKind::Let(bindings, body) => {
+            bindings.iter().any(|b| contains_input(&b.expr)) || contains_input(body)
+        }
+        ExprKind::Block(exprs) | ExprKind::And(exprs) | ExprKind::Or(exprs) | ExprKind::Tuple(exprs) => {
+            exprs.iter().any(contains_input)
         }
-        Expr::Block(exprs) => exprs.iter().any(|e| contains_input(e)),
-        Expr::Set(_, e) => contains_input(e),
-        Expr::Loop(e) => contains_input(e),
-        Expr::Break(e) => contains_input(e),
-        Expr::Call(_, args) => args.iter().any(|e| contains_input(e)),
-        Expr::Cast(e, _) => contains_input(e),
+        ExprKind::TupleSet(e1, e2, e3) => contains_input(e1) || contains_input(e2) || contains_input(e3),
+        ExprKind::Set(_, e) => contains_input(e),
+        ExprKind::Loop(_, e) => contains_input(e),
+        ExprKind::Break(_, e) => contains_input(e),
+        ExprKind::While(e1, e2) => contains_input(e1) || contains_input(e2),
+        ExprKind::For(_, lo, hi, body) => contains_input(lo) || contains_input(hi) || contains_input(body),
+        ExprKind::Call(_, args) => args.iter().any(contains_input),
+        ExprKind::Cast(e, _) => contains_input(e),
         _ => false,
     }
 }
\ No newline at end of file
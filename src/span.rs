@@ -0,0 +1,63 @@
+// src/span.rs
+
+/// Byte range into the original source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+/// 1-based line and column of a byte offset
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let col = source[line_start..offset].chars().count() + 1;
+    (line, col)
+}
+
+/// Formats a diagnostic as `file:line:col: error: message`, followed by the
+/// offending source line and a caret underline.
+pub fn render_error(file: &str, source: &str, span: Span, message: &str) -> String {
+    let (line, col) = line_col(source, span.start);
+    let line_text = source.lines().nth(line - 1).unwrap_or("");
+
+    // Underline to the end of the span, or the end of the line if it spans several
+    let line_len = line_text.chars().count();
+    let span_len = source
+        .get(span.start..span.end.min(source.len()))
+        .map(|s| s.chars().count())
+        .unwrap_or(0);
+    let width = span_len.min(line_len.saturating_sub(col - 1)).max(1);
+
+    format!(
+        "{}:{}:{}: error: {}\n  {}\n  {}{}",
+        file,
+        line,
+        col,
+        message,
+        line_text,
+        " ".repeat(col - 1),
+        "^".repeat(width)
+    )
+}
+
+/// Short description of the code at `span` for runtime error messages,
+/// e.g. `(+ x y) at fac.snek:4:12`
+pub fn describe_span(file: &str, source: &str, span: Span) -> String {
+    const MAX_LEN: usize = 40;
+    let text = source.get(span.start..span.end).unwrap_or("");
+    let mut snippet = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if snippet.chars().count() > MAX_LEN {
+        snippet = snippet.chars().take(MAX_LEN - 3).collect::<String>() + "...";
+    }
+    let (line, col) = line_col(source, span.start);
+    format!("{} at {}:{}:{}", snippet, file, line, col)
+}
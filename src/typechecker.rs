@@ -1,6 +1,34 @@
 //typechecker.rs
 use im::HashMap;
 use crate::ast::*;
+use crate::span::{Span, render_error};
+
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl TypeError {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        TypeError { span, message: message.into() }
+    }
+
+    /// `file:line:col: error: message` plus the source line with a caret
+    pub fn render(&self, file: &str, source: &str) -> String {
+        render_error(file, source, self.span, &self.message)
+    }
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn err<T>(span: Span, message: impl Into<String>) -> Result<T, TypeError> {
+    Err(TypeError::new(span, message))
+}
 
 impl Type {
     pub fn union(&self, other: &Type) -> Type {
@@ -8,9 +36,8 @@ impl Type {
             (Type::Any, _) | (_, Type::Any) => Type::Any,
             (t1, t2) if t1 == t2 => t1.clone(),
             (Type::Nothing, t) | (t, Type::Nothing) => t.clone(),
-            (Type::Num, Type::Bool) | (Type::Bool, Type::Num) => Type::Any,
-            (Type::Num, Type::Num) => Type::Num,
-            (Type::Bool, Type::Bool) => Type::Bool,
+            // Two different kinds of value
+            _ => Type::Any,
         }
     }
     
@@ -23,7 +50,9 @@ impl Type {
     }
 }
 
-pub fn typecheck_program(program: &Program, input_type: Option<Type>) -> Result<Type, String> {
+pub fn typecheck_program(program: &Program, input_type: Option<Type>) -> Result<Type, TypeError> {
+    check_program_loops(program)?;
+
     // Check all function definitions
     let empty_defines = HashMap::new();
     for defn in &program.defns {
@@ -42,7 +71,7 @@ pub fn typecheck_program(program: &Program, input_type: Option<Type>) -> Result<
 }
 
 // Make these functions public for REPL
-pub fn typecheck_defn(defn: &FunDefn, all_defns: &[FunDefn], define_env: &HashMap<String, Type>) -> Result<(), String> {
+pub fn typecheck_defn(defn: &FunDefn, all_defns: &[FunDefn], define_env: &HashMap<String, Type>) -> Result<(), TypeError> {
 
     let mut env = define_env.clone();
     
@@ -65,7 +94,7 @@ pub fn typecheck_defn(defn: &FunDefn, all_defns: &[FunDefn], define_env: &HashMa
     // Check return type if annotated
     if let Some(ref ret_type) = defn.return_type {
         if !body_type.is_subtype(ret_type) {
-            return Err(format!("Type error: function {} body has type {:?} but declared {:?}", 
+            return err(defn.body.span, format!("Type error: function {} body has type {:?} but declared {:?}", 
                 defn.name, body_type, ret_type));
         }
     }
@@ -75,109 +104,176 @@ pub fn typecheck_defn(defn: &FunDefn, all_defns: &[FunDefn], define_env: &HashMa
     Ok(())
 }
 
-pub fn typecheck_expr(expr: &Expr, env: &HashMap<String, Type>, defns: &[FunDefn]) -> Result<Type, String> {
-    match expr {
-        Expr::Number(_) => Ok(Type::Num),
-        Expr::Boolean(_) => Ok(Type::Bool),
-        Expr::Input => {
+pub fn typecheck_expr(expr: &Expr, env: &HashMap<String, Type>, defns: &[FunDefn]) -> Result<Type, TypeError> {
+    match &expr.kind {
+        ExprKind::Number(_) => Ok(Type::Num),
+        ExprKind::Boolean(_) => Ok(Type::Bool),
+        ExprKind::Input => {
             env.get("input")
                 .cloned()
-                .ok_or_else(|| "Type error: input not in environment".to_string())
+                .ok_or_else(|| TypeError::new(expr.span, "Type error: input not in environment"))
         }
-        Expr::Id(name) => {
+        ExprKind::Id(name) => {
             env.get(name)
                 .cloned()
-                .ok_or_else(|| format!("Type error: unbound variable {}", name))
+                .ok_or_else(|| TypeError::new(expr.span, format!("Type error: unbound variable {}", name)))
         }
-        Expr::UnOp(Op1::Add1 | Op1::Sub1, e) => {
+        ExprKind::UnOp(Op1::Add1 | Op1::Sub1, e) => {
             let t = typecheck_expr(e, env, defns)?;
             if !t.is_subtype(&Type::Num) {
-                return Err(format!("Type error: add1/sub1 requires Num, got {:?}", t));
+                return err(e.span, format!("Type error: add1/sub1 requires Num, got {:?}", t));
             }
             Ok(Type::Num)
         }
-        Expr::UnOp(Op1::IsNum | Op1::IsBool, e) => {
+        ExprKind::UnOp(Op1::Not, e) => {
+            let t = typecheck_expr(e, env, defns)?;
+            if !t.is_subtype(&Type::Bool) {
+                return err(e.span, format!("Type error: not requires Bool, got {:?}", t));
+            }
+            Ok(Type::Bool)
+        }
+        ExprKind::And(exprs) | ExprKind::Or(exprs) => {
+            for e in exprs {
+                let t = typecheck_expr(e, env, defns)?;
+                if !t.is_subtype(&Type::Bool) {
+                    return err(e.span, format!("Type error: and/or requires Bool, got {:?}", t));
+                }
+            }
+            Ok(Type::Bool)
+        }
+        ExprKind::UnOp(Op1::IsNum | Op1::IsBool, e) => {
             typecheck_expr(e, env, defns)?;
             Ok(Type::Bool)
         }
-        Expr::UnOp(Op1::Print, e) => {
+        ExprKind::UnOp(Op1::Print, e) => {
             typecheck_expr(e, env, defns)
         }
-        Expr::BinOp(Op2::Plus | Op2::Minus | Op2::Times, e1, e2) => {
+        ExprKind::BinOp(
+            Op2::Plus
+            | Op2::Minus
+            | Op2::Times
+            | Op2::Divide
+            | Op2::Remainder
+            | Op2::BitAnd
+            | Op2::BitOr
+            | Op2::BitXor
+            | Op2::Shl
+            | Op2::Shr,
+            e1,
+            e2,
+        ) => {
             let t1 = typecheck_expr(e1, env, defns)?;
             let t2 = typecheck_expr(e2, env, defns)?;
             if !t1.is_subtype(&Type::Num) {
-                return Err(format!("Type error: arithmetic requires Num, got {:?}", t1));
+                return err(e1.span, format!("Type error: arithmetic requires Num, got {:?}", t1));
             }
             if !t2.is_subtype(&Type::Num) {
-                return Err(format!("Type error: arithmetic requires Num, got {:?}", t2));
+                return err(e2.span, format!("Type error: arithmetic requires Num, got {:?}", t2));
             }
             Ok(Type::Num)
         }
-        Expr::BinOp(Op2::Less | Op2::Greater | Op2::LessEqual | Op2::GreaterEqual, e1, e2) => {
+        ExprKind::BinOp(Op2::Index, e1, e2) => {
+            check_element(e1, e2, env, defns)?;
+            // Elements can be anything
+            Ok(Type::Any)
+        }
+        ExprKind::Tuple(exprs) => {
+            for e in exprs {
+                typecheck_expr(e, env, defns)?;
+            }
+            Ok(Type::Tuple)
+        }
+        ExprKind::TupleSet(tuple, index, value) => {
+            check_element(tuple, index, env, defns)?;
+            typecheck_expr(value, env, defns)
+        }
+        ExprKind::BinOp(Op2::Less | Op2::Greater | Op2::LessEqual | Op2::GreaterEqual, e1, e2) => {
             let t1 = typecheck_expr(e1, env, defns)?;
             let t2 = typecheck_expr(e2, env, defns)?;
             if !t1.is_subtype(&Type::Num) || !t2.is_subtype(&Type::Num) {
-                return Err("Type error: comparison requires Num".to_string());
+                return err(expr.span, "Type error: comparison requires Num");
             }
             Ok(Type::Bool)
         }
-        Expr::BinOp(Op2::Equal, e1, e2) => {
+        ExprKind::BinOp(Op2::Equal, e1, e2) => {
             let t1 = typecheck_expr(e1, env, defns)?;
             let t2 = typecheck_expr(e2, env, defns)?;
             if (t1.is_subtype(&Type::Num) && t2.is_subtype(&Type::Num)) ||
-               (t1.is_subtype(&Type::Bool) && t2.is_subtype(&Type::Bool)) {
+               (t1.is_subtype(&Type::Bool) && t2.is_subtype(&Type::Bool)) ||
+               (t1.is_subtype(&Type::Tuple) && t2.is_subtype(&Type::Tuple)) {
                 Ok(Type::Bool)
             } else {
-                Err("Type error: = requires both Num or both Bool".to_string())
+                err(expr.span, "Type error: = requires both Num, both Bool or both Tuple")
             }
         }
-        Expr::Let(bindings, body) => {
+        ExprKind::Let(bindings, body) => {
             let mut new_env = env.clone();
-            for (name, e) in bindings {
-                let t = typecheck_expr(e, &new_env, defns)?;
-                new_env = new_env.update(name.clone(), t);
+            for binding in bindings {
+                let t = typecheck_expr(&binding.expr, &new_env, defns)?;
+                new_env = new_env.update(binding.name.clone(), t);
             }
             typecheck_expr(body, &new_env, defns)
         }
-        Expr::If(cond, then_e, else_e) => {
+        ExprKind::If(cond, then_e, else_e) => {
             let cond_t = typecheck_expr(cond, env, defns)?;
             if !cond_t.is_subtype(&Type::Bool) {
-                return Err(format!("Type error: if condition must be Bool, got {:?}", cond_t));
+                return err(cond.span, format!("Type error: condition must be Bool, got {:?}", cond_t));
             }
             let then_t = typecheck_expr(then_e, env, defns)?;
             let else_t = typecheck_expr(else_e, env, defns)?;
             Ok(then_t.union(&else_t))
         }
-        Expr::Block(exprs) => {
+        ExprKind::Block(exprs) => {
             let mut last_type = Type::Any;
             for e in exprs {
                 last_type = typecheck_expr(e, env, defns)?;
             }
             Ok(last_type)
         }
-        Expr::Set(name, e) => {
+        ExprKind::Set(name, e) => {
             let val_type = typecheck_expr(e, env, defns)?;
             let var_type = env.get(name)
-                .ok_or_else(|| format!("Type error: unbound variable {}", name))?;
+                .ok_or_else(|| TypeError::new(expr.span, format!("Type error: unbound variable {}", name)))?;
             if !val_type.is_subtype(var_type) {
-                return Err(format!("Type error: cannot assign {:?} to {:?}", val_type, var_type));
+                return err(e.span, format!("Type error: cannot assign {:?} to {:?}", val_type, var_type));
             }
             Ok(val_type)
         }
-        Expr::Loop(body) => {
-            collect_break_types(body, env, defns)
+        ExprKind::Loop(label, body) => {
+            collect_break_types(body, label.as_deref(), env, defns)
+        }
+        ExprKind::While(cond, body) => {
+            let cond_t = typecheck_expr(cond, env, defns)?;
+            if !cond_t.is_subtype(&Type::Bool) {
+                return err(cond.span, format!("Type error: condition must be Bool, got {:?}", cond_t));
+            }
+            typecheck_expr(body, env, defns)?;
+            // Running out gives false
+            let breaks = collect_break_types(cond, None, env, defns)?.union(&collect_break_types(body, None, env, defns)?);
+            Ok(Type::Bool.union(&breaks))
+        }
+        ExprKind::For(name, lo, hi, body) => {
+            for bound in [lo, hi] {
+                let t = typecheck_expr(bound, env, defns)?;
+                if !t.is_subtype(&Type::Num) {
+                    return err(bound.span, format!("Type error: for range requires Num, got {:?}", t));
+                }
+            }
+            let body_env = env.update(name.clone(), Type::Num);
+            typecheck_expr(body, &body_env, defns)?;
+            Ok(Type::Bool.union(&collect_break_types(body, None, &body_env, defns)?))
         }
-        Expr::Break(e) => {
+        ExprKind::Break(_, e) => {
             typecheck_expr(e, env, defns)?;
             Ok(Type::Nothing)
         }
-        Expr::Call(fname, args) => {
+        ExprKind::Continue => Ok(Type::Nothing),
+        ExprKind::Call(fname, args) => {
             let defn = defns.iter().find(|d| d.name == *fname)
-                .ok_or_else(|| format!("Type error: undefined function {}", fname))?;
+                .ok_or_else(|| TypeError::new(expr.span, format!("Type error: undefined function {}", fname)))?;
             
             if args.len() != defn.params.len() {
-                return Err(format!("Type error: wrong number of arguments"));
+                return err(expr.span, "Type error: wrong number of arguments");
             }
             
             // Check arguments
@@ -185,7 +281,7 @@ pub fn typecheck_expr(expr: &Expr, env: &HashMap<String, Type>, defns: &[FunDefn
                 for (arg, expected_type) in args.iter().zip(param_types.iter()) {
                     let arg_type = typecheck_expr(arg, env, defns)?;
                     if !arg_type.is_subtype(expected_type) {
-                        return Err(format!("Type error: argument has type {:?}, expected {:?}", 
+                        return err(arg.span, format!("Type error: argument has type {:?}, expected {:?}", 
                             arg_type, expected_type));
                     }
                 }
@@ -199,16 +295,31 @@ pub fn typecheck_expr(expr: &Expr, env: &HashMap<String, Type>, defns: &[FunDefn
                 Ok(Type::Any)
             }
         }
-        Expr::Cast(e, target_type) => {
+        ExprKind::Cast(e, target_type) => {
             typecheck_expr(e, env, defns)?;
             Ok(target_type.clone())
         }
     }
 }
 
-fn collect_break_types(expr: &Expr, env: &HashMap<String, Type>, defns: &[FunDefn]) -> Result<Type, String> {
+/// `tuple` must be a Tuple and `index` a Num
+fn check_element(tuple: &Expr, index: &Expr, env: &HashMap<String, Type>, defns: &[FunDefn]) -> Result<(), TypeError> {
+    let t = typecheck_expr(tuple, env, defns)?;
+    if !t.is_subtype(&Type::Tuple) {
+        return err(tuple.span, format!("Type error: index requires Tuple, got {:?}", t));
+    }
+    let t = typecheck_expr(index, env, defns)?;
+    if !t.is_subtype(&Type::Num) {
+        return err(index.span, format!("Type error: index requires Num, got {:?}", t));
+    }
+    Ok(())
+}
+
+/// Union of the types of the breaks in `expr` that leave the loop it is the
+/// body of: unlabeled ones outside nested loops, and any naming `label`
+fn collect_break_types(expr: &Expr, label: Option<&str>, env: &HashMap<String, Type>, defns: &[FunDefn]) -> Result<Type, TypeError> {
     let mut result = Type::Nothing;
-    collect_break_types_helper(expr, env, defns, &mut result, false)?;
+    collect_break_types_helper(expr, env, defns, label, &mut result, false)?;
     Ok(result)
 }
 
@@ -216,52 +327,233 @@ fn collect_break_types_helper(
     expr: &Expr, 
     env: &HashMap<String, Type>, 
     defns: &[FunDefn],
+    label: Option<&str>,
     result: &mut Type,
     in_nested_loop: bool
-) -> Result<(), String> {
-    match expr {
-        Expr::Break(e) if !in_nested_loop => {
+) -> Result<(), TypeError> {
+    match &expr.kind {
+        ExprKind::Break(target, e) if match target {
+            None => !in_nested_loop,
+            Some(target) => label == Some(target.as_str()),
+        } => {
             let t = typecheck_expr(e, env, defns)?;
             *result = result.union(&t);
             Ok(())
         }
-        Expr::Loop(_) => {
-            // Don't recurse into nested loops
-            Ok(())
+        ExprKind::Loop(inner, body) => {
+            // Only breaks naming our label get out of nested loops, and a
+            // loop with the same label hides ours
+            if label.is_none() || inner.as_deref() == label {
+                return Ok(());
+            }
+            collect_break_types_helper(body, env, defns, label, result, true)
         }
-        Expr::UnOp(_, e) => collect_break_types_helper(e, env, defns, result, in_nested_loop),
-        Expr::BinOp(_, e1, e2) => {
-            collect_break_types_helper(e1, env, defns, result, in_nested_loop)?;
-            collect_break_types_helper(e2, env, defns, result, in_nested_loop)
+        ExprKind::While(cond, body) => {
+            if label.is_none() {
+                return Ok(());
+            }
+            collect_break_types_helper(cond, env, defns, label, result, true)?;
+            collect_break_types_helper(body, env, defns, label, result, true)
         }
-        Expr::If(e1, e2, e3) => {
-            collect_break_types_helper(e1, env, defns, result, in_nested_loop)?;
-            collect_break_types_helper(e2, env, defns, result, in_nested_loop)?;
-            collect_break_types_helper(e3, env, defns, result, in_nested_loop)
+        ExprKind::For(name, lo, hi, body) => {
+            // The range is evaluated before the nested loop starts
+            collect_break_types_helper(lo, env, defns, label, result, in_nested_loop)?;
+            collect_break_types_helper(hi, env, defns, label, result, in_nested_loop)?;
+            if label.is_none() {
+                return Ok(());
+            }
+            collect_break_types_helper(body, &env.update(name.clone(), Type::Num), defns, label, result, true)
+        }
+        ExprKind::UnOp(_, e) => collect_break_types_helper(e, env, defns, label, result, in_nested_loop),
+        ExprKind::BinOp(_, e1, e2) => {
+            collect_break_types_helper(e1, env, defns, label, result, in_nested_loop)?;
+            collect_break_types_helper(e2, env, defns, label, result, in_nested_loop)
+        }
+        ExprKind::If(e1, e2, e3) => {
+            collect_break_types_helper(e1, env, defns, label, result, in_nested_loop)?;
+            collect_break_types_helper(e2, env, defns, label, result, in_nested_loop)?;
+            collect_break_types_helper(e3, env, defns, label, result, in_nested_loop)
         }
-        Expr::Let(bindings, body) => {
+        ExprKind::Let(bindings, body) => {
             let mut new_env = env.clone();
-            for (name, e) in bindings {
-                collect_break_types_helper(e, &new_env, defns, result, in_nested_loop)?;
-                let t = typecheck_expr(e, &new_env, defns)?;
-                new_env = new_env.update(name.clone(), t);
+            for binding in bindings {
+                collect_break_types_helper(&binding.expr, &new_env, defns, label, result, in_nested_loop)?;
+                let t = typecheck_expr(&binding.expr, &new_env, defns)?;
+                new_env = new_env.update(binding.name.clone(), t);
             }
-            collect_break_types_helper(body, &new_env, defns, result, in_nested_loop)
+            collect_break_types_helper(body, &new_env, defns, label, result, in_nested_loop)
         }
-        Expr::Block(exprs) => {
+        ExprKind::Block(exprs) | ExprKind::And(exprs) | ExprKind::Or(exprs) | ExprKind::Tuple(exprs) => {
             for e in exprs {
-                collect_break_types_helper(e, env, defns, result, in_nested_loop)?;
+                collect_break_types_helper(e, env, defns, label, result, in_nested_loop)?;
             }
             Ok(())
         }
-        Expr::Set(_, e) => collect_break_types_helper(e, env, defns, result, in_nested_loop),
-        Expr::Call(_, args) => {
+        ExprKind::Set(_, e) => collect_break_types_helper(e, env, defns, label, result, in_nested_loop),
+        ExprKind::Call(_, args) => {
             for arg in args {
-                collect_break_types_helper(arg, env, defns, result, in_nested_loop)?;
+                collect_break_types_helper(arg, env, defns, label, result, in_nested_loop)?;
             }
             Ok(())
         }
-        Expr::Cast(e, _) => collect_break_types_helper(e, env, defns, result, in_nested_loop),
+        ExprKind::Cast(e, _) => collect_break_types_helper(e, env, defns, label, result, in_nested_loop),
+        ExprKind::TupleSet(tuple, index, value) => {
+            collect_break_types_helper(tuple, env, defns, label, result, in_nested_loop)?;
+            collect_break_types_helper(index, env, defns, label, result, in_nested_loop)?;
+            collect_break_types_helper(value, env, defns, label, result, in_nested_loop)
+        }
         _ => Ok(()),
     }
-}
\ No newline at end of file
+}
+/// Every `break` and `continue` is inside a loop, and every label a `break`
+/// names is on a loop around it. This needs no types, so programs compiled
+/// without typechecking are checked too.
+pub fn check_program_loops(program: &Program) -> Result<(), TypeError> {
+    for defn in &program.defns {
+        check_loops(&defn.body, &mut Vec::new())?;
+    }
+    check_loops(&program.main, &mut Vec::new())
+}
+
+/// `loops` holds the labels of the loops around `expr`, innermost last
+pub fn check_loops(expr: &Expr, loops: &mut Vec<Option<String>>) -> Result<(), TypeError> {
+    fn in_loop(label: Option<String>, body: &Expr, loops: &mut Vec<Option<String>>) -> Result<(), TypeError> {
+        loops.push(label);
+        let checked = check_loops(body, loops);
+        loops.pop();
+        checked
+    }
+    match &expr.kind {
+        ExprKind::Break(Some(label), _) if !loops.iter().any(|l| l.as_ref() == Some(label)) => {
+            err(expr.span, format!("Unknown loop label :{}", label))
+        }
+        ExprKind::Break(None, _) if loops.is_empty() => err(expr.span, "break outside of loop"),
+        ExprKind::Continue if loops.is_empty() => err(expr.span, "continue outside of loop"),
+        ExprKind::Break(_, e) | ExprKind::UnOp(_, e) | ExprKind::Set(_, e) | ExprKind::Cast(e, _) => {
+            check_loops(e, loops)
+        }
+        ExprKind::Loop(label, body) => in_loop(label.clone(), body, loops),
+        ExprKind::While(cond, body) => {
+            in_loop(None, cond, loops)?;
+            in_loop(None, body, loops)
+        }
+        ExprKind::For(_, lo, hi, body) => {
+            // The range is evaluated before the loop starts
+            check_loops(lo, loops)?;
+            check_loops(hi, loops)?;
+            in_loop(None, body, loops)
+        }
+        ExprKind::BinOp(_, e1, e2) => {
+            check_loops(e1, loops)?;
+            check_loops(e2, loops)
+        }
+        ExprKind::If(e1, e2, e3) | ExprKind::TupleSet(e1, e2, e3) => {
+            check_loops(e1, loops)?;
+            check_loops(e2, loops)?;
+            check_loops(e3, loops)
+        }
+        ExprKind::Let(bindings, body) => {
+            for binding in bindings {
+                check_loops(&binding.expr, loops)?;
+            }
+            check_loops(body, loops)
+        }
+        ExprKind::Block(exprs) | ExprKind::And(exprs) | ExprKind::Or(exprs) | ExprKind::Tuple(exprs)
+        | ExprKind::Call(_, exprs) => {
+            for e in exprs {
+                check_loops(e, loops)?;
+            }
+            Ok(())
+        }
+        ExprKind::Number(_) | ExprKind::Id(_) | ExprKind::Input | ExprKind::Boolean(_) | ExprKind::Continue => Ok(()),
+    }
+}
+
+/// No two functions share a name, every call names a function and passes
+/// it as many arguments as it takes, no let binds a name twice and every
+/// variable is bound. Like the loop check this needs no types, and it lets
+/// the compiler assume a well-formed program.
+pub fn check_program_names(program: &Program) -> Result<(), TypeError> {
+    let mut arities = HashMap::new();
+    for defn in &program.defns {
+        if arities.insert(defn.name.clone(), defn.params.len()).is_some() {
+            return err(defn.span, format!("Duplicate function definition: {}", defn.name));
+        }
+    }
+    for defn in &program.defns {
+        if let ExprKind::Input = defn.body.kind {
+            return err(defn.body.span, "Invalid: input not allowed in function definitions");
+        }
+        check_names(&defn.body, &mut defn.params.clone(), &arities)?;
+    }
+    check_names(&program.main, &mut Vec::new(), &arities)
+}
+
+/// `scope` holds the variables bound around `expr`, innermost last, and
+/// `arities` the parameter count of each function
+pub fn check_names(expr: &Expr, scope: &mut Vec<String>, arities: &HashMap<String, usize>) -> Result<(), TypeError> {
+    fn bound(name: &str, scope: &[String]) -> bool {
+        scope.iter().any(|var| var == name)
+    }
+    // Each binding sees the ones before it. Leaves them all in scope.
+    fn let_names(bindings: &[Binding], body: &Expr, scope: &mut Vec<String>, arities: &HashMap<String, usize>) -> Result<(), TypeError> {
+        for (i, binding) in bindings.iter().enumerate() {
+            if bindings[..i].iter().any(|b| b.name == binding.name) {
+                return err(binding.span, "Duplicate binding");
+            }
+            check_names(&binding.expr, scope, arities)?;
+            scope.push(binding.name.clone());
+        }
+        check_names(body, scope, arities)
+    }
+    match &expr.kind {
+        ExprKind::Id(name) | ExprKind::Set(name, _) if !bound(name, scope) => {
+            err(expr.span, format!("Unbound variable identifier {}", name))
+        }
+        ExprKind::Call(name, args) => match arities.get(name) {
+            None => err(expr.span, format!("Undefined function: {}", name)),
+            Some(&expected) if expected != args.len() => err(
+                expr.span,
+                format!("Wrong number of arguments for {}: expected {}, got {}", name, expected, args.len()),
+            ),
+            Some(_) => {
+                for arg in args {
+                    check_names(arg, scope, arities)?;
+                }
+                Ok(())
+            }
+        },
+        ExprKind::Let(bindings, body) => {
+            let outer = scope.len();
+            let checked = let_names(bindings, body, scope, arities);
+            scope.truncate(outer);
+            checked
+        }
+        ExprKind::For(name, lo, hi, body) => {
+            check_names(lo, scope, arities)?;
+            check_names(hi, scope, arities)?;
+            scope.push(name.clone());
+            let checked = check_names(body, scope, arities);
+            scope.pop();
+            checked
+        }
+        ExprKind::Break(_, e) | ExprKind::UnOp(_, e) | ExprKind::Set(_, e) | ExprKind::Cast(e, _)
+        | ExprKind::Loop(_, e) => check_names(e, scope, arities),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::While(e1, e2) => {
+            check_names(e1, scope, arities)?;
+            check_names(e2, scope, arities)
+        }
+        ExprKind::If(e1, e2, e3) | ExprKind::TupleSet(e1, e2, e3) => {
+            check_names(e1, scope, arities)?;
+            check_names(e2, scope, arities)?;
+            check_names(e3, scope, arities)
+        }
+        ExprKind::Block(exprs) | ExprKind::And(exprs) | ExprKind::Or(exprs) | ExprKind::Tuple(exprs) => {
+            for e in exprs {
+                check_names(e, scope, arities)?;
+            }
+            Ok(())
+        }
+        ExprKind::Number(_) | ExprKind::Id(_) | ExprKind::Input | ExprKind::Boolean(_) | ExprKind::Continue => Ok(()),
+    }
+}
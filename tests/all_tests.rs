@@ -4,21 +4,70 @@ mod infra;
 success_tests! {
     test_input: { file: "input", input: "2", expected: "2" },
     test_input_tc: { file: "input", input: "false", expected: "false", typecheck: true },
+    test_tail_mutual: { file: "tail_mutual", input: "10000000", expected: "10000000\ntrue" },
+    test_many_args: { file: "many_args", input: "7", expected: "91\n160" },
+    test_tail_stack_args: { file: "tail_stack_args", input: "3", expected: "67812345" },
+    test_register_pressure: { file: "register_pressure", input: "10", expected: "1150" },
+    test_set_in_operand: { file: "set_in_operand", expected: "113" },
+    test_fold: { file: "fold", expected: "11\ntrue\ntrue\n15" },
+    test_inline: { file: "inline", input: "4", expected: "25\n5\n5\n10\n4\n120" },
+    test_branch: { file: "branch", input: "3", expected: "1\n1\n0\n1\n0\n1\n0\n3" },
+    test_radix_literal: { file: "radix_literal", input: "15", expected: "31\n-10\n1000000\n4611686018427387903" },
+    test_divide: { file: "divide", input: "11", expected: "2\n3\n-2\n-3\n419244183493398900" },
+    test_bitwise: { file: "bitwise", input: "10", expected: "8\n58\n-11\n160\n-3\n0" },
+    test_logic: { file: "logic", input: "5", expected: "true\n99\nfalse\nfalse\ntrue\ntrue" },
+    test_big_literal: { file: "big_literal", input: "1", expected: "4611686018427387903\n-4611686018427387904\n3000000001" },
+    test_cond: { file: "cond", input: "3", expected: "1\n6\n7\n7\nfalse\n5" },
+    test_loops: { file: "loops", input: "5", expected: "120\n25\nfalse\n5\n8\n8\n9\n10\nfalse" },
+    test_labeled_loop: { file: "labeled_loop", input: "12", expected: "403\n7" },
+    test_tuple: { file: "tuple", input: "5", expected: "6\n(tuple true (tuple 1 2) (tuple 11 6))\ntrue\nfalse\nfalse\n(tuple true (tuple 1 (tuple)) (tuple 11 6))" },
 
 }
 
 runtime_error_tests! {
     test_overflow_error: { file: "overflow", expected: "overflow" },
+    test_error_site_arith: { file: "error_site_arith", expected: "invalid argument in (+ x y) at tests/error_site_arith.snek:2:3" },
+    test_fold_overflow: { file: "fold_overflow", expected: "overflow in (* 2147483647 (* 2147483647 2)) at tests/fold_overflow.snek:1:6" },
+    test_branch_error: { file: "branch_error", input: "true", expected: "invalid argument in (< 1 input) at tests/branch_error.snek:1:5" },
+    test_divide_by_zero: { file: "divide_by_zero", input: "3", expected: "division by zero in (% 10 d) at tests/divide_by_zero.snek:2:3" },
+    test_shl_overflow: { file: "shl_overflow", input: "62", expected: "overflow in (shl mask input) at tests/shl_overflow.snek:2:3" },
+    test_logic_error: { file: "logic_error", input: "3", expected: "invalid argument in (or (< input 0) input) at tests/logic_error.snek:1:1" },
+    test_cond_error: { file: "cond_error", input: "30", expected: "invalid argument in (< input true) at tests/cond_error.snek:2:8" },
+    test_for_bound_error: { file: "for_bound_error", input: "true", expected: "invalid argument in (for (i 0 input) (set! total (+ total... at tests/for_bound_error.snek:2:3" },
+    test_index_out_of_bounds: { file: "index_out_of_bounds", input: "3", expected: "index out of bounds in (index t input) at tests/index_out_of_bounds.snek:2:18" },
+    test_error_site_overflow: { file: "error_site_overflow", input: "4611686018427387903", expected: "overflow in (add1 input) at tests/error_site_overflow.snek:3:3" },
 }
 
 static_error_tests! {
     test_parse_error: { file: "parse", input: "2", expected: "Invalid" },
+    test_parse_error_location: { file: "parse_keyword", expected: "parse_keyword.snek:2:10: error: keyword" },
+    test_literal_out_of_range: { file: "literal_range", expected: "literal_range.snek:2:4: error: number out of range" },
+    test_radix_literal_out_of_range: { file: "radix_literal_range", expected: "radix_literal_range.snek:2:10: error: number out of range" },
+    test_logic_tc: { file: "logic_tc", expected: "Type error: and/or requires Bool, got Num", typecheck: true },
+    test_cond_tc: { file: "cond_tc", expected: "cond_tc.snek:2:8: error: Type error: condition must be Bool, got Num", typecheck: true },
+    test_loops_tc: { file: "loops_tc", expected: "loops_tc.snek:2:13: error: Type error: for range requires Num, got Bool", typecheck: true },
+    test_unknown_label: { file: "unknown_label", expected: "unknown_label.snek:3:19: error: Unknown loop label :inner" },
+    test_continue_outside_loop: { file: "continue_outside_loop", expected: "continue_outside_loop.snek:2:10: error: continue outside of loop" },
+    test_tuple_tc: { file: "tuple_tc", expected: "tuple_tc.snek:2:10: error: Type error: index requires Tuple, got Any", typecheck: true },
+    test_parse_error_unclosed: { file: "parse_unclosed", expected: "parse_unclosed.snek:1:1: error: Invalid: unclosed '('" },
 }
 
 
 repl_tests! {
     test_simple_bools: {commands:["(define x true)", "x", "false"], expected: ["true", "false"]},
     test_define_and_use: { commands: ["(define a 10)", "(define b (+ a 5))", "(+ a b)"], expected: ["25"], typecheck: true },
+    test_repl_stack_args: { commands: ["(fun (f a b c d e f g) (- g a))", "(f 1 2 3 4 5 6 7)"], expected: ["6"] },
+    test_repl_error_in_call_recovers: { commands: [
+        "(define x 5)",
+        "(fun (f a) (let ((y (* a 2))) (+ y (+ a true))))",
+        "(f 3)",
+        "(let ((a 1) (b 2) (c 3) (d 4) (e 5)) (+ a (+ b (+ c (+ d (+ e x))))))",
+    ], expected: ["invalid argument", "20"] },
+    test_repl_divide_by_zero_recovers: { commands: ["(/ 10 (- 3 3))", "(% 10 4)"], expected: ["division by zero", "2"] },
+    test_repl_tuple_define: {
+        commands: ["(define t (tuple 1 2))", "(tuple-set! t 0 (tuple 3))", "t", "(index t 2)", "(index t 1)"],
+        expected: ["(tuple 3)", "(tuple (tuple 3) 2)", "index out of bounds", "2"]
+    },
     repl_complicated_tc: { commands: [
         "(define acc 0)",
         "(fun (even (x : Num)) -> Num (set! acc (add1 acc)))",
@@ -31,3 +80,163 @@ repl_tests! {
     ], expected: ["7", "21", "22", "22"], typecheck: true},
 
 }
+
+#[test]
+fn build_produces_executable() {
+    let exe = infra::build("input");
+    let run = std::process::Command::new(&exe).arg("21").output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout).trim(), "21");
+}
+
+// On Linux `build` links the built-in ELF object rather than nasm output
+#[cfg(target_os = "linux")]
+#[test]
+fn build_object_reports_error_sites() {
+    let exe = infra::build("error_site_arith");
+    let run = std::process::Command::new(&exe).output().unwrap();
+    assert!(String::from_utf8_lossy(&run.stderr).contains("invalid argument in (+ x y) at tests/error_site_arith.snek:2:3"));
+}
+
+#[test]
+fn engine_returns_value_and_output() {
+    let out = cobra::Engine::new()
+        .compile("(block (print input) (+ input 1))")
+        .unwrap()
+        .run(41)
+        .unwrap();
+    assert_eq!(out.value, cobra::Value::Num(42));
+    assert_eq!(out.printed, "41\n");
+}
+
+#[test]
+fn engine_runtime_error_unwinds() {
+    let prog = cobra::Engine::new()
+        .file_name("f.snek")
+        .compile("(fun (f x) (add1 x))\n(block (print 1) (f true) (print 2))")
+        .unwrap();
+    match prog.run(0) {
+        Err(cobra::Error::Runtime(e)) => {
+            assert_eq!(e.message, "invalid argument in (add1 x) at f.snek:1:12");
+            assert_eq!(e.printed, "1\n");
+        }
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+    // Unwinding leaves the program usable for another run
+    assert!(matches!(prog.run(0), Err(cobra::Error::Runtime(_))));
+}
+
+#[test]
+fn engine_reports_compile_errors_with_spans() {
+    let engine = cobra::Engine::new().file_name("f.snek");
+    let error = |source| match engine.compile(source) {
+        Err(cobra::Error::Compile(msg)) => msg.lines().next().unwrap().to_string(),
+        Err(other) => panic!("expected a compile error, got {:?}", other),
+        Ok(_) => panic!("expected a compile error"),
+    };
+    assert_eq!(error("(+ 1 (f 1))"), "f.snek:1:6: error: Undefined function: f");
+    assert_eq!(error("(fun (f x) x)\n(f 1 2)"), "f.snek:2:1: error: Wrong number of arguments for f: expected 1, got 2");
+    assert_eq!(error("(let ((x 1)) (+ x y))"), "f.snek:1:19: error: Unbound variable identifier y");
+    assert_eq!(error("(let ((x 1) (x 2)) x)"), "f.snek:1:13: error: Duplicate binding");
+    assert_eq!(error("(fun (f x) x)\n(fun (f y) y)\n1"), "f.snek:2:1: error: Duplicate function definition: f");
+}
+
+#[test]
+fn engine_lowers_to_ir() {
+    let ir = cobra::Engine::new()
+        .opt_level(cobra::optimize::OptLevel::O0)
+        .compile_to_ir("(fun (f x) (* x 2))\n(let ((x (add1 input))) (let ((x (f x))) (+ x x)))")
+        .unwrap();
+    assert_eq!(
+        ir.to_string(),
+        "fun f(x) {\n  x * 2\n}\n\nmain {\n  let x = add1 input;\n  let x.1 = f(x);\n  x.1 + x.1\n}\n"
+    );
+}
+
+#[test]
+fn engine_folds_constants() {
+    let ir = cobra::Engine::new()
+        .compile_to_ir("(let ((x 3) (y input)) (if (< x 4) (+ y (* x 2)) (print y)))")
+        .unwrap();
+    assert_eq!(ir.to_string(), "main {\n  input + 6\n}\n");
+}
+
+#[test]
+fn engine_inlines_small_functions_at_o2() {
+    use cobra::optimize::OptLevel;
+    let source = "(fun (sq x) (* x x))\n(sq (add1 input))";
+    let main = |level| {
+        let ir = cobra::Engine::new().opt_level(level).compile_to_ir(source).unwrap().to_string();
+        ir[ir.find("main").unwrap()..].to_string()
+    };
+    assert_eq!(main(OptLevel::O0), "main {\n  let %1 = add1 input;\n  sq(%1)\n}\n");
+    assert_eq!(main(OptLevel::O2), "main {\n  let %1 = add1 input;\n  %1 * %1\n}\n");
+    for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+        let out = cobra::Engine::new().opt_level(level).compile(source).unwrap().run(6).unwrap();
+        assert_eq!(out.value, cobra::Value::Num(49));
+    }
+}
+
+#[test]
+fn engine_types_labeled_breaks_by_target() {
+    use cobra::ast::Type;
+    let engine = cobra::Engine::new();
+    // The inner loop's break gives Bool, but only the break naming :a leaves the outer loop
+    let source = "(loop :a (loop :b (block (break :b true) (break :a 5))))";
+    assert_eq!(engine.type_of(source).unwrap(), Type::Num);
+    // An inner loop with the same label takes the break
+    assert_eq!(engine.type_of("(loop :a (loop :a (break :a true)))").unwrap(), Type::Nothing);
+    assert_eq!(engine.type_of("(loop :a (for (i 0 3) (break :a i)))").unwrap(), Type::Num);
+}
+
+#[test]
+fn engine_returns_tuples() {
+    use cobra::Value;
+    let engine = cobra::Engine::new();
+    let out = engine.compile("(tuple input (tuple true))").unwrap().run(4).unwrap();
+    assert_eq!(out.value, Value::Tuple(vec![Value::Num(4), Value::Tuple(vec![Value::Bool(true)])]));
+    assert_eq!(out.value.to_string(), "(tuple 4 (tuple true))");
+    let cycle = engine
+        .compile("(let ((t (tuple 1 2 3)) (u (tuple t false))) (block (tuple-set! t 0 u) (print t) (index u 0)))")
+        .unwrap();
+    let out = cycle.run(0).unwrap();
+    let u = Value::Tuple(vec![Value::Cycle(1), Value::Bool(false)]);
+    assert_eq!(out.value, Value::Tuple(vec![u, Value::Num(2), Value::Num(3)]));
+    assert_eq!(out.value.to_string(), "(tuple (tuple ... false) 2 3)");
+    assert_eq!(out.printed, "(tuple (tuple ... false) 2 3)\n");
+    assert!(matches!(cycle.run(Value::Tuple(vec![])), Err(cobra::Error::Input(_))));
+}
+
+#[test]
+fn engine_reports_peephole_savings() {
+    use cobra::optimize::OptLevel;
+    let source = "(let ((x input) (y (+ x 1))) (block (set! x (+ x y)) (* x 2)))";
+    let o0 = cobra::Engine::new().opt_level(OptLevel::O0).compile(source).unwrap();
+    let o2 = cobra::Engine::new().compile(source).unwrap();
+    assert_eq!(o0.peephole_saved(), 0);
+    assert!(o2.peephole_saved() > 0);
+    assert_eq!(o0.run(5).unwrap().value, o2.run(5).unwrap().value);
+}
+
+#[test]
+fn engine_compiles_and_runs_concurrently() {
+    let sum = std::thread::spawn(|| {
+        let prog = cobra::Engine::new()
+            .compile("(let ((i 0) (acc 0)) (loop (if (> i input) (break acc) (block (set! acc (+ acc i)) (set! i (add1 i))))))")
+            .unwrap();
+        (0..200).map(|n| prog.run(n).unwrap().value).collect::<Vec<_>>()
+    });
+    let even = std::thread::spawn(|| {
+        let prog = cobra::Engine::new()
+            .compile("(fun (even n) (if (= n 0) true (if (= n 1) false (even (- n 2)))))\n(block (print input) (even input))")
+            .unwrap();
+        (0..200).map(|n| prog.run(n).unwrap()).collect::<Vec<_>>()
+    });
+    for (n, value) in sum.join().unwrap().into_iter().enumerate() {
+        let n = n as i64;
+        assert_eq!(value, cobra::Value::Num(n * (n + 1) / 2));
+    }
+    for (n, out) in even.join().unwrap().into_iter().enumerate() {
+        assert_eq!(out.value, cobra::Value::Bool(n % 2 == 0));
+        assert_eq!(out.printed, format!("{}\n", n));
+    }
+}
@@ -81,7 +81,7 @@ fn run_success_test_with_typecheck(name: &str, file: &str, expected: &str, input
         failed_flags.push((if typecheck { "-te" } else { "-e" }, jit_trim.to_string(), jit_out));
     }
     if expected_trim != run_trim {
-        failed_flags.push((if typecheck { "-tc" } else { "-c" }, run_trim.to_string(), run_out));
+        failed_flags.push((aot_flag(typecheck), run_trim.to_string(), run_out));
     }
     if !failed_flags.is_empty() {
         for (flag, actual_trim, raw) in &failed_flags {
@@ -144,18 +144,38 @@ impl std::fmt::Display for Ext {
 }
 
 
+// The compiler binary built for this test run, whatever --target was used
+const BOA_PATH: &str = env!("CARGO_BIN_EXE_cobra");
+
+// Rust target for the AOT runtime
+#[cfg(target_os = "macos")]
+const TARGET: &str = "x86_64-apple-darwin";
+#[cfg(not(target_os = "macos"))]
+const TARGET: &str = "x86_64-unknown-linux-gnu";
+
+// Linux gets an ELF object straight from the compiler; macOS goes through
+// assembly text and nasm
+#[cfg(target_os = "macos")]
+fn aot_flag(typecheck: bool) -> &'static str {
+    if typecheck { "-tc" } else { "-c" }
+}
+#[cfg(not(target_os = "macos"))]
+fn aot_flag(typecheck: bool) -> &'static str {
+    if typecheck { "-to" } else { "-o" }
+}
+
 fn compile_with_typecheck(name: &str, file: &str, input: Option<&str>, typecheck: bool) -> Result<(String, String), SnekError> {
-    let boa_path = if cfg!(target_os = "macos") {
-        PathBuf::from("target/x86_64-apple-darwin/debug/cobra")
-    } else {
-        PathBuf::from("target/debug/cobra")
-    };
-    // First phase: compile (-c or -tc)
-    let compile_flag = if typecheck { "-tc" } else { "-c" };
+    let boa_path = PathBuf::from(BOA_PATH);
+    // Each test gets its own library directory so tests can link in parallel
+    let lib_dir = Path::new("target").join("snek-tests").join(name);
+    std::fs::create_dir_all(&lib_dir).unwrap();
+
+    // First phase: compile (-o/-to, or -c/-tc on macOS)
+    let compiled = if cfg!(target_os = "macos") { mk_path(name, Ext::Asm) } else { lib_dir.join("our_code.o") };
     let output_c = Command::new(&boa_path)
-        .arg(compile_flag)
+        .arg(aot_flag(typecheck))
         .arg(&mk_path(file, Ext::Snek))
-        .arg(&mk_path(name, Ext::Asm))
+        .arg(&compiled)
         .output()
         .expect("could not run the compiler");
     if !output_c.status.success() {
@@ -177,12 +197,10 @@ fn compile_with_typecheck(name: &str, file: &str, input: Option<&str>, typecheck
 
     eprintln!("JIT result: {}", jit_stdout);
 
-    // Assemble and link
-    let output = Command::new("make")
-        .arg(&mk_path(name, Ext::Run))
-        .output()
-        .expect("could not run make");
-    assert!(output.status.success(), "linking failed");
+    if cfg!(target_os = "macos") {
+        assemble(&compiled, &lib_dir.join("our_code.o"));
+    }
+    link(name, &lib_dir);
 
     // Run produced program and capture stdout
     let output_run = run(name, input)
@@ -194,6 +212,43 @@ fn compile_with_typecheck(name: &str, file: &str, input: Option<&str>, typecheck
 }
 
 
+/// Assemble `asm` into the Mach-O object `obj` with nasm
+fn assemble(asm: &Path, obj: &Path) {
+    let nasm = Command::new("nasm")
+        .arg("-f")
+        .arg("macho64")
+        .arg(asm)
+        .arg("-o")
+        .arg(obj)
+        .output()
+        .expect("could not run nasm");
+    assert!(nasm.status.success(), "assembling failed: {}", String::from_utf8_lossy(&nasm.stderr));
+}
+
+/// Link `lib_dir`/our_code.o against runtime/start.rs into tests/<name>.run
+fn link(name: &str, lib_dir: &Path) {
+    let obj = lib_dir.join("our_code.o");
+    let ar = Command::new("ar")
+        .arg("rcs")
+        .arg(lib_dir.join("libour_code.a"))
+        .arg(&obj)
+        .output()
+        .expect("could not run ar");
+    assert!(ar.status.success(), "archiving failed: {}", String::from_utf8_lossy(&ar.stderr));
+
+    let rustc = Command::new("rustc")
+        .arg("--target")
+        .arg(TARGET)
+        .arg("-L")
+        .arg(lib_dir)
+        .arg("runtime/start.rs")
+        .arg("-o")
+        .arg(mk_path(name, Ext::Run))
+        .output()
+        .expect("could not run rustc");
+    assert!(rustc.status.success(), "linking failed: {}", String::from_utf8_lossy(&rustc.stderr));
+}
+
 fn run(name: &str, input: Option<&str>) -> Result<String, String> {
     let mut cmd = Command::new(&mk_path(name, Ext::Run));
     if let Some(input) = input {
@@ -207,6 +262,23 @@ fn run(name: &str, input: Option<&str>) -> Result<String, String> {
     }
 }
 
+/// Build tests/<file>.snek into an executable with `cobra build` and return
+/// its path
+pub(crate) fn build(file: &str) -> PathBuf {
+    let dir = Path::new("target").join("snek-tests").join("build");
+    std::fs::create_dir_all(&dir).unwrap();
+    let exe = dir.join(file);
+    let build = Command::new(BOA_PATH)
+        .arg("build")
+        .arg(mk_path(file, Ext::Snek))
+        .arg("-o")
+        .arg(&exe)
+        .output()
+        .expect("could not run the compiler");
+    assert!(build.status.success(), "build failed: {}", String::from_utf8_lossy(&build.stderr));
+    exe
+}
+
 
 #[macro_export]
 macro_rules! repl_tests {
@@ -284,13 +356,7 @@ pub(crate) fn run_repl_sequence_test(name: &str, commands: &[&str], expected_out
 
 
 fn run_repl_with_timeout(commands: &[&str], timeout_ms: u64, typecheck: bool) -> String {
-    let boa_path = if cfg!(target_os = "macos") {
-        "target/x86_64-apple-darwin/debug/cobra"
-    } else {
-        "target/debug/cobra"
-    };
-
-    let mut child = Command::new(boa_path)
+    let mut child = Command::new(BOA_PATH)
         .arg(if typecheck { "-ti" } else { "-i" })
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())